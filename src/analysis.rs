@@ -0,0 +1,7 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+
+pub mod cfg;
+pub use cfg::{BasicBlock, Cfg, Edge, EdgeKind};
+pub mod symbols;
+pub use symbols::Symbols;
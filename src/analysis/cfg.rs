@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cartridge::{BankedAddr, Cartridge};
+use crate::sm83::{self, Flow, Instruction};
+
+/// where the cpu can start executing without anyone jumping there:
+/// the entry point, the rst vectors and the interrupt vectors.
+pub const DEFAULT_ENTRIES: [u16; 14] = [
+    0x100, // entry point
+    0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, // rst
+    0x40, 0x48, 0x50, 0x58,
+    0x60, // vblank, stat, timer, serial, joypad
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Branch, // the taken side of a conditional jump
+    Call,   // the callee; the return site gets a Fallthrough edge
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub to: BankedAddr,
+    pub kind: EdgeKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    pub start: BankedAddr,
+    pub end: BankedAddr, // one past the last instruction
+    pub instrs: Vec<(BankedAddr, Instruction)>,
+    // how the last instruction leaves the block
+    pub exit: Flow,
+    pub succs: Vec<Edge>,
+    pub preds: Vec<BankedAddr>, // starts of the predecessor blocks
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cfg {
+    pub blocks: BTreeMap<BankedAddr, BasicBlock>,
+    pub entries: BTreeSet<BankedAddr>,
+    // targets of call and rst, i.e. sm83 functions
+    pub functions: BTreeSet<BankedAddr>,
+    // jp hl sites, whose targets we cannot know statically
+    pub indirect: BTreeSet<BankedAddr>,
+    // jumps and calls out of the rom (to code copied to ram)
+    pub external: BTreeSet<u16>,
+}
+
+impl Cfg {
+    /// discovers the code reachable from the default entries
+    pub fn discover(rom: &Cartridge) -> Self {
+        let entries = DEFAULT_ENTRIES.map(|a| BankedAddr::new(a, 1));
+        Self::discover_from(rom, &entries)
+    }
+
+    /// recursive descent from `entries`, following every static edge
+    pub fn discover_from(
+        rom: &Cartridge,
+        entries: &[BankedAddr],
+    ) -> Self {
+        let mut cfg = Cfg {
+            entries: entries.iter().copied().collect(),
+            ..Default::default()
+        };
+
+        // every decoded instruction, and the addrs that start a block
+        let mut code: BTreeMap<BankedAddr, Instruction> =
+            BTreeMap::new();
+        let mut leaders: BTreeSet<BankedAddr> = BTreeSet::new();
+        let mut worklist: Vec<BankedAddr> = entries.to_vec();
+
+        while let Some(start) = worklist.pop() {
+            if !leaders.insert(start) && code.contains_key(&start) {
+                continue;
+            }
+            let mut pc = start;
+            loop {
+                if code.contains_key(&pc) {
+                    // already decoded from here on; we merge into that
+                    // code, so it has to start a block of its own
+                    leaders.insert(pc);
+                    break;
+                }
+                let instr = sm83::decode_instr(rom.instr_bytes(pc));
+                code.insert(pc, instr);
+                let next = pc.to(pc.addr.wrapping_add(instr.len()));
+                let flow = instr.flow(pc.addr);
+
+                let mut target = |dest: u16, cfg: &mut Cfg| {
+                    if dest >= 0x8000 {
+                        cfg.external.insert(dest);
+                    } else {
+                        worklist.push(pc.to(dest));
+                    }
+                };
+                match flow {
+                    Flow::Next => {}
+                    Flow::Jump(dest) | Flow::Branch(_, dest) => {
+                        target(dest, &mut cfg)
+                    }
+                    Flow::Call(dest) | Flow::CondCall(_, dest) => {
+                        if dest < 0x8000 {
+                            cfg.functions.insert(pc.to(dest));
+                        }
+                        target(dest, &mut cfg)
+                    }
+                    Flow::Indirect => {
+                        cfg.indirect.insert(pc);
+                    }
+                    Flow::Ret | Flow::CondRet(_) | Flow::Lockup => {}
+                }
+
+                if !flow.falls_through() {
+                    break;
+                }
+                if flow.ends_block() {
+                    worklist.push(next);
+                    break;
+                }
+                if next.addr >= 0x8000 {
+                    break;
+                }
+                pc = next;
+            }
+        }
+
+        // now cut the code into blocks, one per leader
+        for &start in &leaders {
+            let block = Self::build_block(start, &code, &leaders);
+            cfg.blocks.insert(start, block);
+        }
+        let edges: Vec<(BankedAddr, BankedAddr)> = cfg
+            .blocks
+            .values()
+            .flat_map(|b| b.succs.iter().map(|e| (b.start, e.to)))
+            .collect();
+        for (from, to) in edges {
+            if let Some(b) = cfg.blocks.get_mut(&to) {
+                b.preds.push(from);
+            }
+        }
+        cfg
+    }
+
+    fn build_block(
+        start: BankedAddr,
+        code: &BTreeMap<BankedAddr, Instruction>,
+        leaders: &BTreeSet<BankedAddr>,
+    ) -> BasicBlock {
+        let mut instrs = vec![];
+        let mut pc = start;
+        let mut exit = Flow::Next;
+        while let Some(&instr) = code.get(&pc) {
+            instrs.push((pc, instr));
+            exit = instr.flow(pc.addr);
+            pc = pc.to(pc.addr.wrapping_add(instr.len()));
+            if exit.ends_block() || leaders.contains(&pc) {
+                break;
+            }
+        }
+        let end = pc;
+        let last = instrs.last().map_or(start, |&(a, _)| a);
+
+        let mut succs = vec![];
+        let mut edge = |dest: u16, kind| {
+            if dest < 0x8000 {
+                succs.push(Edge {
+                    to: last.to(dest),
+                    kind,
+                });
+            }
+        };
+        match exit {
+            Flow::Jump(dest) => edge(dest, EdgeKind::Jump),
+            Flow::Branch(_, dest) => edge(dest, EdgeKind::Branch),
+            Flow::Call(dest) | Flow::CondCall(_, dest) => {
+                edge(dest, EdgeKind::Call)
+            }
+            _ => {}
+        }
+        if exit.falls_through() && code.contains_key(&end) {
+            succs.push(Edge {
+                to: end,
+                kind: EdgeKind::Fallthrough,
+            });
+        }
+
+        BasicBlock {
+            start,
+            end,
+            instrs,
+            exit,
+            succs,
+            preds: vec![],
+        }
+    }
+
+    /// the block starting exactly at `addr`
+    pub fn block(&self, addr: BankedAddr) -> Option<&BasicBlock> {
+        self.blocks.get(&addr)
+    }
+
+    /// whether `addr` is the start of a discovered instruction
+    pub fn is_code(&self, addr: BankedAddr) -> bool {
+        self.blocks
+            .range(..=addr)
+            .rev()
+            .take_while(|(_, b)| b.start.bank == addr.bank)
+            .any(|(_, b)| b.instrs.iter().any(|&(a, _)| a == addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom_from;
+    use crate::sm83::asm::asm;
+
+    /// a 64 KiB rom with each of `code` at its offset
+    fn rom(code: &[(usize, Vec<u8>)]) -> Cartridge {
+        let mut rom = vec![0; 0x10000];
+        for (offset, bytes) in code {
+            rom[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        test_rom_from(rom)
+    }
+
+    fn at(addr: u16) -> BankedAddr {
+        BankedAddr::new(addr, 0)
+    }
+
+    fn succs(cfg: &Cfg, addr: u16) -> Vec<(BankedAddr, EdgeKind)> {
+        let block = cfg.block(at(addr)).unwrap();
+        block.succs.iter().map(|e| (e.to, e.kind)).collect()
+    }
+
+    #[test]
+    fn splits_blocks_at_targets_and_calls() {
+        let code = asm!(
+            0x150,
+            "main:   ld b, 3
+            .loop:  dec b
+                    jr nz, .loop
+                    call func
+                    rst $08
+                    ld hl, main
+                    jp hl
+            func:   ret"
+        );
+        let rom = rom(&[(0x150, code), (0x08, asm!("ret"))]);
+        let cfg = Cfg::discover_from(&rom, &[at(0x150)]);
+
+        let starts: Vec<u16> =
+            cfg.blocks.keys().map(|a| a.addr).collect();
+        assert_eq!(
+            starts,
+            [0x08, 0x150, 0x152, 0x155, 0x158, 0x159, 0x15d]
+        );
+        assert_eq!(cfg.functions, [at(0x08), at(0x15d)].into());
+        assert_eq!(cfg.indirect, [at(0x15c)].into());
+
+        // ld b falls into the loop, which branches back or falls out
+        assert_eq!(
+            succs(&cfg, 0x150),
+            [(at(0x152), EdgeKind::Fallthrough)]
+        );
+        assert_eq!(
+            succs(&cfg, 0x152),
+            [
+                (at(0x152), EdgeKind::Branch),
+                (at(0x155), EdgeKind::Fallthrough)
+            ]
+        );
+        assert_eq!(
+            cfg.block(at(0x152)).unwrap().preds,
+            [at(0x150), at(0x152)]
+        );
+        assert_eq!(
+            succs(&cfg, 0x155),
+            [
+                (at(0x15d), EdgeKind::Call),
+                (at(0x158), EdgeKind::Fallthrough)
+            ]
+        );
+        assert_eq!(
+            succs(&cfg, 0x158),
+            [
+                (at(0x08), EdgeKind::Call),
+                (at(0x159), EdgeKind::Fallthrough)
+            ]
+        );
+
+        // jp hl and ret end their blocks, with nowhere known to go
+        let jp_hl = cfg.block(at(0x159)).unwrap();
+        assert_eq!(
+            (jp_hl.exit, jp_hl.end),
+            (Flow::Indirect, at(0x15d))
+        );
+        assert!(jp_hl.succs.is_empty());
+        let ret = cfg.block(at(0x15d)).unwrap();
+        assert_eq!((ret.exit, ret.instrs.len()), (Flow::Ret, 1));
+        assert!(ret.succs.is_empty());
+        assert!(cfg.is_code(at(0x153)) && !cfg.is_code(at(0x154)));
+    }
+
+    #[test]
+    fn banked_code_stays_in_its_bank() {
+        let far = asm!(
+            0x4000,
+            "       call func
+                    jp nz, $0150
+                    jp $ff80
+            func:   ret"
+        );
+        let home = asm!(0x150, "call $4000 / halt / jr @");
+        let rom =
+            rom(&[(0x150, home), (0x4000, asm!("ret")), (0x8000, far)]);
+        let entry = BankedAddr::new(0x4000, 2);
+        let cfg = Cfg::discover_from(&rom, &[entry]);
+
+        let far = |addr| BankedAddr::new(addr, 2);
+        assert_eq!(
+            cfg.functions,
+            [BankedAddr::new(0x4000, 1), far(0x4009)].into()
+        );
+        assert_eq!(cfg.external, [0xff80].into());
+        let jump = cfg.block(far(0x4003)).unwrap();
+        assert_eq!(jump.succs[0].to, at(0x150));
+        assert_eq!(jump.succs[1].to, far(0x4006));
+        // bank 0 does not know what is mapped, and calls bank 1
+        assert!(cfg.block(BankedAddr::new(0x4000, 1)).is_some());
+        assert_eq!(cfg.block(at(0x150)).unwrap().succs[0].to.bank, 1);
+    }
+}
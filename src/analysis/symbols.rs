@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::cartridge::BankedAddr;
+
+// names for addresses, from an rgbds style .sym file: one `bank:addr name`
+// per line, all hex, `;` starts a comment.
+
+#[derive(Debug)]
+pub enum SymbolsError {
+    Io(std::io::Error),
+    // a line that is not `bank:addr name`
+    Syntax(usize),
+}
+
+impl fmt::Display for SymbolsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolsError::Io(e) => write!(f, "could not read symbols: {e}"),
+            SymbolsError::Syntax(line) => {
+                write!(f, "line {line}: expected `bank:addr name`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SymbolsError {}
+
+impl From<std::io::Error> for SymbolsError {
+    fn from(e: std::io::Error) -> Self {
+        SymbolsError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    names: BTreeMap<BankedAddr, String>,
+}
+
+impl Symbols {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SymbolsError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolsError> {
+        let mut names = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = line.split_once(' ').and_then(|(addr, name)| {
+                let (bank, addr) = addr.split_once(':')?;
+                let bank = u16::from_str_radix(bank, 16).ok()?;
+                let addr = u16::from_str_radix(addr, 16).ok()?;
+                Some((BankedAddr::new(addr, bank), name.trim()))
+            });
+            let Some((addr, name)) = parsed else {
+                return Err(SymbolsError::Syntax(i + 1));
+            };
+            // the first name wins, the rest are usually local labels
+            names.entry(addr).or_insert_with(|| name.to_string());
+        }
+        Ok(Self { names })
+    }
+
+    pub fn get(&self, addr: BankedAddr) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rgbds_sym() {
+        let syms = Symbols::parse(
+            "; File generated by rgblink\n\
+             00:0150 Main\n\
+             00:0150 Main.loop\n\
+             02:4000 Banked ; far away\n\n",
+        )
+        .unwrap();
+        assert_eq!(syms.get(BankedAddr::new(0x150, 0)), Some("Main"));
+        assert_eq!(syms.get(BankedAddr::new(0x4000, 2)), Some("Banked"));
+        assert_eq!(syms.get(BankedAddr::new(0x4000, 1)), None);
+        assert!(matches!(
+            Symbols::parse("00:0150 Main\nnope"),
+            Err(SymbolsError::Syntax(2))
+        ));
+    }
+}
@@ -0,0 +1,464 @@
+// the memory everything runs on. the interpreter does every access
+// through a Bus, and so does the runtime. compiled code reads and writes
+// memory() directly, the way the readme describes, and only goes through
+// read8 and write8 where it was compiled to trap (i/o, with trap_io). so
+// memory() has to hold what everything else reads as.
+
+use std::ffi::c_void;
+use std::ops::{Deref, DerefMut};
+
+use crate::cartridge::Cartridge;
+use crate::mbc::Controller;
+use crate::transpile::abi::BusHooks;
+
+/// what is where on the memory map
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Rom0,
+    RomX, // the switchable bank
+    Vram,
+    CartRam,
+    Wram,
+    Echo, // wram again
+    Oam,
+    Unusable,
+    Io,
+    Hram,
+    Ie,
+}
+
+impl Region {
+    pub const fn of(addr: u16) -> Self {
+        use Region::*;
+        match addr {
+            0x0000..=0x3fff => Rom0,
+            0x4000..=0x7fff => RomX,
+            0x8000..=0x9fff => Vram,
+            0xa000..=0xbfff => CartRam,
+            0xc000..=0xdfff => Wram,
+            0xe000..=0xfdff => Echo,
+            0xfe00..=0xfe9f => Oam,
+            0xfea0..=0xfeff => Unusable,
+            0xff00..=0xff7f => Io,
+            0xff80..=0xfffe => Hram,
+            0xffff => Ie,
+        }
+    }
+
+    pub const fn is_rom(self) -> bool {
+        matches!(self, Region::Rom0 | Region::RomX)
+    }
+}
+
+pub trait Bus {
+    fn read8(&mut self, addr: u16) -> u8;
+    fn write8(&mut self, addr: u16, value: u8);
+
+    fn region(&self, addr: u16) -> Region {
+        Region::of(addr)
+    }
+
+    /// the rom bank in 0x4000..0x8000
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    /// the rom bank in 0x0000..0x4000
+    fn rom0_bank(&self) -> u16 {
+        0
+    }
+
+    /// whether stores to the rom go to a memory bank controller, and
+    /// leave it as it is
+    fn switches_banks(&self) -> bool {
+        false
+    }
+
+    /// the 64 KiB compiled code reads and writes
+    fn memory(&mut self) -> &mut [u8; 0x10000];
+}
+
+/// the slow paths of code compiled with trap_io or switch_banks, on
+/// `bus`. valid as long as bus does not move.
+pub fn hooks<B: Bus>(bus: &mut B) -> BusHooks {
+    BusHooks {
+        data: bus as *mut B as *mut c_void,
+        read: read::<B>,
+        write: write::<B>,
+        rom_bank: bus.rom_bank(),
+        rom0_switched: false,
+    }
+}
+
+// safety, for both: hooks came from hooks(), its data is the B that was
+// called on, and mem its memory(). a panic in a bus aborts.
+
+unsafe extern "sysv64" fn read<B: Bus>(
+    hooks: *mut BusHooks,
+    _: *mut u8,
+    addr: u32,
+) {
+    let bus = &mut *((*hooks).data as *mut B);
+    // compiled code reads it from memory
+    let value = bus.read8(addr as u16);
+    bus.memory()[addr as usize] = value;
+}
+
+unsafe extern "sysv64" fn write<B: Bus>(
+    hooks: *mut BusHooks,
+    _: *mut u8,
+    addr: u32,
+) {
+    let bus = &mut *((*hooks).data as *mut B);
+    let value = bus.memory()[addr as usize];
+    let rom0_bank = bus.rom0_bank();
+    bus.write8(addr as u16, value);
+    (*hooks).rom_bank = bus.rom_bank();
+    if bus.rom0_bank() != rom0_bank {
+        (*hooks).rom0_switched = true;
+    }
+}
+
+/// 64 KiB of plain memory, rom included
+#[derive(Clone)]
+pub struct Flat(pub Box<[u8; 0x10000]>);
+
+impl Default for Flat {
+    fn default() -> Self {
+        Self(Box::new([0; 0x10000]))
+    }
+}
+
+impl Deref for Flat {
+    type Target = [u8; 0x10000];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Flat {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Bus for Flat {
+    fn read8(&mut self, addr: u16) -> u8 {
+        self[addr as usize]
+    }
+
+    fn write8(&mut self, addr: u16, value: u8) {
+        self[addr as usize] = value;
+    }
+
+    fn memory(&mut self) -> &mut [u8; 0x10000] {
+        self
+    }
+}
+
+/// a cartridge, with the banks its mbc picks mapped at 0x0000, 0x4000
+/// and 0xa000. the rom reads as what is mapped, stores there go to the
+/// mbc.
+pub struct Banked {
+    rom: Cartridge,
+    mbc: Controller,
+    ram: Vec<u8>,
+    // what is in memory at 0x0000, 0x4000 and 0xa000
+    rom0_bank: u16,
+    rom_bank: u16,
+    ram_bank: Option<u8>,
+    mem: Flat,
+}
+
+impl Banked {
+    /// `rom`, as it comes out of reset. None if its mbc is not one of
+    /// those in mbc.rs.
+    pub fn new(rom: Cartridge) -> Option<Self> {
+        let mbc = Controller::new(rom.header().mbc())?;
+        let ram = vec![0; rom.header().ram_size];
+        let mut mem = Flat::default();
+        mem[0xa000..0xc000].fill(0xff);
+        let mut banked = Self {
+            rom,
+            mbc,
+            ram,
+            rom0_bank: u16::MAX,
+            rom_bank: u16::MAX,
+            ram_bank: None,
+            mem,
+        };
+        banked.map_rom();
+        banked.map_ram();
+        Some(banked)
+    }
+
+    /// all of the cartridge's ram, for saving it
+    pub fn ram(&mut self) -> &[u8] {
+        self.unmap_ram();
+        &self.ram
+    }
+
+    fn ram_window(&mut self, bank: u8) -> (&mut [u8], &mut [u8]) {
+        let start = (bank as usize * 0x2000).min(self.ram.len());
+        let end = (start + 0x2000).min(self.ram.len());
+        (&mut self.ram[start..end], &mut self.mem[0xa000..0xc000])
+    }
+
+    /// brings 0x0000..0x8000 in line with the mbc
+    fn map_rom(&mut self) {
+        let banks = self.rom.header().rom_banks() as u16;
+        let bank = self.mbc.rom0_bank() % banks;
+        if bank != self.rom0_bank {
+            self.copy_bank(bank, 0x0000);
+            self.rom0_bank = bank;
+        }
+        let bank = self.mbc.rom_bank() % banks;
+        if bank != self.rom_bank {
+            self.copy_bank(bank, 0x4000);
+            self.rom_bank = bank;
+        }
+    }
+
+    /// `bank` into the window at `at`. what the rom does not have reads
+    /// as 0xff.
+    fn copy_bank(&mut self, bank: u16, at: usize) {
+        let window = &mut self.mem[at..at + 0x4000];
+        let bytes = self.rom.bank(bank);
+        window[..bytes.len()].copy_from_slice(bytes);
+        window[bytes.len()..].fill(0xff);
+    }
+
+    /// what compiled code wrote to the ram window goes back to its bank
+    fn unmap_ram(&mut self) {
+        if let Some(bank) = self.ram_bank {
+            let (ram, window) = self.ram_window(bank);
+            let len = ram.len();
+            ram.copy_from_slice(&window[..len]);
+        }
+    }
+
+    /// brings 0xa000..0xc000 in line with the mbc. without ram there it
+    /// reads as 0xff.
+    fn map_ram(&mut self) {
+        let banks = self.ram.len().div_ceil(0x2000) as u8;
+        let bank = self.mbc.ram_bank().filter(|_| banks > 0);
+        let bank = bank.map(|bank| bank % banks);
+        if bank == self.ram_bank {
+            return;
+        }
+        self.unmap_ram();
+        self.ram_bank = bank;
+        let (ram, window) = match bank {
+            Some(bank) => self.ram_window(bank),
+            None => (&mut [][..], &mut self.mem[0xa000..0xc000]),
+        };
+        window[..ram.len()].copy_from_slice(ram);
+        window[ram.len()..].fill(0xff);
+    }
+
+    /// what the rom has at `addr`, as mapped
+    fn rom_byte(&self, addr: u16) -> u8 {
+        let (bank, offset) = match addr {
+            ..0x4000 => (self.rom0_bank, addr),
+            _ => (self.rom_bank, addr - 0x4000),
+        };
+        let bytes = self.rom.bank(bank);
+        bytes.get(offset as usize).copied().unwrap_or(0xff)
+    }
+}
+
+impl Bus for Banked {
+    fn read8(&mut self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    fn write8(&mut self, addr: u16, value: u8) {
+        match self.region(addr) {
+            Region::Rom0 | Region::RomX => {
+                self.mbc.write(addr, value);
+                self.map_rom();
+                self.map_ram();
+                // compiled code stores first, and asks after
+                self.mem[addr as usize] = self.rom_byte(addr);
+            }
+            // no ram mapped, the store goes nowhere
+            Region::CartRam if self.ram_bank.is_none() => {
+                self.mem[addr as usize] = 0xff;
+            }
+            _ => self.mem[addr as usize] = value,
+        }
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank
+    }
+
+    fn rom0_bank(&self) -> u16 {
+        self.rom0_bank
+    }
+
+    fn switches_banks(&self) -> bool {
+        true
+    }
+
+    fn memory(&mut self) -> &mut [u8; 0x10000] {
+        &mut self.mem
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// `bus`, with every access through it in `log`. what compiled code does
+/// to memory() directly is not in there.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Recording<B> {
+    pub bus: B,
+    pub log: Vec<Access>,
+}
+
+#[cfg(test)]
+impl<B: Bus> Recording<B> {
+    pub fn new(bus: B) -> Self {
+        Self { bus, log: vec![] }
+    }
+}
+
+#[cfg(test)]
+impl<B: Bus> Bus for Recording<B> {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let value = self.bus.read8(addr);
+        self.log.push(Access::Read(addr, value));
+        value
+    }
+
+    fn write8(&mut self, addr: u16, value: u8) {
+        self.log.push(Access::Write(addr, value));
+        self.bus.write8(addr, value);
+    }
+
+    fn region(&self, addr: u16) -> Region {
+        self.bus.region(addr)
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.bus.rom_bank()
+    }
+
+    fn rom0_bank(&self) -> u16 {
+        self.bus.rom0_bank()
+    }
+
+    fn switches_banks(&self) -> bool {
+        self.bus.switches_banks()
+    }
+
+    fn memory(&mut self) -> &mut [u8; 0x10000] {
+        self.bus.memory()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{test_rom as rom, test_rom_from};
+    use crate::interp::{Cpu, Exit};
+    use crate::sm83::asm::asm;
+
+    #[test]
+    fn interpreter_goes_through_the_bus() {
+        let code = asm!(
+            0x150,
+            "       ld hl, $c000
+                    ld [hl], $12
+                    ldh a, [$44]
+                    push hl
+                    halt"
+        );
+        let mut cpu = Cpu::new(Recording::new(Flat::default()));
+        cpu.mem.memory()[..0x8000].copy_from_slice(rom(&code).rom());
+        cpu.mem.memory()[0xff44] = 0x90;
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        assert_eq!(cpu.run_until(10, |_| false), Exit::Halt);
+
+        // everything but the fetches
+        let data: Vec<_> = cpu
+            .mem
+            .log
+            .iter()
+            .filter(|&&(Access::Read(addr, _) | Access::Write(addr, _))| {
+                !Region::of(addr).is_rom()
+            })
+            .collect();
+        use Access::*;
+        assert_eq!(
+            data,
+            [
+                &Write(0xc000, 0x12),
+                &Read(0xff44, 0x90),
+                &Write(0xfffc, 0x00),
+                &Write(0xfffd, 0xc0),
+            ]
+        );
+    }
+
+    #[test]
+    fn banked_maps_what_the_mbc_says() {
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x4000] = 1;
+        bytes[0x8000] = 2;
+        bytes[0xc000] = 3;
+        // mbc5, 2 banks of ram
+        bytes[0x147] = 0x1a;
+        bytes[0x149] = 0x03;
+        let mut bus = Banked::new(test_rom_from(bytes)).unwrap();
+        assert_eq!((bus.rom_bank(), bus.read8(0x4000)), (1, 1));
+        bus.write8(0x2000, 3);
+        assert_eq!((bus.rom_bank(), bus.read8(0x4000)), (3, 3));
+        // past the end of the rom it wraps around
+        bus.write8(0x2000, 6);
+        assert_eq!((bus.rom_bank(), bus.read8(0x4000)), (2, 2));
+        assert_eq!(bus.memory()[0x2000], 0);
+
+        // off, then a bank each
+        bus.write8(0xa000, 0x55);
+        assert_eq!(bus.read8(0xa000), 0xff);
+        bus.write8(0x0000, 0x0a);
+        bus.write8(0xa000, 0x55);
+        bus.write8(0x4000, 1);
+        assert_eq!(bus.read8(0xa000), 0);
+        // like compiled code would
+        bus.memory()[0xa001] = 0x66;
+        bus.write8(0x4000, 0);
+        assert_eq!(bus.read8(0xa000), 0x55);
+        assert_eq!(&bus.ram()[0x2000..0x2002], [0, 0x66]);
+    }
+
+    #[test]
+    fn mbc1_mode_1_maps_bank_0x20_at_0() {
+        // 1 MiB, where the high bits pick from 32 banks at a time
+        let mut bytes = vec![0; 0x100000];
+        bytes[0x80010] = 0x20;
+        bytes[0x84010] = 0x21;
+        bytes[0x147] = 0x01;
+        let mut bus = Banked::new(test_rom_from(bytes)).unwrap();
+        bus.write8(0x4000, 1);
+        assert_eq!((bus.rom0_bank(), bus.rom_bank()), (0, 0x21));
+        assert_eq!((bus.read8(0x0010), bus.read8(0x4010)), (0, 0x21));
+        bus.write8(0x6000, 1);
+        assert_eq!((bus.rom0_bank(), bus.rom_bank()), (0x20, 0x21));
+        assert_eq!((bus.read8(0x0010), bus.read8(0x4010)), (0x20, 0x21));
+        // stores there leave what is mapped
+        bus.write8(0x0010, 0x0a);
+        assert_eq!(bus.read8(0x0010), 0x20);
+        bus.write8(0x6000, 0);
+        assert_eq!(bus.read8(0x0010), 0);
+    }
+}
@@ -0,0 +1,579 @@
+use std::fmt;
+use std::path::Path;
+
+// the header lives at 0x100..0x150 in bank 0
+pub const HEADER_END: usize = 0x150;
+
+pub const BANK_SIZE: usize = 0x4000;
+
+const TITLE: std::ops::Range<usize> = 0x134..0x144;
+const CGB_FLAG: usize = 0x143;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const HEADER_CHECKSUM: usize = 0x14d;
+const GLOBAL_CHECKSUM: usize = 0x14e;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    // the image does not even contain a full header
+    TooSmall(usize),
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    // the file length does not match the ROM size byte
+    SizeMismatch { expected: usize, actual: usize },
+    HeaderChecksum { expected: u8, actual: u8 },
+    GlobalChecksum { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use CartridgeError::*;
+        match self {
+            Io(e) => write!(f, "could not read the rom: {e}"),
+            TooSmall(len) => write!(
+                f,
+                "rom is {len:#x} bytes, too small to hold a header"
+            ),
+            UnknownCartridgeType(t) => {
+                write!(f, "unknown cartridge type ${t:02x}")
+            }
+            UnknownRomSize(s) => write!(f, "unknown rom size ${s:02x}"),
+            UnknownRamSize(s) => write!(f, "unknown ram size ${s:02x}"),
+            SizeMismatch { expected, actual } => write!(
+                f,
+                "header says the rom is {expected:#x} bytes, \
+                 but the image is {actual:#x} bytes"
+            ),
+            HeaderChecksum { expected, actual } => write!(
+                f,
+                "bad header checksum: header says ${expected:02x}, \
+                 computed ${actual:02x}"
+            ),
+            GlobalChecksum { expected, actual } => write!(
+                f,
+                "bad global checksum: header says ${expected:04x}, \
+                 computed ${actual:04x}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<std::io::Error> for CartridgeError {
+    fn from(e: std::io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    DmgOnly,     // anything else
+    CgbEnhanced, // $80
+    CgbOnly,     // $c0
+}
+
+/// the memory bank controller, which decides the memory model we compile
+/// against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: Mbc,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub const fn from_code(code: u8) -> Option<Self> {
+        use Mbc::*;
+        // (mbc, ram, battery, timer, rumble)
+        let (mbc, ram, battery, timer, rumble) = match code {
+            0x00 => (None, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false),
+            0x03 => (Mbc1, true, true, false, false),
+            0x05 => (Mbc2, false, false, false, false),
+            0x06 => (Mbc2, false, true, false, false),
+            0x08 => (None, true, false, false, false),
+            0x09 => (None, true, true, false, false),
+            0x0b => (Mmm01, false, false, false, false),
+            0x0c => (Mmm01, true, false, false, false),
+            0x0d => (Mmm01, true, true, false, false),
+            0x0f => (Mbc3, false, true, true, false),
+            0x10 => (Mbc3, true, true, true, false),
+            0x11 => (Mbc3, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false),
+            0x13 => (Mbc3, true, true, false, false),
+            0x19 => (Mbc5, false, false, false, false),
+            0x1a => (Mbc5, true, false, false, false),
+            0x1b => (Mbc5, true, true, false, false),
+            0x1c => (Mbc5, false, false, false, true),
+            0x1d => (Mbc5, true, false, false, true),
+            0x1e => (Mbc5, true, true, false, true),
+            0x20 => (Mbc6, true, true, false, false),
+            0x22 => (Mbc7, true, true, false, true),
+            0xfc => (PocketCamera, true, true, false, false),
+            0xfd => (Tama5, true, true, false, false),
+            0xfe => (HuC3, true, true, true, false),
+            0xff => (HuC1, true, true, false, false),
+            _ => return Option::None,
+        };
+        Some(Self {
+            code,
+            mbc,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize, // in bytes
+    pub ram_size: usize, // in bytes; MBC2 has its ram built in
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    /// parses the header out of `rom`, without validating the checksums.
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let cgb = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::CgbEnhanced,
+            0xc0 => CgbSupport::CgbOnly,
+            _ => CgbSupport::DmgOnly,
+        };
+        // on cgb carts the last title byte is the cgb flag
+        let title = match cgb {
+            CgbSupport::DmgOnly => &rom[TITLE],
+            _ => &rom[TITLE.start..CGB_FLAG],
+        };
+        let title = title
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(
+                |&c| if c.is_ascii_graphic() { c as char } else { ' ' },
+            )
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let cartridge_type = CartridgeType::from_code(
+            rom[CARTRIDGE_TYPE],
+        )
+        .ok_or(CartridgeError::UnknownCartridgeType(
+            rom[CARTRIDGE_TYPE],
+        ))?;
+        let rom_size = match rom[ROM_SIZE] {
+            n @ 0x00..=0x08 => (2 * BANK_SIZE) << n,
+            // unofficial sizes, listed in some docs
+            0x52 => 72 * BANK_SIZE,
+            0x53 => 80 * BANK_SIZE,
+            0x54 => 96 * BANK_SIZE,
+            n => return Err(CartridgeError::UnknownRomSize(n)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 0x800, // unused, but seen in the wild
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            n => return Err(CartridgeError::UnknownRamSize(n)),
+        };
+
+        Ok(Self {
+            title,
+            cgb,
+            sgb: rom[SGB_FLAG] == 0x03,
+            cartridge_type,
+            rom_size,
+            ram_size,
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([
+                rom[GLOBAL_CHECKSUM],
+                rom[GLOBAL_CHECKSUM + 1],
+            ]),
+        })
+    }
+
+    pub fn rom_banks(&self) -> usize {
+        self.rom_size / BANK_SIZE
+    }
+
+    pub fn mbc(&self) -> Mbc {
+        self.cartridge_type.mbc
+    }
+}
+
+/// the checksum the boot rom verifies, over 0x134..=0x14c
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..=0x14c]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+/// sum of every byte in the rom, besides the checksum itself
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| {
+            i != GLOBAL_CHECKSUM && i != GLOBAL_CHECKSUM + 1
+        })
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+}
+
+#[derive(Clone)]
+pub struct Cartridge {
+    header: Header,
+    rom: Box<[u8]>,
+}
+
+impl Cartridge {
+    pub fn load(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, CartridgeError> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// parses and validates a rom image. the global checksum is left to
+    /// `verify`: the hardware never looks at it, so dumps and homebrew
+    /// that get it wrong still run.
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let header = Header::parse(&rom)?;
+
+        if rom.len() != header.rom_size {
+            return Err(CartridgeError::SizeMismatch {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+        let actual = header_checksum(&rom);
+        if actual != header.header_checksum {
+            return Err(CartridgeError::HeaderChecksum {
+                expected: header.header_checksum,
+                actual,
+            });
+        }
+
+        Ok(Self {
+            header,
+            rom: rom.into_boxed_slice(),
+        })
+    }
+
+    /// the checks `from_bytes` does not do, i.e. the global checksum
+    pub fn verify(&self) -> Result<(), CartridgeError> {
+        let actual = global_checksum(&self.rom);
+        if actual != self.header.global_checksum {
+            return Err(CartridgeError::GlobalChecksum {
+                expected: self.header.global_checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    #[cfg(test)]
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// the 16 KiB of `bank`, or what the rom has of them
+    pub fn bank(&self, bank: u16) -> &[u8] {
+        let start = (bank as usize * BANK_SIZE).min(self.rom.len());
+        let end = (start + BANK_SIZE).min(self.rom.len());
+        &self.rom[start..end]
+    }
+
+    /// the 16 KiB of `bank`, or what the rom has of them
+    pub fn bank_mut(&mut self, bank: u16) -> &mut [u8] {
+        let start = (bank as usize * BANK_SIZE).min(self.rom.len());
+        let end = (start + BANK_SIZE).min(self.rom.len());
+        &mut self.rom[start..end]
+    }
+
+    /// the 3 bytes at `addr`, enough to decode any instruction. reads
+    /// past the end of the bank or the rom give 0.
+    pub fn instr_bytes(&self, addr: BankedAddr) -> [u8; 3] {
+        let mut bytes = [0; 3];
+        let window_end = if addr.addr < 0x4000 { 0x4000 } else { 0x8000 };
+        for (i, b) in bytes.iter_mut().enumerate() {
+            if addr.addr as usize + i >= window_end {
+                break;
+            }
+            if let Some(&x) = self.rom.get(addr.rom_offset() + i) {
+                *b = x;
+            }
+        }
+        bytes
+    }
+}
+
+/// where code is decoded from: the rom, or what is in memory right now
+pub trait CodeSource {
+    /// the 3 bytes at `addr`, enough to decode any instruction
+    fn instr_bytes(&self, addr: BankedAddr) -> [u8; 3];
+}
+
+impl CodeSource for Cartridge {
+    fn instr_bytes(&self, addr: BankedAddr) -> [u8; 3] {
+        Cartridge::instr_bytes(self, addr)
+    }
+}
+
+/// the whole address space, with the rom in it as mapped
+impl CodeSource for [u8; 0x10000] {
+    fn instr_bytes(&self, addr: BankedAddr) -> [u8; 3] {
+        [0, 1, 2].map(|i| self[addr.addr.wrapping_add(i) as usize])
+    }
+}
+
+/// an address in rom, qualified with the bank it lives in. code in
+/// 0x0000..0x4000 is always bank 0, 0x4000..0x8000 is whatever bank the
+/// mbc has switched in. ram is bank 0 too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BankedAddr {
+    pub bank: u16,
+    pub addr: u16,
+}
+
+impl BankedAddr {
+    /// `addr` as seen while `mapped_bank` is switched into 0x4000..0x8000
+    pub const fn new(addr: u16, mapped_bank: u16) -> Self {
+        let bank = match addr {
+            0x4000..=0x7fff => mapped_bank,
+            _ => 0,
+        };
+        Self { bank, addr }
+    }
+
+    /// another address as seen from the code at `self`. jumps out of a
+    /// switchable bank stay in that bank. code in bank 0 does not know
+    /// what is mapped, so it gets bank 1, which every mbc maps on reset.
+    pub const fn to(self, addr: u16) -> Self {
+        let mapped_bank = if self.bank == 0 { 1 } else { self.bank };
+        Self::new(addr, mapped_bank)
+    }
+
+    pub const fn rom_offset(self) -> usize {
+        self.bank as usize * BANK_SIZE + (self.addr as usize % BANK_SIZE)
+    }
+}
+
+impl From<BankedAddr> for usize {
+    fn from(a: BankedAddr) -> usize {
+        a.rom_offset()
+    }
+}
+
+impl fmt::Display for BankedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // rgbds style
+        write!(f, "{:02x}:{:04x}", self.bank, self.addr)
+    }
+}
+
+/// a 32 KiB rom with `code` at 0x150, for tests
+#[cfg(test)]
+pub fn test_rom(code: &[u8]) -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    test_rom_from(rom)
+}
+
+/// `rom` with its size and checksums in the header, for tests
+#[cfg(test)]
+pub fn test_rom_from(mut rom: Vec<u8>) -> Cartridge {
+    rom[ROM_SIZE] = (rom.len() / (2 * BANK_SIZE)).trailing_zeros() as u8;
+    fix_checksums(&mut rom);
+    Cartridge::from_bytes(rom).unwrap()
+}
+
+#[cfg(test)]
+fn fix_checksums(rom: &mut [u8]) {
+    rom[HEADER_CHECKSUM] = header_checksum(rom);
+    let [hi, lo] = global_checksum(rom).to_be_bytes();
+    rom[GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2].copy_from_slice(&[hi, lo]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a 32 KiB image with `title` and good checksums
+    fn image(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE.start..TITLE.start + title.len()].copy_from_slice(title);
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let mut rom = vec![0; 0x10000];
+        rom[TITLE.start..TITLE.start + 5].copy_from_slice(b"HELLO");
+        rom[SGB_FLAG] = 0x03;
+        rom[CARTRIDGE_TYPE] = 0x13;
+        rom[ROM_SIZE] = 0x01;
+        rom[RAM_SIZE] = 0x03;
+        fix_checksums(&mut rom);
+        let cart = Cartridge::from_bytes(rom).unwrap();
+        let header = cart.header();
+        assert_eq!(header.title, "HELLO");
+        assert_eq!(header.cgb, CgbSupport::DmgOnly);
+        assert!(header.sgb);
+        assert_eq!(header.mbc(), Mbc::Mbc3);
+        assert!(header.cartridge_type.ram && header.cartridge_type.battery);
+        assert_eq!((header.rom_size, header.rom_banks()), (0x10000, 4));
+        assert_eq!(header.ram_size, 0x8000);
+    }
+
+    #[test]
+    fn cgb_titles_stop_before_the_flag() {
+        let title = |flag| {
+            let mut rom = image(b"ABCDEFGHIJKLMNO");
+            rom[CGB_FLAG] = flag;
+            let header = Header::parse(&rom).unwrap();
+            (header.title, header.cgb)
+        };
+        assert_eq!(
+            title(b'P'),
+            ("ABCDEFGHIJKLMNOP".to_string(), CgbSupport::DmgOnly)
+        );
+        assert_eq!(
+            title(0x80),
+            ("ABCDEFGHIJKLMNO".to_string(), CgbSupport::CgbEnhanced)
+        );
+        assert_eq!(
+            title(0xc0),
+            ("ABCDEFGHIJKLMNO".to_string(), CgbSupport::CgbOnly)
+        );
+        // anything unprintable is a space, and the end is trimmed
+        let rom = image(b"A\x01B  ");
+        assert_eq!(Header::parse(&rom).unwrap().title, "A B");
+    }
+
+    #[test]
+    fn rejects_corrupt_dumps() {
+        use CartridgeError::*;
+        let load = |rom| Cartridge::from_bytes(rom).err().unwrap();
+        let with = |at: usize, value| {
+            let mut rom = image(b"");
+            rom[at] = value;
+            fix_checksums(&mut rom);
+            rom
+        };
+
+        assert!(matches!(load(vec![0; 0x14f]), TooSmall(0x14f)));
+        assert!(matches!(
+            load(with(CARTRIDGE_TYPE, 0x04)),
+            UnknownCartridgeType(0x04)
+        ));
+        assert!(matches!(load(with(ROM_SIZE, 0x09)), UnknownRomSize(0x09)));
+        assert!(matches!(load(with(RAM_SIZE, 0x06)), UnknownRamSize(0x06)));
+        assert!(matches!(
+            load(with(ROM_SIZE, 0x01)),
+            SizeMismatch {
+                expected: 0x10000,
+                actual: 0x8000
+            }
+        ));
+
+        // a flipped bit after the checksums were made
+        let mut rom = image(b"");
+        rom[TITLE.start] ^= 1;
+        assert!(matches!(
+            load(rom),
+            HeaderChecksum {
+                expected: 0xe7,
+                actual: 0xe6
+            }
+        ));
+
+        // a bad global checksum loads, but does not verify
+        let mut rom = image(b"");
+        rom[0x200] ^= 1;
+        let expected = global_checksum(&image(b""));
+        let cart = Cartridge::from_bytes(rom).unwrap();
+        assert!(matches!(
+            cart.verify(),
+            Err(GlobalChecksum { expected: e, actual: a })
+                if e == expected && a == expected + 1
+        ));
+        assert!(Cartridge::from_bytes(image(b"")).unwrap().verify().is_ok());
+    }
+
+    #[test]
+    fn banked_addrs() {
+        assert_eq!(BankedAddr::new(0x150, 5).bank, 0);
+        assert_eq!(BankedAddr::new(0x4100, 5).bank, 5);
+        assert_eq!(BankedAddr::new(0xc000, 5).bank, 0);
+
+        // bank 0 assumes bank 1, banked code stays in its bank
+        let home = BankedAddr::new(0x150, 0);
+        assert_eq!(home.to(0x4000), BankedAddr::new(0x4000, 1));
+        let far = BankedAddr::new(0x4000, 3);
+        assert_eq!(far.to(0x7000), BankedAddr::new(0x7000, 3));
+        assert_eq!(far.to(0x0038), BankedAddr::new(0x0038, 0));
+        assert_eq!(far.to(0xff80), BankedAddr::new(0xff80, 0));
+
+        assert_eq!(home.rom_offset(), 0x150);
+        assert_eq!(BankedAddr::new(0x4010, 1).rom_offset(), 0x4010);
+        assert_eq!(BankedAddr::new(0x4010, 3).rom_offset(), 0xc010);
+        assert_eq!(BankedAddr::new(0x4010, 3).to_string(), "03:4010");
+    }
+
+    #[test]
+    fn instr_bytes_stop_at_the_bank() {
+        let mut rom = image(b"");
+        rom[0x3ffe..0x4001].copy_from_slice(&[0xcd, 0x34, 0x12]);
+        rom[0x7ffe..].copy_from_slice(&[0xcd, 0x34]);
+        fix_checksums(&mut rom);
+        let cart = Cartridge::from_bytes(rom).unwrap();
+        let at = |bank, addr| cart.instr_bytes(BankedAddr { bank, addr });
+
+        assert_eq!(at(0, 0x3ffd), [0, 0xcd, 0x34]);
+        assert_eq!(at(0, 0x3ffe), [0xcd, 0x34, 0]);
+        assert_eq!(at(1, 0x4000), [0x12, 0, 0]);
+        assert_eq!(at(1, 0x7ffe), [0xcd, 0x34, 0]);
+        // one past the rom
+        assert_eq!(at(2, 0x4000), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,418 @@
+// differential fuzzing. random straight-line code from random states goes
+// through the interpreter and through a subject, usually the recompiler,
+// and the end states are compared. the first divergence gets cut down to
+// something small enough to read.
+
+use std::fmt;
+
+use crate::cartridge::BankedAddr;
+use crate::interp::{Cpu, Regs};
+use crate::jit::Jit;
+use crate::sm83::*;
+use crate::transpile::abi::ExitReason;
+use crate::transpile::Context;
+
+/// where the generated code pretends to live
+pub const ORIGIN: u16 = 0x0150;
+
+/// something that runs a straight-line sequence of instructions
+pub trait Executor {
+    fn name(&self) -> &str;
+
+    /// whether `instr` may show up in the code given to `run`
+    fn supports(&self, _instr: Instruction) -> bool {
+        true
+    }
+
+    /// the flags it promises to get right, as a mask of F
+    fn exact_flags(&self) -> u8 {
+        0xf0
+    }
+
+    /// runs `code` as if it was at ORIGIN, leaving the result in `cpu`
+    fn run(
+        &mut self,
+        code: &[Instruction],
+        cpu: &mut Cpu,
+    ) -> Result<(), String>;
+}
+
+/// the reference everything else is compared to
+pub struct Interp;
+
+impl Executor for Interp {
+    fn name(&self) -> &str {
+        "interp"
+    }
+
+    fn run(
+        &mut self,
+        code: &[Instruction],
+        cpu: &mut Cpu,
+    ) -> Result<(), String> {
+        cpu.regs.pc = ORIGIN;
+        for &instr in code {
+            cpu.execute(instr);
+        }
+        Ok(())
+    }
+}
+
+/// the code as one recompiled block, run in process
+pub struct Recompiled {
+    // see Context::accurate_flags
+    pub accurate_flags: bool,
+}
+
+impl Executor for Recompiled {
+    fn name(&self) -> &str {
+        match self.accurate_flags {
+            true => "jit-accurate",
+            false => "jit",
+        }
+    }
+
+    fn run(
+        &mut self,
+        code: &[Instruction],
+        cpu: &mut Cpu,
+    ) -> Result<(), String> {
+        let mut ctx = Context::default();
+        ctx.accurate_flags = self.accurate_flags;
+        let start = BankedAddr::new(ORIGIN, 0);
+        ctx.compile_instrs(start, code).map_err(|e| e.to_string())?;
+        let jit = Jit::new(&mut ctx, &[start]).map_err(|e| e.to_string())?;
+
+        let end = code.iter().fold(ORIGIN, |pc, instr| pc + instr.len());
+        match jit.run(start, cpu) {
+            Some(ExitReason::Jump) if cpu.regs.pc == end => Ok(()),
+            exit => Err(format!("left with {exit:?} at {:04x}", cpu.regs.pc)),
+        }
+    }
+}
+
+/// the executors `fuzz` on the command line knows about
+pub fn executor(name: &str) -> Option<Box<dyn Executor>> {
+    match name {
+        "interp" => Some(Box::new(Interp)),
+        "jit" => Some(Box::new(Recompiled {
+            accurate_flags: false,
+        })),
+        "jit-accurate" => Some(Box::new(Recompiled {
+            accurate_flags: true,
+        })),
+        _ => None,
+    }
+}
+
+/// xorshift64*. plenty for fuzzing, and the same on every machine
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // never zero, and nearby seeds end up far apart
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// code that runs from start to end, with no way to leave early
+fn straight_line(instr: Instruction) -> bool {
+    use Instruction::*;
+    matches!(instr.flow(ORIGIN), Flow::Next)
+        && !matches!(instr, HALT | STOP(_) | Invalid(_) | EI | DI)
+}
+
+#[derive(Clone)]
+pub struct Case {
+    pub seed: u64,
+    pub code: Vec<Instruction>,
+    pub init: Cpu,
+}
+
+impl Case {
+    /// `len` random instructions `subject` supports, from a random state
+    pub fn generate(seed: u64, len: usize, subject: &dyn Executor) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut init = Cpu::default();
+        rng.fill(&mut init.mem[..]);
+        let [a, f, b, c, d, e, h, l] = rng.u64().to_le_bytes();
+        init.regs = Regs {
+            a,
+            f: f & 0xf0,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp: rng.u64() as u16,
+            pc: ORIGIN,
+        };
+
+        let mut code = Vec::with_capacity(len);
+        while code.len() < len {
+            // every opcode equally likely, the cb ones included
+            let [op, b1, b2, cb, ..] = rng.u64().to_le_bytes();
+            let bytes = if cb & 1 == 0 {
+                [op, b1, b2]
+            } else {
+                [0xcb, op, b1]
+            };
+            let instr = decode_instr(bytes);
+            if straight_line(instr) && subject.supports(instr) {
+                code.push(instr);
+            }
+        }
+        Self { seed, code, init }
+    }
+}
+
+/// where two end states differ, oracle first
+fn diff(oracle: &Cpu, subject: &Cpu, flags: u8) -> Vec<String> {
+    let (o, s) = (&oracle.regs, &subject.regs);
+    let mut diffs = vec![];
+    for (name, o, s) in [
+        ("a", o.a, s.a),
+        ("f", o.f & flags, s.f & flags),
+        ("b", o.b, s.b),
+        ("c", o.c, s.c),
+        ("d", o.d, s.d),
+        ("e", o.e, s.e),
+        ("h", o.h, s.h),
+        ("l", o.l, s.l),
+    ] {
+        if o != s {
+            diffs.push(format!("{name}: {o:02x} vs {s:02x}"));
+        }
+    }
+    if o.sp != s.sp {
+        diffs.push(format!("sp: {:04x} vs {:04x}", o.sp, s.sp));
+    }
+
+    let mem: Vec<usize> = (0..0x10000)
+        .filter(|&i| oracle.mem[i] != subject.mem[i])
+        .collect();
+    for &addr in mem.iter().take(4) {
+        diffs.push(format!(
+            "[{addr:04x}]: {:02x} vs {:02x}",
+            oracle.mem[addr], subject.mem[addr]
+        ));
+    }
+    if mem.len() > 4 {
+        diffs.push(format!("and {} more bytes", mem.len() - 4));
+    }
+    diffs
+}
+
+fn check(
+    oracle: &mut dyn Executor,
+    subject: &mut dyn Executor,
+    code: &[Instruction],
+    init: &Cpu,
+) -> Result<(), Vec<String>> {
+    let mut o = init.clone();
+    if let Err(e) = oracle.run(code, &mut o) {
+        panic!("the oracle failed: {e}");
+    }
+    let mut s = init.clone();
+    if let Err(e) = subject.run(code, &mut s) {
+        return Err(vec![format!("{}: {e}", subject.name())]);
+    }
+    let diffs = diff(&o, &s, oracle.exact_flags() & subject.exact_flags());
+    if diffs.is_empty() {
+        Ok(())
+    } else {
+        Err(diffs)
+    }
+}
+
+/// cuts a diverging case down: up to the first instruction that goes
+/// wrong, then as few instructions and as many zero registers as possible
+fn minimize(
+    oracle: &mut dyn Executor,
+    subject: &mut dyn Executor,
+    mut case: Case,
+) -> (Case, Vec<String>) {
+    let diverges =
+        |oracle: &mut dyn Executor, subject: &mut dyn Executor, case: &Case| {
+            check(oracle, subject, &case.code, &case.init).is_err()
+        };
+
+    let first = (1..=case.code.len())
+        .find(|&n| check(oracle, subject, &case.code[..n], &case.init).is_err())
+        .expect("the case does not diverge");
+    case.code.truncate(first);
+
+    // the two agree right before the last instruction, so starting from
+    // there usually leaves just that one
+    let (head, last) = case.code.split_at(first - 1);
+    let mut before = case.init.clone();
+    let _ = oracle.run(head, &mut before);
+    before.regs.pc = ORIGIN;
+    let single = Case {
+        code: last.to_vec(),
+        init: before,
+        ..case.clone()
+    };
+    if diverges(oracle, subject, &single) {
+        case = single;
+    } else {
+        let mut i = 0;
+        while i + 1 < case.code.len() {
+            let mut shorter = case.clone();
+            shorter.code.remove(i);
+            if diverges(oracle, subject, &shorter) {
+                case = shorter;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    let zero: [fn(&mut Regs); 9] = [
+        |r| r.a = 0,
+        |r| r.f = 0,
+        |r| r.b = 0,
+        |r| r.c = 0,
+        |r| r.d = 0,
+        |r| r.e = 0,
+        |r| r.h = 0,
+        |r| r.l = 0,
+        |r| r.sp = 0,
+    ];
+    for zero in zero {
+        let mut simpler = case.clone();
+        zero(&mut simpler.init.regs);
+        if diverges(oracle, subject, &simpler) {
+            case = simpler;
+        }
+    }
+
+    let diffs = check(oracle, subject, &case.code, &case.init).unwrap_err();
+    (case, diffs)
+}
+
+pub struct Divergence {
+    pub oracle: String,
+    pub subject: String,
+    pub case: Case, // minimized
+    pub diffs: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} diverges from {} in case {}",
+            self.subject, self.oracle, self.case.seed
+        )?;
+        writeln!(f, "from {}", self.case.init.regs)?;
+        for instr in &self.case.code {
+            writeln!(f, "    {instr}")?;
+        }
+        writeln!(f, "{} vs {}:", self.oracle, self.subject)?;
+        for diff in &self.diffs {
+            writeln!(f, "    {diff}")?;
+        }
+        Ok(())
+    }
+}
+
+/// runs `cases` random cases of `len` instructions, seeded `seed`,
+/// `seed + 1` and so on, and reports the first divergence. returns how
+/// many cases passed.
+pub fn fuzz(
+    oracle: &mut dyn Executor,
+    subject: &mut dyn Executor,
+    seed: u64,
+    cases: u64,
+    len: usize,
+) -> Result<u64, Box<Divergence>> {
+    for i in 0..cases {
+        let case = Case::generate(seed.wrapping_add(i), len, subject);
+        if check(oracle, subject, &case.code, &case.init).is_err() {
+            let (case, diffs) = minimize(oracle, subject, case);
+            return Err(Box::new(Divergence {
+                oracle: oracle.name().into(),
+                subject: subject.name().into(),
+                case,
+                diffs,
+            }));
+        }
+    }
+    Ok(cases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the interpreter with a bug planted: rlc leaves z alone, like rol
+    /// does with ZF on x86
+    struct Rol;
+
+    impl Executor for Rol {
+        fn name(&self) -> &str {
+            "rol"
+        }
+
+        fn run(
+            &mut self,
+            code: &[Instruction],
+            cpu: &mut Cpu,
+        ) -> Result<(), String> {
+            cpu.regs.pc = ORIGIN;
+            for &instr in code {
+                let z = cpu.regs.f & 0x80;
+                cpu.execute(instr);
+                if let Instruction::Prefix(PrefixOp::RLC, _) = instr {
+                    cpu.regs.f = cpu.regs.f & !0x80 | z;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interp_agrees_with_itself() {
+        fuzz(&mut Interp, &mut Interp, 0, 200, 32)
+            .unwrap_or_else(|d| panic!("{d}"));
+    }
+
+    #[test]
+    fn jit_agrees_with_interp() {
+        for accurate_flags in [false, true] {
+            let mut jit = Recompiled { accurate_flags };
+            fuzz(&mut Interp, &mut jit, 0, 100, 16)
+                .unwrap_or_else(|d| panic!("{d}"));
+        }
+    }
+
+    #[test]
+    fn finds_and_minimizes() {
+        let d = fuzz(&mut Interp, &mut Rol, 0, 1000, 32)
+            .expect_err("the planted bug went unnoticed");
+        assert!(
+            matches!(d.case.code[..], [Instruction::Prefix(PrefixOp::RLC, _)]),
+            "{d}"
+        );
+        assert_eq!(d.diffs.len(), 1, "{d}");
+        assert!(d.diffs[0].starts_with("f: "), "{d}");
+    }
+}
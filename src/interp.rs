@@ -0,0 +1,524 @@
+// a plain, slow, obviously correct sm83 interpreter. it is the oracle
+// the recompiled code is checked against, and the fallback for code we
+// can not compile statically.
+
+use crate::bus::{Bus, Flat};
+use crate::sm83::{self, *};
+
+const Z: u8 = 0x80;
+const N: u8 = 0x40;
+const H: u8 = 0x20;
+const CY: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Regs {
+    pub a: u8,
+    pub f: u8, // the low nibble is always 0
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl Regs {
+    pub fn pair(&self, rr: RegPair) -> u16 {
+        use RegPair::*;
+        let (hi, lo) = match rr {
+            SP => return self.sp,
+            AF => (self.a, self.f),
+            BC => (self.b, self.c),
+            DE => (self.d, self.e),
+            HL => (self.h, self.l),
+        };
+        u16::from_be_bytes([hi, lo])
+    }
+
+    pub fn set_pair(&mut self, rr: RegPair, value: u16) {
+        use RegPair::*;
+        let [hi, lo] = value.to_be_bytes();
+        match rr {
+            SP => self.sp = value,
+            AF => (self.a, self.f) = (hi, lo & 0xf0),
+            BC => (self.b, self.c) = (hi, lo),
+            DE => (self.d, self.e) = (hi, lo),
+            HL => (self.h, self.l) = (hi, lo),
+        }
+    }
+
+    fn reg(&mut self, r: Reg) -> &mut u8 {
+        use Reg::*;
+        match r {
+            A => &mut self.a,
+            F => &mut self.f,
+            B => &mut self.b,
+            C => &mut self.c,
+            D => &mut self.d,
+            E => &mut self.e,
+            H => &mut self.h,
+            L => &mut self.l,
+            HL_ => panic!("[hl] is memory, not a register"),
+        }
+    }
+
+    pub fn flag(&self, flag: u8) -> bool {
+        self.f & flag != 0
+    }
+
+    fn set_flags(&mut self, z: bool, n: bool, h: bool, c: bool) {
+        self.f =
+            (z as u8) << 7 | (n as u8) << 6 | (h as u8) << 5 | (c as u8) << 4;
+    }
+
+    pub fn cond(&self, c: Condition) -> bool {
+        match c {
+            Condition::NZ => !self.flag(Z),
+            Condition::Z => self.flag(Z),
+            Condition::NC => !self.flag(CY),
+            Condition::C => self.flag(CY),
+        }
+    }
+}
+
+impl std::fmt::Display for Regs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use RegPair::*;
+        write!(
+            f,
+            "af={:04x} bc={:04x} de={:04x} hl={:04x} sp={:04x} pc={:04x}",
+            self.pair(AF),
+            self.pair(BC),
+            self.pair(DE),
+            self.pair(HL),
+            self.sp,
+            self.pc
+        )
+    }
+}
+
+/// why the cpu stopped running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    Halt,
+    Stop,
+    Lockup(u8), // an invalid opcode
+    // run_until's predicate held, or it ran out of steps
+    Reached,
+    StepLimit,
+}
+
+#[derive(Clone)]
+pub struct Cpu<B = Flat> {
+    pub regs: Regs,
+    pub ime: bool,
+    // ei enables interrupts after the next instruction
+    ime_pending: bool,
+    pub mem: B,
+    pub cycles: u64, // M-cycles
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new(Flat::default())
+    }
+}
+
+impl<B: Bus> Cpu<B> {
+    pub fn new(mem: B) -> Self {
+        Self {
+            regs: Regs::default(),
+            ime: false,
+            ime_pending: false,
+            mem,
+            cycles: 0,
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.mem.read8(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.mem.write8(addr, value);
+    }
+
+    fn read16(&mut self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    fn write16(&mut self, addr: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
+    fn push(&mut self, value: u16) {
+        self.regs.sp = self.regs.sp.wrapping_sub(2);
+        self.write16(self.regs.sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.read16(self.regs.sp);
+        self.regs.sp = self.regs.sp.wrapping_add(2);
+        value
+    }
+
+    pub fn r8(&mut self, r: Reg) -> u8 {
+        match r {
+            Reg::HL_ => self.read(self.regs.pair(RegPair::HL)),
+            _ => {
+                let mut regs = self.regs;
+                *regs.reg(r)
+            }
+        }
+    }
+
+    pub fn set_r8(&mut self, r: Reg, value: u8) {
+        match r {
+            Reg::HL_ => self.write(self.regs.pair(RegPair::HL), value),
+            _ => *self.regs.reg(r) = value,
+        }
+    }
+
+    /// the instruction at pc
+    pub fn fetch(&mut self) -> Instruction {
+        let pc = self.regs.pc;
+        sm83::decode_instr([0, 1, 2].map(|i| self.read(pc.wrapping_add(i))))
+    }
+
+    /// executes one instruction. halt, stop and invalid opcodes leave pc
+    /// after (or, for invalid ones, at) the instruction and report it.
+    pub fn step(&mut self) -> Option<Exit> {
+        let instr = self.fetch();
+        self.execute(instr)
+    }
+
+    /// executes `instr` as if it was at pc
+    pub fn execute(&mut self, instr: Instruction) -> Option<Exit> {
+        use Instruction::*;
+
+        let pc = self.regs.pc;
+        let next = pc.wrapping_add(instr.len());
+        self.regs.pc = next;
+
+        let info = instr.info();
+        let mut taken = true;
+        let mut exit = None;
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+
+        match instr {
+            NOP => {}
+            LD_pa16_SP(a16) => self.write16(a16, self.regs.sp),
+            STOP(_) => exit = Some(Exit::Stop),
+            HALT => exit = Some(Exit::Halt),
+            Invalid(op) => {
+                self.regs.pc = pc;
+                exit = Some(Exit::Lockup(op));
+            }
+            DI => self.ime = false,
+            EI => self.ime_pending = true,
+
+            LD_rr_d16(rr, d16) => self.regs.set_pair(rr, d16),
+            LD_prr_A(rr) => self.write(self.regs.pair(rr), self.regs.a),
+            LD_A_prr(rr) => self.regs.a = self.read(self.regs.pair(rr)),
+            LD_pHLi_A | LD_pHLd_A | LD_A_pHLi | LD_A_pHLd => {
+                let hl = self.regs.pair(RegPair::HL);
+                match instr {
+                    LD_pHLi_A | LD_pHLd_A => self.write(hl, self.regs.a),
+                    _ => self.regs.a = self.read(hl),
+                }
+                let hl = match instr {
+                    LD_pHLi_A | LD_A_pHLi => hl.wrapping_add(1),
+                    _ => hl.wrapping_sub(1),
+                };
+                self.regs.set_pair(RegPair::HL, hl);
+            }
+            LD_r_d8(r, d8) => self.set_r8(r, d8),
+            LD_r_r(r1, r2) => {
+                let v = self.r8(r2);
+                self.set_r8(r1, v)
+            }
+            LDH_pa8_A(a8) => self.write(0xff00 | a8 as u16, self.regs.a),
+            LDH_A_pa8(a8) => self.regs.a = self.read(0xff00 | a8 as u16),
+            LDH_pC_A => self.write(0xff00 | self.regs.c as u16, self.regs.a),
+            LDH_A_pC => self.regs.a = self.read(0xff00 | self.regs.c as u16),
+            LD_pa16_A(a16) => self.write(a16, self.regs.a),
+            LD_A_pa16(a16) => self.regs.a = self.read(a16),
+            LD_SP_HL => self.regs.sp = self.regs.pair(RegPair::HL),
+
+            INC_rr(rr) => {
+                let v = self.regs.pair(rr).wrapping_add(1);
+                self.regs.set_pair(rr, v)
+            }
+            DEC_rr(rr) => {
+                let v = self.regs.pair(rr).wrapping_sub(1);
+                self.regs.set_pair(rr, v)
+            }
+            INC_r(r) => {
+                let v = self.r8(r);
+                let res = v.wrapping_add(1);
+                let c = self.regs.flag(CY);
+                self.regs.set_flags(res == 0, false, v & 0xf == 0xf, c);
+                self.set_r8(r, res);
+            }
+            DEC_r(r) => {
+                let v = self.r8(r);
+                let res = v.wrapping_sub(1);
+                let c = self.regs.flag(CY);
+                self.regs.set_flags(res == 0, true, v & 0xf == 0, c);
+                self.set_r8(r, res);
+            }
+            ADD_HL_rr(rr) => {
+                let hl = self.regs.pair(RegPair::HL);
+                let v = self.regs.pair(rr);
+                let (res, c) = hl.overflowing_add(v);
+                let h = (hl & 0xfff) + (v & 0xfff) > 0xfff;
+                let z = self.regs.flag(Z);
+                self.regs.set_flags(z, false, h, c);
+                self.regs.set_pair(RegPair::HL, res);
+            }
+            ADD_SP_r8(r8) => self.regs.sp = self.sp_plus(r8),
+            LD_HL_SP_r8(r8) => {
+                let v = self.sp_plus(r8);
+                self.regs.set_pair(RegPair::HL, v)
+            }
+
+            RLCA | RRCA | RLA | RRA => {
+                let op = match instr {
+                    RLCA => PrefixOp::RLC,
+                    RRCA => PrefixOp::RRC,
+                    RLA => PrefixOp::RL,
+                    _ => PrefixOp::RR,
+                };
+                self.prefix(op, Reg::A);
+                // the a-only versions always clear z
+                self.regs.f &= !Z;
+            }
+            Prefix(op, r) => self.prefix(op, r),
+
+            DAA => {
+                let (mut a, f) = (self.regs.a, self.regs.f);
+                let mut c = f & CY != 0;
+                if f & N == 0 {
+                    if c || a > 0x99 {
+                        a = a.wrapping_add(0x60);
+                        c = true;
+                    }
+                    if f & H != 0 || a & 0xf > 9 {
+                        a = a.wrapping_add(0x06);
+                    }
+                } else {
+                    if c {
+                        a = a.wrapping_sub(0x60);
+                    }
+                    if f & H != 0 {
+                        a = a.wrapping_sub(0x06);
+                    }
+                }
+                self.regs.a = a;
+                self.regs.set_flags(a == 0, f & N != 0, false, c);
+            }
+            CPL => {
+                self.regs.a = !self.regs.a;
+                self.regs.f |= N | H;
+            }
+            SCF => self.regs.f = self.regs.f & Z | CY,
+            CCF => self.regs.f = (self.regs.f & (Z | CY)) ^ CY,
+            Alu_A_RegOrNum(op, operand) => {
+                let v = match operand {
+                    RegOrNum::Reg(r) => self.r8(r),
+                    RegOrNum::Num(d8) => d8,
+                };
+                self.alu(op, v);
+            }
+
+            JP_a16(_) | JR_r8(_) | JP_c_a16(..) | JR_c_r8(..) | CALL_a16(_)
+            | CALL_c_a16(..) | RST_vector(_) => {
+                let (dest, call) = match instr.flow(pc) {
+                    Flow::Jump(dest) => (dest, false),
+                    Flow::Branch(c, dest) => {
+                        taken = self.regs.cond(c);
+                        (dest, false)
+                    }
+                    Flow::Call(dest) => (dest, true),
+                    Flow::CondCall(c, dest) => {
+                        taken = self.regs.cond(c);
+                        (dest, true)
+                    }
+                    _ => unreachable!(),
+                };
+                if taken {
+                    if call {
+                        self.push(next);
+                    }
+                    self.regs.pc = dest;
+                }
+            }
+            JP_HL => self.regs.pc = self.regs.pair(RegPair::HL),
+            RET | RETI => {
+                self.regs.pc = self.pop();
+                if instr == RETI {
+                    self.ime = true;
+                }
+            }
+            RET_c(c) => {
+                taken = self.regs.cond(c);
+                if taken {
+                    self.regs.pc = self.pop();
+                }
+            }
+            PUSH_rr(rr) => self.push(self.regs.pair(rr)),
+            POP_rr(rr) => {
+                let v = self.pop();
+                self.regs.set_pair(rr, v)
+            }
+        }
+
+        self.cycles += if taken {
+            info.cycles_taken
+        } else {
+            info.cycles_not_taken
+        } as u64;
+        exit
+    }
+
+    /// sp + r8, with the flags of add sp, r8 and ld hl, sp+r8
+    fn sp_plus(&mut self, r8: i8) -> u16 {
+        let sp = self.regs.sp;
+        let u = r8 as u8 as u16;
+        let h = (sp & 0xf) + (u & 0xf) > 0xf;
+        let c = (sp & 0xff) + u > 0xff;
+        self.regs.set_flags(false, false, h, c);
+        sp.wrapping_add_signed(r8 as i16)
+    }
+
+    fn alu(&mut self, op: AluBlockOp, v: u8) {
+        use AluBlockOp::*;
+        let a = self.regs.a;
+        let cin = self.regs.flag(CY) as u8;
+        let (res, n, h, c) = match op {
+            ADD | ADC => {
+                let cin = if op == ADC { cin } else { 0 };
+                let res = a as u16 + v as u16 + cin as u16;
+                let h = (a & 0xf) + (v & 0xf) + cin > 0xf;
+                (res as u8, false, h, res > 0xff)
+            }
+            SUB | SBC | CP => {
+                let cin = if op == SBC { cin } else { 0 };
+                let res = (a as i16) - (v as i16) - (cin as i16);
+                let h =
+                    ((a & 0xf) as i16) - ((v & 0xf) as i16) - (cin as i16) < 0;
+                (res as u8, true, h, res < 0)
+            }
+            AND => (a & v, false, true, false),
+            XOR => (a ^ v, false, false, false),
+            OR => (a | v, false, false, false),
+        };
+        self.regs.set_flags(res == 0, n, h, c);
+        if op != CP {
+            self.regs.a = res;
+        }
+    }
+
+    fn prefix(&mut self, op: PrefixOp, r: Reg) {
+        use PrefixOp::*;
+        let v = self.r8(r);
+        let cin = self.regs.flag(CY) as u8;
+        let (res, c) = match op {
+            RLC => (v.rotate_left(1), v & 0x80 != 0),
+            RRC => (v.rotate_right(1), v & 1 != 0),
+            RL => (v << 1 | cin, v & 0x80 != 0),
+            RR => (v >> 1 | cin << 7, v & 1 != 0),
+            SLA => (v << 1, v & 0x80 != 0),
+            SRA => ((v as i8 >> 1) as u8, v & 1 != 0),
+            SWAP => (v.rotate_left(4), false),
+            SRL => (v >> 1, v & 1 != 0),
+            BIT(u3) => {
+                let c = self.regs.flag(CY);
+                self.regs.set_flags(v & 1 << u3 == 0, false, true, c);
+                return;
+            }
+            RES(u3) => return self.set_r8(r, v & !(1 << u3)),
+            SET(u3) => return self.set_r8(r, v | 1 << u3),
+        };
+        self.regs.set_flags(res == 0, false, false, c);
+        self.set_r8(r, res);
+    }
+
+    /// steps until `done` holds before an instruction, the cpu halts,
+    /// stops or locks up, or `max_steps` instructions ran.
+    pub fn run_until(
+        &mut self,
+        max_steps: usize,
+        mut done: impl FnMut(&Self) -> bool,
+    ) -> Exit {
+        for _ in 0..max_steps {
+            if done(self) {
+                return Exit::Reached;
+            }
+            if let Some(exit) = self.step() {
+                return exit;
+            }
+        }
+        Exit::StepLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm83::asm::asm;
+
+    fn run(src: &str) -> Cpu {
+        let mut cpu = Cpu::default();
+        let code = asm!(0x100, src);
+        cpu.mem[0x100..0x100 + code.len()].copy_from_slice(&code);
+        cpu.regs.pc = 0x100;
+        cpu.regs.sp = 0xfffe;
+        assert_eq!(cpu.run_until(1000, |_| false), Exit::Halt);
+        cpu
+    }
+
+    #[test]
+    fn bcd() {
+        // 19 + 28 = 47, then 47 - 48 = 99 with a borrow
+        let cpu = run("ld a, $19 / add $28 / daa / ld b, a
+                       sub $48 / daa / halt");
+        assert_eq!(cpu.regs.b, 0x47);
+        assert_eq!(cpu.regs.a, 0x99);
+        assert_eq!(cpu.regs.f, N | CY);
+    }
+
+    #[test]
+    fn half_carry() {
+        let cpu = run("ld a, $0f / inc a / halt");
+        assert_eq!(cpu.regs.f, H);
+        let cpu = run("ld a, $10 / sub 1 / halt");
+        assert_eq!(cpu.regs.f, N | H);
+        let cpu = run("ld hl, $0fff / ld bc, 1 / add hl, bc / halt");
+        assert_eq!((cpu.regs.pair(RegPair::HL), cpu.regs.f), (0x1000, H));
+        let cpu = run("ld sp, $00ff / add sp, 1 / halt");
+        assert_eq!((cpu.regs.sp, cpu.regs.f), (0x0100, H | CY));
+    }
+
+    #[test]
+    fn calls_and_stack() {
+        let cpu = run("
+                    ld bc, $12ff
+                    push bc
+                    pop af
+                    call f
+                    halt
+            f:      ld d, a
+                    ret");
+        assert_eq!((cpu.regs.a, cpu.regs.f, cpu.regs.d), (0x12, 0xf0, 0x12));
+        assert_eq!(cpu.regs.sp, 0xfffe);
+        assert_eq!(cpu.regs.pc, 0x109); // just past the halt
+    }
+}
@@ -0,0 +1,92 @@
+// the hardware registers: rust callbacks for reads and writes of
+// 0xff00..0xff80, on top of another bus. memory keeps the last value
+// written, so whatever has no read callback reads that.
+
+use std::ops::RangeInclusive;
+
+use crate::bus::{Bus, Flat, Region};
+
+type Read = Box<dyn FnMut(u16) -> u8>;
+type Write = Box<dyn FnMut(u16, u8)>;
+
+#[derive(Default)]
+pub struct Io<B = Flat> {
+    pub bus: B,
+    reads: Vec<(RangeInclusive<u16>, Read)>,
+    writes: Vec<(RangeInclusive<u16>, Write)>,
+}
+
+impl<B: Bus> Io<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus,
+            reads: vec![],
+            writes: vec![],
+        }
+    }
+
+    /// reads of `addrs` give what `f` says. the first callback that
+    /// takes an addr wins.
+    pub fn on_read(
+        &mut self,
+        addrs: RangeInclusive<u16>,
+        f: impl FnMut(u16) -> u8 + 'static,
+    ) {
+        self.reads.push((addrs, Box::new(f)));
+    }
+
+    /// `f` gets every value written to `addrs`
+    pub fn on_write(
+        &mut self,
+        addrs: RangeInclusive<u16>,
+        f: impl FnMut(u16, u8) + 'static,
+    ) {
+        self.writes.push((addrs, Box::new(f)));
+    }
+}
+
+impl<B: Bus> Bus for Io<B> {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let f = match self.region(addr) {
+            Region::Io => {
+                self.reads.iter_mut().find(|(r, _)| r.contains(&addr))
+            }
+            _ => None,
+        };
+        match f {
+            Some((_, f)) => f(addr),
+            None => self.bus.read8(addr),
+        }
+    }
+
+    fn write8(&mut self, addr: u16, value: u8) {
+        self.bus.write8(addr, value);
+        if self.region(addr) != Region::Io {
+            return;
+        }
+        let f = self.writes.iter_mut().find(|(r, _)| r.contains(&addr));
+        if let Some((_, f)) = f {
+            f(addr, value);
+        }
+    }
+
+    fn region(&self, addr: u16) -> Region {
+        self.bus.region(addr)
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.bus.rom_bank()
+    }
+
+    fn rom0_bank(&self) -> u16 {
+        self.bus.rom0_bank()
+    }
+
+    fn switches_banks(&self) -> bool {
+        self.bus.switches_banks()
+    }
+
+    fn memory(&mut self) -> &mut [u8; 0x10000] {
+        self.bus.memory()
+    }
+}
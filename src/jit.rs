@@ -0,0 +1,466 @@
+// runs compiled code in process. the code is encoded by iced, lands in an
+// executable mapping, and the entry points are called as sm83_funcs.
+// linux on x86-64 only.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+
+use memmap2::Mmap;
+
+use crate::bus::{self, Bus};
+use crate::cartridge::BankedAddr;
+use crate::interp::{Cpu, Exit};
+use crate::sm83::RegPair;
+use crate::transpile::abi::{ExitReason, Sm83Func};
+use crate::transpile::{CompileError, Context};
+
+#[derive(Debug)]
+pub enum JitError {
+    Io(io::Error),
+    Compile(CompileError),
+    // run was given an addr that is not one of the entries
+    NoEntry(BankedAddr),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::Io(e) => write!(f, "{e}"),
+            JitError::Compile(e) => write!(f, "{e}"),
+            JitError::NoEntry(addr) => write!(f, "no entry for {addr}"),
+        }
+    }
+}
+
+impl std::error::Error for JitError {}
+
+impl From<io::Error> for JitError {
+    fn from(e: io::Error) -> Self {
+        JitError::Io(e)
+    }
+}
+
+impl From<CompileError> for JitError {
+    fn from(e: CompileError) -> Self {
+        JitError::Compile(e)
+    }
+}
+
+pub struct Jit {
+    code: Mmap,
+    entries: HashMap<BankedAddr, usize>,
+    // where indirect jumps found nothing compiled
+    missed: RefCell<BTreeSet<BankedAddr>>,
+}
+
+impl Jit {
+    /// maps everything `ctx` compiled, callable from `entries`
+    pub fn new(
+        ctx: &mut Context,
+        entries: &[BankedAddr],
+    ) -> Result<Self, JitError> {
+        // nothing is absolute, so it runs wherever it is mapped
+        let assembled = ctx.assemble(entries, 0)?;
+
+        // an empty mapping is an error, and there is always a prologue
+        let mut map = memmap2::MmapMut::map_anon(assembled.code.len())?;
+        map.copy_from_slice(&assembled.code);
+        let code = map.make_exec()?;
+        Ok(Self {
+            code,
+            entries: assembled.entries,
+            missed: RefCell::default(),
+        })
+    }
+
+    pub fn entry(&self, addr: BankedAddr) -> Option<Sm83Func> {
+        let offset = *self.entries.get(&addr)?;
+        // safety: the entry stubs are sm83_funcs, and the mapping lives
+        // as long as self
+        Some(unsafe {
+            std::mem::transmute::<*const u8, Sm83Func>(
+                self.code.as_ptr().add(offset),
+            )
+        })
+    }
+
+    /// runs the compiled code from `addr` on `cpu` until it gives control
+    /// back. what it traps goes through `cpu.mem`. None if `addr` is not
+    /// an entry point.
+    pub fn run<B: Bus>(
+        &self,
+        addr: BankedAddr,
+        cpu: &mut Cpu<B>,
+    ) -> Option<ExitReason> {
+        use RegPair::*;
+        let f = self.entry(addr)?;
+        let mut hooks = bus::hooks(&mut cpu.mem);
+        let regs = &cpu.regs;
+        // safety: the code only touches mem, which is 64 KiB, and the
+        // stack below its own frame. the bus outlives the call.
+        let state = unsafe {
+            f(
+                regs.pair(AF) as u32 | (cpu.ime as u32) << 16,
+                regs.pair(BC) as u32,
+                regs.pair(DE) as u32,
+                regs.pair(HL) as u32,
+                regs.sp as u32,
+                cpu.mem.memory().as_mut_ptr(),
+                &mut hooks,
+            )
+        };
+        cpu.regs.set_pair(AF, state.af);
+        cpu.regs.set_pair(BC, state.bc);
+        cpu.regs.set_pair(DE, state.de);
+        cpu.regs.set_pair(HL, state.hl);
+        cpu.regs.sp = state.sp;
+        cpu.regs.pc = state.pc;
+        cpu.ime = state.ime != 0;
+        let exit = ExitReason::from_u16(state.exit).expect("bad exit reason");
+        if exit == ExitReason::Indirect {
+            let bank = cpu.mem.rom_bank();
+            self.missed
+                .borrow_mut()
+                .insert(BankedAddr::new(state.pc, bank));
+        }
+        Some(exit)
+    }
+
+    /// like `run`, but interprets from where an indirect jump found
+    /// nothing compiled until it reaches an entry point again. `cpu.mem`
+    /// has to hold the code for that. None if `addr` is not an entry, or
+    /// if it interpreted `max_steps` instructions without getting back.
+    /// the runtime compiles what it misses instead, this is for code
+    /// compiled ahead of time.
+    #[allow(dead_code)]
+    pub fn run_interpreting<B: Bus>(
+        &self,
+        mut addr: BankedAddr,
+        cpu: &mut Cpu<B>,
+        max_steps: usize,
+    ) -> Option<ExitReason> {
+        let mut steps = 0;
+        loop {
+            match self.run(addr, cpu)? {
+                ExitReason::Indirect => {}
+                exit => return Some(exit),
+            }
+            let at =
+                |cpu: &Cpu<B>| BankedAddr::new(cpu.regs.pc, cpu.mem.rom_bank());
+            let exit = cpu.run_until(max_steps - steps, |cpu| {
+                steps += 1;
+                self.entries.contains_key(&at(cpu))
+            });
+            match exit {
+                Exit::Reached => addr = at(cpu),
+                Exit::Halt => return Some(ExitReason::Halt),
+                Exit::Stop => return Some(ExitReason::Stop),
+                Exit::Lockup(_) => return Some(ExitReason::Lockup),
+                Exit::StepLimit => return None,
+            }
+        }
+    }
+
+    /// every target an indirect jump or ret left to the runtime for, so far.
+    /// compiling them next time makes them fast.
+    #[allow(dead_code)]
+    pub fn missed(&self) -> Vec<BankedAddr> {
+        self.missed.borrow().iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Cfg;
+    use crate::bus::Flat;
+    use crate::cartridge::{test_rom as rom, Cartridge};
+    use crate::io::Io;
+    use crate::sm83::asm::asm;
+
+    fn compile(rom: &Cartridge, entry: BankedAddr) -> Jit {
+        let mut ctx = Context::default();
+        for &start in Cfg::discover_from(rom, &[entry]).blocks.keys() {
+            ctx.compile(rom, start).unwrap();
+        }
+        Jit::new(&mut ctx, &[entry]).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    #[test]
+    fn runs_a_loop() {
+        let code = asm!(
+            0x150,
+            "       ld b, 10
+                    xor a
+            loop:   add b
+                    dec b
+                    jr nz, loop
+                    ld [$c000], a
+                    halt"
+        );
+        let rom = rom(&code);
+        let entry = BankedAddr::new(0x150, 0);
+        let jit = compile(&rom, entry);
+
+        let mut cpu = Cpu::default();
+        cpu.regs.sp = 0xfffe;
+        assert_eq!(jit.run(entry, &mut cpu), Some(ExitReason::Halt));
+        assert_eq!(cpu.regs.a, 55);
+        assert_eq!(cpu.mem[0xc000], 55);
+        assert_eq!(cpu.regs.pc, 0x150 + code.len() as u16);
+
+        // and the same as the interpreter says
+        let reference = interpret(&rom);
+        assert_eq!(reference.regs, cpu.regs);
+        assert!(reference.mem[..] == cpu.mem[..]);
+    }
+
+    /// what the interpreter makes of `rom` from 0x150 to a halt, with
+    /// the rom out of the way
+    fn interpret(rom: &Cartridge) -> Cpu {
+        let mut cpu = Cpu::default();
+        cpu.mem[..0x8000].copy_from_slice(rom.rom());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        cpu.run_until(1000, |_| false);
+        cpu.mem[..0x8000].fill(0);
+        cpu
+    }
+
+    #[test]
+    fn known_flags_meet_jumps_and_push_af() {
+        // rlca leaves z = 0 and h = 0 as constants, inc a leaves h in AF
+        let code = asm!(
+            0x150,
+            "       ld a, $0f
+                    inc a
+                    push af
+                    rlca
+                    jr z, never
+                    push af
+                    pop bc
+                    pop de
+                    ld a, $f0
+                    push hl
+                    pop af
+                    cpl
+                    push af
+                    halt
+            never:  halt"
+        );
+        let rom = rom(&code);
+        let entry = BankedAddr::new(0x150, 0);
+        let jit = compile(&rom, entry);
+
+        let mut cpu = Cpu::default();
+        cpu.regs.sp = 0xfffe;
+        assert_eq!(jit.run(entry, &mut cpu), Some(ExitReason::Halt));
+        let reference = interpret(&rom);
+        assert_eq!(reference.regs, cpu.regs);
+        assert!(reference.mem[..] == cpu.mem[..]);
+        assert_eq!((cpu.regs.b, cpu.regs.c, cpu.regs.e), (0x20, 0x00, 0x20));
+    }
+
+    #[test]
+    fn leaves_where_nothing_is_compiled() {
+        let rom = rom(&asm!(0x150, "ld hl, $1234 / push hl / call $4000"));
+        let entry = BankedAddr::new(0x150, 0);
+        // only the first block, the call target stays out
+        let mut ctx = Context::default();
+        ctx.compile(&rom, entry).unwrap();
+        let jit = Jit::new(&mut ctx, &[entry]).unwrap();
+
+        let mut cpu = Cpu::default();
+        cpu.regs.sp = 0xd000;
+        assert_eq!(jit.run(entry, &mut cpu), Some(ExitReason::Jump));
+        assert_eq!(cpu.regs.pc, 0x4000);
+        assert_eq!(cpu.regs.sp, 0xcffc);
+        assert_eq!(cpu.mem[0xcffc..0xd000], [0x57, 0x01, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn calls_come_back_by_host_ret() {
+        let code = asm!(
+            0x150,
+            "       di
+                    ld b, 3
+                    xor a
+            loop:   call twice
+                    dec b
+                    jr nz, loop
+                    call nc, never_c
+                    and a
+                    call z, never_z
+                    call last
+                    ld [$c000], a
+                    halt
+            twice:  call once
+            once:   inc a
+                    ret
+            never_c: ret c
+                    ld e, a
+                    ret nz
+            never_z: ld d, a
+                    ret
+            last:   reti"
+        );
+        let rom = rom(&code);
+        let entry = BankedAddr::new(0x150, 0);
+        let jit = compile(&rom, entry);
+
+        let mut cpu = Cpu::default();
+        cpu.regs.sp = 0xfffe;
+        assert_eq!(jit.run(entry, &mut cpu), Some(ExitReason::Halt));
+        let reference = interpret(&rom);
+        assert_eq!(reference.regs, cpu.regs);
+        assert!(reference.mem[..] == cpu.mem[..]);
+        assert_eq!((cpu.regs.a, cpu.regs.e), (6, 6));
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn rets_elsewhere_go_through_dispatch() {
+        // sub swaps its return address for there, and the ret to there
+        // can not be a host ret
+        let code = asm!(
+            0x150,
+            "       call sub
+                    halt
+            sub:    pop hl
+                    ld hl, there
+                    push hl
+                    ret
+            there:  ld a, $42
+                    ld hl, $4000
+                    push hl
+                    ret"
+        );
+        let rom = rom(&code);
+        let entry = BankedAddr::new(0x150, 0);
+        let mut ctx = Context::default();
+        let starts = Cfg::discover_from(&rom, &[entry]).blocks;
+        for &start in starts.keys() {
+            ctx.compile(&rom, start).unwrap();
+        }
+        // nothing finds there but the dispatcher
+        let there = BankedAddr::new(0x15a, 0);
+        assert!(!starts.contains_key(&there));
+        ctx.compile(&rom, there).unwrap();
+        let jit = Jit::new(&mut ctx, &[entry]).unwrap();
+
+        let mut cpu = Cpu::default();
+        cpu.regs.sp = 0xfffe;
+        // 0x4000 is not compiled at all
+        assert_eq!(jit.run(entry, &mut cpu), Some(ExitReason::Indirect));
+        assert_eq!(
+            (cpu.regs.a, cpu.regs.pc, cpu.regs.sp),
+            (0x42, 0x4000, 0xfffe)
+        );
+    }
+
+    #[test]
+    fn jp_hl_goes_through_dispatch() {
+        let code = asm!(
+            0x150,
+            "       ld hl, two
+                    jp hl
+            two:    ld b, 2
+                    ld hl, $c000
+                    jp hl
+            back:   ld d, 4
+                    halt"
+        );
+        let rom = rom(&code);
+        let entry = BankedAddr::new(0x150, 0);
+        let back = BankedAddr::new(0x15a, 0);
+        let mut ctx = Context::default();
+        for &start in Cfg::discover_from(&rom, &[entry, back]).blocks.keys() {
+            ctx.compile(&rom, start).unwrap();
+        }
+        // the table would have two in it, a jp hl does not say
+        ctx.compile(&rom, BankedAddr::new(0x154, 0)).unwrap();
+        let jit = Jit::new(&mut ctx, &[entry, back]).unwrap();
+
+        // and wram only has something for the interpreter
+        let mut cpu = Cpu::default();
+        cpu.regs.sp = 0xfffe;
+        let ram = asm!(0xc000, "ld c, 3 / jp $015a");
+        cpu.mem[0xc000..0xc000 + ram.len()].copy_from_slice(&ram);
+        assert_eq!(
+            jit.run_interpreting(entry, &mut cpu, 100),
+            Some(ExitReason::Halt)
+        );
+        let regs = &cpu.regs;
+        assert_eq!((regs.b, regs.c, regs.d, regs.pc), (2, 3, 4, 0x15d));
+        assert_eq!(jit.missed(), [entry.to(0xc000)]);
+    }
+
+    #[test]
+    fn io_calls_back() {
+        let code = asm!(
+            0x150,
+            "       ldh a, [$44]
+                    ld b, a
+                    ld a, $12
+                    ldh [$40], a
+                    ld c, $47
+                    ld a, $e4
+                    ldh [c], a
+                    ld hl, $ff44
+                    ld d, [hl]
+                    ld hl, $c000
+                    ld [hl], a
+                    ld hl, $ff01
+                    ld [hl], $41
+                    scf
+                    inc [hl]
+                    halt"
+        );
+        let rom = rom(&code);
+        let entry = BankedAddr::new(0x150, 0);
+        let mut ctx = Context::default();
+        ctx.trap_io = true;
+        ctx.compile(&rom, entry).unwrap();
+        let jit = Jit::new(&mut ctx, &[entry]).unwrap();
+
+        // ly says vblank, every write is logged
+        let mut io = Io::new(Flat::default());
+        io.on_read(0xff44..=0xff44, |_| 0x90);
+        let written = std::rc::Rc::new(RefCell::new(vec![]));
+        let log = written.clone();
+        io.on_write(0xff00..=0xff7f, move |addr, value| {
+            log.borrow_mut().push((addr, value))
+        });
+
+        let mut cpu = Cpu::new(io);
+        cpu.regs.sp = 0xfffe;
+        assert_eq!(jit.run(entry, &mut cpu), Some(ExitReason::Halt));
+        assert_eq!((cpu.regs.b, cpu.regs.d), (0x90, 0x90));
+        assert_eq!(
+            written.borrow()[..],
+            [
+                (0xff40, 0x12),
+                (0xff47, 0xe4),
+                (0xff01, 0x41),
+                (0xff01, 0x42)
+            ]
+        );
+        // the flags went around the calls
+        assert_eq!(cpu.regs.f, 0x10);
+        assert_eq!(cpu.mem.memory()[0xc000], 0xe4);
+    }
+
+    #[test]
+    fn bad_encodings_are_errors() {
+        let rom = rom(&asm!(0x150, "ld b, [hl] / halt"));
+        let entry = BankedAddr::new(0x150, 0);
+        // bh can not go with the rex prefix r15 needs
+        let mut ctx = Context::default();
+        ctx.mem_base_reg = iced_x86::code_asm::r15;
+        ctx.compile(&rom, entry).unwrap();
+        let err = ctx.assemble(&[entry], 0).err().unwrap();
+        assert!(matches!(err, CompileError::Iced(_)), "{err}");
+    }
+}
@@ -1,8 +1,265 @@
+// the sm83 mnemonics are all upper case acronyms
+#![allow(clippy::upper_case_acronyms)]
+
+mod analysis;
+mod bus;
+mod cartridge;
+mod fuzz;
+mod interp;
+mod io;
+mod jit;
+mod mbc;
+mod runtime;
 mod sm83;
 use sm83::*;
 mod transpile;
 
+use cartridge::Cartridge;
+
+const USAGE: &str = "\
+usage: gb_recompiler <rom.gb>
+       gb_recompiler disasm <rom.gb> [bank:]start [end]
+       gb_recompiler opcodes
+       gb_recompiler run <rom.gb>
+       gb_recompiler obj <rom.gb> <out.o> [rom.sym]
+       gb_recompiler asm <rom.gb> <out.s> [rom.sym]
+       gb_recompiler fuzz <executor> [seed] [cases]";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+fn load(path: &str) -> Cartridge {
+    let cart = match Cartridge::load(path) {
+        Ok(cart) => cart,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cart.verify() {
+        eprintln!("{path}: warning: {e}");
+    }
+    cart
+}
+
+/// `$150`, `0x150`, `150` are all hex, as in the rest of the gb world
+fn parse_hex(s: &str) -> Option<u16> {
+    let s = s.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn info(cart: &Cartridge) {
+    let header = cart.header();
+    println!("title:     {}", header.title);
+    println!("type:      {:?}", header.cartridge_type);
+    println!("cgb:       {:?}", header.cgb);
+    println!("sgb:       {}", header.sgb);
+    println!("rom banks: {}", header.rom_banks());
+    println!("ram:       {:#x} bytes", header.ram_size);
+
+    let cfg = analysis::Cfg::discover(cart);
+    println!(
+        "code:      {} blocks, {} functions, {} jp hl",
+        cfg.blocks.len(),
+        cfg.functions.len(),
+        cfg.indirect.len()
+    );
+}
+
+fn fail(e: impl std::fmt::Display) -> ! {
+    eprintln!("{e}");
+    std::process::exit(1);
+}
+
+/// what `recompile` writes
+#[derive(Clone, Copy)]
+enum Output {
+    Obj,
+    Asm,
+}
+
+/// recompiles everything reachable in `cart` into `out`, and a c header
+/// for it next to it
+fn recompile(
+    cart: &Cartridge,
+    out: &str,
+    syms: Option<analysis::Symbols>,
+    output: Output,
+) {
+    let cfg = analysis::Cfg::discover(cart);
+    let mut ctx = transpile::Context::default();
+    for &start in cfg.blocks.keys() {
+        ctx.compile(cart, start).unwrap_or_else(|e| fail(e));
+    }
+    let functions = cfg
+        .functions
+        .union(&cfg.entries)
+        .filter(|addr| cfg.blocks.contains_key(addr))
+        .copied();
+    let functions = transpile::abi::function_names(functions, syms.as_ref());
+    let bytes = match output {
+        Output::Obj => transpile::elf::write_object(&mut ctx, &functions),
+        Output::Asm => transpile::gas::write_asm(&mut ctx, &functions)
+            .map(String::into_bytes),
+    }
+    .unwrap_or_else(|e| fail(e));
+    std::fs::write(out, bytes).unwrap_or_else(|e| fail(format!("{out}: {e}")));
+
+    let header = std::path::Path::new(out).with_extension("h");
+    let name = header.file_stem().unwrap_or_default().to_string_lossy();
+    let text = transpile::header::write_header(&name, &functions);
+    std::fs::write(&header, text)
+        .unwrap_or_else(|e| fail(format!("{}: {e}", header.display())));
+}
+
+/// runs the rom at `path` from where the boot rom leaves it, until it
+/// halts, stops or locks up. bytes sent over the serial port go to
+/// stdout, battery-backed ram to a .sav next to the rom.
+fn run(path: &str) {
+    use sm83::RegPair::*;
+    use std::cell::Cell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    let cart = load(path);
+    let Some(banked) = bus::Banked::new(cart.clone()) else {
+        fail(format!("no support for {:?}", cart.header().mbc()))
+    };
+    let mut io = io::Io::new(banked);
+    // there is no ppu. ly moves on with every read, so waiting for
+    // vblank ends.
+    let mut ly = 0;
+    io.on_read(0xff44..=0xff44, move |_| {
+        ly = (ly + 1) % 154;
+        ly
+    });
+    let sb = Rc::new(Cell::new(0));
+    let data = sb.clone();
+    io.on_write(0xff01..=0xff01, move |_, v| data.set(v));
+    io.on_write(0xff02..=0xff02, move |_, v| {
+        // a transfer on the internal clock
+        if v == 0x81 {
+            let mut out = std::io::stdout().lock();
+            let _ = out.write_all(&[sb.get()]).and_then(|_| out.flush());
+        }
+    });
+
+    let mut cpu = interp::Cpu::new(io);
+    // what a dmg boot rom leaves
+    let boot = [(AF, 0x01b0), (BC, 0x0013), (DE, 0x00d8), (HL, 0x014d)];
+    for (rr, value) in boot {
+        cpu.regs.set_pair(rr, value);
+    }
+    cpu.regs.sp = 0xfffe;
+    cpu.regs.pc = 0x100;
+    let battery = cart.header().cartridge_type.battery;
+    let mut runtime = runtime::Runtime::new(cart);
+    let exit = runtime.run(&mut cpu).unwrap_or_else(|e| fail(e));
+    let blocks = runtime.compiled().len();
+    eprintln!("{exit:?} at ${:04x}, {blocks} blocks", cpu.regs.pc);
+
+    if battery {
+        let sav = std::path::Path::new(path).with_extension("sav");
+        std::fs::write(&sav, cpu.mem.bus.ram())
+            .unwrap_or_else(|e| fail(format!("{}: {e}", sav.display())));
+    }
+}
+
+/// fuzzes `subject` against the interpreter, forever unless `cases` says
+/// otherwise
+fn fuzz(subject: &str, seed: Option<u64>, cases: Option<u64>) {
+    const BATCH: u64 = 10_000;
+
+    let Some(mut subject) = fuzz::executor(subject) else {
+        eprintln!("no executor called {subject}");
+        std::process::exit(2);
+    };
+    let seed = seed.unwrap_or_else(|| {
+        let now = std::time::SystemTime::now();
+        now.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    });
+    println!("seed {seed}");
+
+    let mut done = 0;
+    while cases.is_none_or(|cases| done < cases) {
+        let batch = cases.map_or(BATCH, |cases| BATCH.min(cases - done));
+        let res = fuzz::fuzz(
+            &mut fuzz::Interp,
+            &mut *subject,
+            seed.wrapping_add(done),
+            batch,
+            32,
+        );
+        if let Err(divergence) = res {
+            print!("{divergence}");
+            std::process::exit(1);
+        }
+        done += batch;
+        println!("{done} cases");
+    }
+}
+
 fn main() {
-    let _ = RegPair::BC;
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["disasm", path, start, ref end @ ..] => {
+            let cart = load(path);
+            let (bank, start) = match start.split_once(':') {
+                Some((bank, start)) => (parse_hex(bank), parse_hex(start)),
+                None => (Some(1), parse_hex(start)),
+            };
+            let (Some(bank), Some(start)) = (bank, start) else {
+                usage()
+            };
+            let end = match end {
+                [] => start.saturating_add(0x40),
+                [end] => parse_hex(end).unwrap_or_else(|| usage()),
+                _ => usage(),
+            };
+            let mut out = std::io::stdout().lock();
+            if let Err(e) = sm83::disasm(&mut out, &cart, bank, start..end) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        ["opcodes"] => {
+            let mut out = std::io::stdout().lock();
+            if let Err(e) = sm83::info::write_csv(&mut out) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        ["run", path] => run(path),
+        ["fuzz", subject, ref rest @ ..] => {
+            let mut nums = rest.iter().map(|n| n.parse().ok());
+            let (seed, cases) = match (nums.next(), nums.next(), nums.next()) {
+                (None, ..) => (None, None),
+                (Some(Some(seed)), None, _) => (Some(seed), None),
+                (Some(Some(seed)), Some(Some(cases)), None) => {
+                    (Some(seed), Some(cases))
+                }
+                _ => usage(),
+            };
+            fuzz(subject, seed, cases);
+        }
+        [cmd @ ("obj" | "asm"), path, out, ref syms @ ..] => {
+            let syms = match syms {
+                [] => None,
+                [syms] => Some(
+                    analysis::Symbols::load(syms)
+                        .unwrap_or_else(|e| fail(format!("{syms}: {e}"))),
+                ),
+                _ => usage(),
+            };
+            let output = match cmd {
+                "obj" => Output::Obj,
+                _ => Output::Asm,
+            };
+            recompile(&load(path), out, syms, output);
+        }
+        [path] => info(&load(path)),
+        _ => usage(),
+    }
 }
@@ -0,0 +1,205 @@
+// what the memory bank controllers do with stores to the rom: they are
+// register writes, which pick the banks in 0x4000..0x8000 and
+// 0xa000..0xc000, and for the mbc1 in 0x0000..0x4000 too. the clock of
+// the mbc3 is not there, selecting one of its registers unmaps the ram.
+
+use crate::cartridge::Mbc;
+
+/// the registers of a memory bank controller
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Controller {
+    // 32 KiB of rom, and maybe a bank of ram
+    None,
+    Mbc1 {
+        ram_enabled: bool,
+        // never 0, the mbc makes that 1
+        low: u8,
+        // the ram bank and the bank at 0 in mode 1, bits 5-6 of the rom
+        // bank either way
+        high: u8,
+        mode: bool,
+    },
+    Mbc3 {
+        ram_enabled: bool,
+        rom: u8,
+        // 8..=0xc are the clock's
+        ram: u8,
+    },
+    Mbc5 {
+        ram_enabled: bool,
+        rom: u16,
+        ram: u8,
+    },
+}
+
+impl Controller {
+    /// `mbc` as it comes out of reset, if it is one of these
+    pub fn new(mbc: Mbc) -> Option<Self> {
+        Some(match mbc {
+            Mbc::None => Controller::None,
+            Mbc::Mbc1 => Controller::Mbc1 {
+                ram_enabled: false,
+                low: 1,
+                high: 0,
+                mode: false,
+            },
+            Mbc::Mbc3 => Controller::Mbc3 {
+                ram_enabled: false,
+                rom: 1,
+                ram: 0,
+            },
+            Mbc::Mbc5 => Controller::Mbc5 {
+                ram_enabled: false,
+                rom: 1,
+                ram: 0,
+            },
+            _ => return Option::None,
+        })
+    }
+
+    /// a store of `value` to `addr` in the rom
+    pub fn write(&mut self, addr: u16, value: u8) {
+        // ram is on with $a in the low nibble, off with anything else
+        let enable = value & 0xf == 0xa;
+        match self {
+            Controller::None => {}
+            Controller::Mbc1 {
+                ram_enabled,
+                low,
+                high,
+                mode,
+            } => match addr {
+                0x0000..=0x1fff => *ram_enabled = enable,
+                0x2000..=0x3fff => *low = (value & 0x1f).max(1),
+                0x4000..=0x5fff => *high = value & 3,
+                _ => *mode = value & 1 != 0,
+            },
+            Controller::Mbc3 {
+                ram_enabled,
+                rom,
+                ram,
+            } => match addr {
+                0x0000..=0x1fff => *ram_enabled = enable,
+                0x2000..=0x3fff => *rom = (value & 0x7f).max(1),
+                0x4000..=0x5fff => *ram = value,
+                // latches the clock
+                _ => {}
+            },
+            Controller::Mbc5 {
+                ram_enabled,
+                rom,
+                ram,
+            } => match addr {
+                0x0000..=0x1fff => *ram_enabled = enable,
+                0x2000..=0x2fff => *rom = *rom & 0x100 | value as u16,
+                0x3000..=0x3fff => {
+                    let high = (value as u16 & 1) << 8;
+                    *rom = *rom & 0xff | high
+                }
+                0x4000..=0x5fff => *ram = value & 0xf,
+                _ => {}
+            },
+        }
+    }
+
+    /// the rom bank in 0x4000..0x8000, before it wraps around the rom
+    pub fn rom_bank(&self) -> u16 {
+        match *self {
+            Controller::None => 1,
+            Controller::Mbc1 { low, high, .. } => (high << 5 | low) as u16,
+            Controller::Mbc3 { rom, .. } => rom as u16,
+            Controller::Mbc5 { rom, .. } => rom,
+        }
+    }
+
+    /// the rom bank in 0x0000..0x4000. the mbc1 maps the high bits there
+    /// too in mode 1, which only shows on carts of 1 MiB or more.
+    pub fn rom0_bank(&self) -> u16 {
+        match *self {
+            Controller::Mbc1 {
+                high, mode: true, ..
+            } => (high as u16) << 5,
+            _ => 0,
+        }
+    }
+
+    /// the ram bank in 0xa000..0xc000, before it wraps around the ram.
+    /// None while there is none.
+    pub fn ram_bank(&self) -> Option<u8> {
+        match *self {
+            Controller::None => Some(0),
+            Controller::Mbc1 {
+                ram_enabled,
+                high,
+                mode,
+                ..
+            } => ram_enabled.then_some(if mode { high } else { 0 }),
+            Controller::Mbc3 {
+                ram_enabled, ram, ..
+            } => (ram_enabled && ram < 8).then_some(ram),
+            Controller::Mbc5 {
+                ram_enabled, ram, ..
+            } => ram_enabled.then_some(ram),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn after(mbc: Mbc, writes: &[(u16, u8)]) -> Controller {
+        let mut c = Controller::new(mbc).unwrap();
+        for &(addr, value) in writes {
+            c.write(addr, value);
+        }
+        c
+    }
+
+    #[test]
+    fn mbc1() {
+        assert_eq!(after(Mbc::Mbc1, &[]).rom_bank(), 1);
+        // bank 0 is 1, the high bits count in either mode
+        assert_eq!(after(Mbc::Mbc1, &[(0x2000, 0)]).rom_bank(), 1);
+        assert_eq!(after(Mbc::Mbc1, &[(0x2000, 0xe4)]).rom_bank(), 4);
+        let c = after(Mbc::Mbc1, &[(0x2100, 0x20), (0x4000, 2)]);
+        assert_eq!(c.rom_bank(), 0x41);
+
+        assert_eq!(after(Mbc::Mbc1, &[(0x4000, 2)]).ram_bank(), None);
+        let c = after(Mbc::Mbc1, &[(0x0000, 0x0a), (0x4000, 2)]);
+        assert_eq!(c.ram_bank(), Some(0));
+        let mode1 = [(0x0000, 0x0a), (0x4000, 2), (0x6000, 1)];
+        assert_eq!(after(Mbc::Mbc1, &mode1).ram_bank(), Some(2));
+        let c = after(Mbc::Mbc1, &[(0x0000, 0x0a), (0x1fff, 0)]);
+        assert_eq!(c.ram_bank(), None);
+
+        // mode 1 maps the high bits at 0 as well
+        assert_eq!(after(Mbc::Mbc1, &[(0x4000, 1)]).rom0_bank(), 0);
+        let c = after(Mbc::Mbc1, &[(0x4000, 1), (0x6000, 1)]);
+        assert_eq!((c.rom0_bank(), c.rom_bank()), (0x20, 0x21));
+        assert_eq!(after(Mbc::Mbc5, &[(0x6000, 1)]).rom0_bank(), 0);
+    }
+
+    #[test]
+    fn mbc3() {
+        assert_eq!(after(Mbc::Mbc3, &[(0x2000, 0)]).rom_bank(), 1);
+        assert_eq!(after(Mbc::Mbc3, &[(0x3fff, 0xff)]).rom_bank(), 0x7f);
+        let c = after(Mbc::Mbc3, &[(0x0000, 0x0a), (0x4000, 3)]);
+        assert_eq!(c.ram_bank(), Some(3));
+        // the clock
+        let c = after(Mbc::Mbc3, &[(0x0000, 0x0a), (0x4000, 8)]);
+        assert_eq!(c.ram_bank(), None);
+    }
+
+    #[test]
+    fn mbc5() {
+        // bank 0 is bank 0, and there is a 9th bit
+        assert_eq!(after(Mbc::Mbc5, &[(0x2000, 0)]).rom_bank(), 0);
+        let c = after(Mbc::Mbc5, &[(0x2000, 0x34), (0x3000, 1)]);
+        assert_eq!(c.rom_bank(), 0x134);
+        let c = after(Mbc::Mbc5, &[(0x3000, 1), (0x2000, 0x34)]);
+        assert_eq!(c.rom_bank(), 0x134);
+        let c = after(Mbc::Mbc5, &[(0x0000, 0x0a), (0x4000, 0x1f)]);
+        assert_eq!(c.ram_bank(), Some(0xf));
+    }
+}
@@ -0,0 +1,513 @@
+// runs a whole rom, compiling as execution gets somewhere new. whatever
+// the compiled code leaves to the runtime for is compiled with all the
+// cfg finds from there, and everything is mapped again, so blocks jump to
+// each other directly and only come back here for what is still missing.
+//
+// memory is whatever bus the cpu has. on a flat one code can write over
+// its own rom. the compiled code tracks stores and leaves when one hits a
+// line with code in it; blocks whose bytes changed go then, and compile
+// again from the new ones. code in ram compiles from memory as it is, and
+// checks it still is whenever it starts, for what tracking does not see.
+//
+// on a bus that switches banks, stores to the rom go to the bus instead,
+// and code compiles for whichever bank is mapped when it gets there.
+// another bank at 0x0000 is new code there, like a store would make.
+
+use crate::analysis::Cfg;
+use crate::bus::Bus;
+use crate::cartridge::{BankedAddr, Cartridge};
+use crate::interp::Cpu;
+use crate::jit::{Jit, JitError};
+use crate::transpile::abi::ExitReason;
+use crate::transpile::Context;
+
+pub struct Runtime {
+    rom: Cartridge,
+    // the block cache, by the sm83 addr each one starts at
+    ctx: Context,
+    // everything in ctx, unless something was compiled since
+    jit: Option<Jit>,
+}
+
+impl Runtime {
+    pub fn new(rom: Cartridge) -> Self {
+        Self {
+            rom,
+            ctx: Self::context(false),
+            jit: None,
+        }
+    }
+
+    fn context(switch_banks: bool) -> Context {
+        let mut ctx = Context::default();
+        ctx.track_writes = true;
+        ctx.trap_io = true;
+        ctx.switch_banks = switch_banks;
+        ctx
+    }
+
+    /// where each compiled block starts, sorted
+    pub fn compiled(&self) -> Vec<BankedAddr> {
+        self.ctx.compiled()
+    }
+
+    /// takes what `bus` has as the code from now on. blocks that were
+    /// compiled from other bytes go, to be compiled again.
+    fn load(&mut self, bus: &mut impl Bus) {
+        let mapped = bus.rom_bank();
+        let mem = bus.memory();
+        for start in self.ctx.stale(mem, mapped) {
+            self.ctx.invalidate(start);
+            self.jit = None;
+        }
+        let windows = [(0, &mem[..0x4000]), (mapped, &mem[0x4000..0x8000])];
+        for (bank, window) in windows {
+            let bank = self.rom.bank_mut(bank);
+            let len = bank.len();
+            bank.copy_from_slice(&window[..len]);
+        }
+    }
+
+    /// the block at `pc` and whatever the cfg finds from there
+    fn compile(&mut self, pc: BankedAddr) -> Result<(), JitError> {
+        for &start in Cfg::discover_from(&self.rom, &[pc]).blocks.keys() {
+            if !self.ctx.is_compiled(start) {
+                self.ctx.compile(&self.rom, start)?;
+                self.jit = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// runs `cpu` from its pc until it halts, stops or locks up.
+    /// `cpu.mem` should have the rom in it, data is read from there.
+    pub fn run<B: Bus>(
+        &mut self,
+        cpu: &mut Cpu<B>,
+    ) -> Result<ExitReason, JitError> {
+        // what was compiled for another kind of bus goes
+        let switch_banks = cpu.mem.switches_banks();
+        if switch_banks != self.ctx.switch_banks {
+            self.ctx = Self::context(switch_banks);
+            self.jit = None;
+        }
+        loop {
+            let pc = BankedAddr::new(cpu.regs.pc, cpu.mem.rom_bank());
+            if !self.ctx.is_compiled(pc) {
+                self.load(&mut cpu.mem);
+                if cpu.mem.region(pc.addr).is_rom() {
+                    self.compile(pc)?;
+                } else {
+                    self.ctx.compile_ram(cpu.mem.memory(), pc)?;
+                    self.jit = None;
+                }
+            }
+            let jit = match &mut self.jit {
+                Some(jit) => jit,
+                jit => {
+                    // any block can be where the last run left off
+                    let entries = self.ctx.compiled();
+                    jit.insert(Jit::new(&mut self.ctx, &entries)?)
+                }
+            };
+            match jit.run(pc, cpu).ok_or(JitError::NoEntry(pc))? {
+                ExitReason::Jump | ExitReason::Indirect => {}
+                ExitReason::CodeWrite => self.load(&mut cpu.mem),
+                exit => return Ok(exit),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::bus::{Banked, Flat};
+    use crate::cartridge::{test_rom as rom, test_rom_from};
+    use crate::io::Io;
+    use crate::sm83::asm::asm;
+    use crate::sm83::RegPair::*;
+
+    #[test]
+    fn compiles_what_it_gets_to() {
+        // nothing static finds table's targets, or where the ret goes
+        let code = asm!(
+            0x150,
+            "       ld b, 0
+                    ld hl, table
+            next:   ld e, [hl]
+                    inc hl
+                    ld d, [hl]
+                    inc hl
+                    push hl
+                    ld h, d
+                    ld l, e
+                    call go
+                    pop hl
+                    ld a, b
+                    cp 3
+                    jr nz, next
+                    ld hl, done
+                    push hl
+                    ret
+            go:     jp hl
+            one:    inc b
+                    ret
+            two:    inc b
+                    inc b
+                    ret
+            done:   ld [$c000], a
+                    halt
+            table:  dw one, two"
+        );
+        let rom = rom(&code);
+        let mut cpu = Cpu::default();
+        cpu.mem[..0x8000].copy_from_slice(rom.rom());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = cpu.clone();
+
+        let mut runtime = Runtime::new(rom);
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        assert!(cpu.mem[..] == reference.mem[..]);
+        assert_eq!(cpu.mem[0xc000], 3);
+
+        let compiled = runtime.compiled();
+        let (one, done) = (0x16b, 0x170);
+        for addr in [one, done] {
+            let addr = BankedAddr::new(addr, 0);
+            assert!(compiled.contains(&addr), "{addr:?} in {compiled:x?}");
+        }
+    }
+
+    #[test]
+    fn recompiles_code_that_changed() {
+        // the ld patches its own immediate, in the block that runs it
+        let code = asm!(
+            0x150,
+            "       ld b, 3
+            loop:   ld a, 0
+                    inc a
+                    ld [loop + 1], a
+                    dec b
+                    jr nz, loop
+                    ld [$c000], a
+                    halt"
+        );
+        let rom = rom(&code);
+        let mut cpu = Cpu::default();
+        cpu.mem[..0x8000].copy_from_slice(rom.rom());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = cpu.clone();
+
+        let mut runtime = Runtime::new(rom);
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        assert!(cpu.mem[..] == reference.mem[..]);
+        assert_eq!(cpu.mem[0xc000], 3);
+    }
+
+    #[test]
+    fn runs_code_copied_to_hram() {
+        // copies routine to $ff80, calls it, patches it, calls it again
+        let code = asm!(
+            0x150,
+            "       ld hl, routine
+                    ld de, $ff80
+                    ld c, end - routine
+            copy:   ld a, [hl+]
+                    ld [de], a
+                    inc de
+                    dec c
+                    jr nz, copy
+                    call $ff80
+                    ld b, a
+                    ld a, 7
+                    ld [$ff81], a
+                    call $ff80
+                    ld c, a
+                    halt
+            routine: ld a, 5
+                    ret
+            end:"
+        );
+        let rom = rom(&code);
+        let mut cpu = Cpu::default();
+        cpu.mem[..0x8000].copy_from_slice(rom.rom());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = cpu.clone();
+
+        let mut runtime = Runtime::new(rom);
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        assert!(cpu.mem[..] == reference.mem[..]);
+        assert_eq!((cpu.regs.b, cpu.regs.c), (5, 7));
+        let hram = BankedAddr::new(0xff80, 0);
+        assert!(runtime.compiled().contains(&hram));
+
+        // nothing tracks this one, the block sees it when it starts
+        cpu.mem[0xff81] = 9;
+        cpu.regs.pc = 0xff80;
+        cpu.regs.sp = 0xfffc;
+        // back to the halt
+        cpu.mem[0xfffc..].copy_from_slice(&[0x6b, 0x01, 0, 0]);
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        assert_eq!((cpu.regs.a, cpu.regs.pc), (9, 0x16c));
+    }
+
+    #[test]
+    fn leaves_frames_that_never_return_behind() {
+        // 0x40000 calls that pop their return address, a host frame each
+        let code = asm!(
+            0x150,
+            "       ld b, 4
+                    ld de, 0
+            loop:   call pop
+            pop:    pop hl
+                    dec de
+                    ld a, d
+                    or e
+                    jr nz, loop
+                    dec b
+                    jr nz, loop
+                    halt"
+        );
+        let rom = rom(&code);
+        let mut cpu = Cpu::default();
+        cpu.mem[..0x8000].copy_from_slice(rom.rom());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+
+        let mut runtime = Runtime::new(rom);
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        let regs = &cpu.regs;
+        assert_eq!((regs.b, regs.pair(DE), regs.pair(HL)), (0, 0, 0x158));
+        assert_eq!(regs.sp, 0xfffe);
+    }
+
+    #[test]
+    fn passes_io_to_the_callbacks() {
+        // prints "hi" to serial, until ly says vblank
+        let code = asm!(
+            0x150,
+            "       ld hl, text
+            next:   ld a, [hl+]
+                    ldh [$01], a
+                    or a
+                    jr nz, next
+            wait:   ldh a, [$44]
+                    cp 144
+                    jr c, wait
+                    halt
+            text:   db \"hi\", 0"
+        );
+        let rom = rom(&code);
+        let mut io = Io::new(Flat::default());
+        io.memory()[..0x8000].copy_from_slice(rom.rom());
+        let serial = Rc::new(RefCell::new(Vec::new()));
+        let sent = serial.clone();
+        io.on_write(0xff01..=0xff01, move |_, v| sent.borrow_mut().push(v));
+        let mut ly = 0;
+        io.on_read(0xff44..=0xff44, move |_| {
+            ly = (ly + 1) % 154;
+            ly
+        });
+        let mut cpu = Cpu::new(io);
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+
+        let mut runtime = Runtime::new(rom);
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        assert_eq!(*serial.borrow(), b"hi\0");
+        assert_eq!(cpu.mem.memory()[0xff44], 144);
+    }
+
+    #[test]
+    fn calls_into_the_bank_that_is_mapped() {
+        // each bank says which one it is, at the same addr
+        let code = asm!(
+            0x150,
+            "       ld a, $0a
+                    ld [$0000], a
+                    ld a, 2
+                    ld [$2000], a
+                    call $4000
+                    ld b, a
+                    ld a, 3
+                    ld [$2100], a
+                    call $4000
+                    ld c, a
+                    ld hl, $3fff
+                    ld [hl], 1
+                    call $4000
+                    ld d, a
+                    ld [$a000], a
+                    ld a, 1
+                    ld [$6000], a
+                    ld [$4000], a
+                    ld a, $77
+                    ld [$a000], a
+                    xor a
+                    ld [$6000], a
+                    ld a, [$a000]
+                    ld e, a
+                    ld [$0000], a
+                    ld a, $55
+                    ld [$a000], a
+                    ld a, [$a000]
+                    ld h, a
+                    ld a, $0a
+                    ld [$0000], a
+                    ld a, [$a000]
+                    ld l, a
+                    halt"
+        );
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x150..0x150 + code.len()].copy_from_slice(&code);
+        for bank in 1..4 {
+            let tag = bank as u8 * 0x11;
+            let at = bank * 0x4000;
+            bytes[at..at + 3].copy_from_slice(&[0x3e, tag, 0xc9]);
+        }
+        // mbc1 with 4 banks of ram
+        bytes[0x147] = 0x03;
+        bytes[0x149] = 0x03;
+        let banked = || Banked::new(test_rom_from(bytes.clone())).unwrap();
+
+        let mut cpu = Cpu::new(banked());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = Cpu::new(banked());
+        reference.regs = cpu.regs;
+
+        let mut runtime = Runtime::new(test_rom_from(bytes.clone()));
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        let (b, c, d, e) = (cpu.regs.b, cpu.regs.c, cpu.regs.d, cpu.regs.e);
+        assert_eq!((b, c, d, e), (0x22, 0x33, 0x11, 0x11));
+        // with the ram off the store goes nowhere
+        assert_eq!((cpu.regs.h, cpu.regs.l), (0xff, 0x11));
+        assert!(cpu.mem.memory()[..] == reference.mem.memory()[..]);
+        assert_eq!(cpu.mem.ram()[..], reference.mem.ram()[..]);
+        assert_eq!((cpu.mem.ram()[0], cpu.mem.ram()[0x2000]), (0x11, 0x77));
+        let compiled = runtime.compiled();
+        for bank in 1..4 {
+            let addr = BankedAddr::new(0x4000, bank);
+            assert!(compiled.contains(&addr), "{addr:?} in {compiled:x?}");
+        }
+    }
+
+    #[test]
+    fn goes_on_in_the_bank_it_switched_to() {
+        let home = asm!(0x150, "call $4000 / halt");
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x150..0x150 + home.len()].copy_from_slice(&home);
+        // every bank switches to the next and says which one it is
+        for bank in 1..4 {
+            let tag = bank * 0x11;
+            let code = asm!(
+                0x4000,
+                &format!(
+                    "ld a, 2 / ld [$2000], a / ld b, {tag}
+                     ld hl, $2000 / ld [hl], 3 / ld c, {tag}
+                     ret"
+                )
+            );
+            let at = bank * 0x4000;
+            bytes[at..at + code.len()].copy_from_slice(&code);
+        }
+        bytes[0x147] = 0x01;
+        let banked = || Banked::new(test_rom_from(bytes.clone())).unwrap();
+
+        let mut cpu = Cpu::new(banked());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = Cpu::new(banked());
+        reference.regs = cpu.regs;
+
+        let mut runtime = Runtime::new(test_rom_from(bytes.clone()));
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        assert_eq!((cpu.regs.b, cpu.regs.c), (0x22, 0x33));
+        assert_eq!(cpu.mem.rom_bank(), 3);
+    }
+
+    #[test]
+    fn rst_comes_back_to_its_bank() {
+        let home = asm!(0x150, "ld a, 2 / ld [$2000], a / call $4000 / halt");
+        let vector = asm!(0x08, "ld b, $11 / ret");
+        let far = asm!(0x4000, "rst $08 / ld c, $22 / rst $08 / ret");
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x150..0x150 + home.len()].copy_from_slice(&home);
+        bytes[0x08..0x08 + vector.len()].copy_from_slice(&vector);
+        bytes[0x8000..0x8000 + far.len()].copy_from_slice(&far);
+        bytes[0x147] = 0x01;
+        let banked = || Banked::new(test_rom_from(bytes.clone())).unwrap();
+
+        let mut cpu = Cpu::new(banked());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = Cpu::new(banked());
+        reference.regs = cpu.regs;
+
+        let mut runtime = Runtime::new(test_rom_from(bytes.clone()));
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        assert_eq!((cpu.regs.b, cpu.regs.c), (0x11, 0x22));
+        let after = BankedAddr::new(0x4001, 2);
+        assert!(runtime.compiled().contains(&after));
+    }
+
+    #[test]
+    fn goes_on_in_what_mbc1_mode_1_maps_at_0() {
+        // the same code in banks 0 and 0x20 but for what it says
+        let mut bytes = vec![0; 0x100000];
+        for (bank, tag) in [(0, 0x11), (0x20, 0x22)] {
+            let code = asm!(
+                0x150,
+                &format!(
+                    "       ld a, 1
+                            ld [$4000], a
+                            ld [$6000], a
+                            ld b, {tag}
+                            call sub
+                            xor a
+                            ld [$6000], a
+                            ld d, {tag}
+                            halt
+                    sub:    ld c, {tag}
+                            ret"
+                )
+            );
+            let at = bank * 0x4000 + 0x150;
+            bytes[at..at + code.len()].copy_from_slice(&code);
+        }
+        bytes[0x147] = 0x01;
+        let banked = || Banked::new(test_rom_from(bytes.clone())).unwrap();
+
+        let mut cpu = Cpu::new(banked());
+        cpu.regs.sp = 0xfffe;
+        cpu.regs.pc = 0x150;
+        let mut reference = Cpu::new(banked());
+        reference.regs = cpu.regs;
+
+        let mut runtime = Runtime::new(test_rom_from(bytes.clone()));
+        assert_eq!(runtime.run(&mut cpu).unwrap(), ExitReason::Halt);
+        reference.run_until(1000, |_| false);
+        assert_eq!(cpu.regs, reference.regs);
+        let (b, c, d) = (cpu.regs.b, cpu.regs.c, cpu.regs.d);
+        assert_eq!((b, c, d), (0x22, 0x22, 0x11));
+    }
+}
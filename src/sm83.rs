@@ -1,11 +1,16 @@
+pub mod asm;
 pub mod decode;
+pub mod disasm;
+pub mod encode;
+pub mod info;
 pub mod instructions;
 pub mod regs;
 //pub use decode::Instruction;
 
 pub use decode::*;
+pub use disasm::disasm;
 pub use instructions::{
-    AluBlockOp, Condition, Instruction, PrefixOp, RegOrNum,
+    AluBlockOp, Condition, Flow, Instruction, PrefixOp, RegOrNum,
 };
 pub use regs::{Reg, RegPair};
 
@@ -0,0 +1,762 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::sm83::*;
+
+// a small rgbds-flavoured assembler, mostly for writing test programs:
+//
+//     main:   ld a, 3 / add b
+//     .loop:  dec a
+//             jr nz, .loop   ; local to main
+//             ret
+//             db "hi", 0 / dw main + 2
+//
+// statements end at a newline or a `/`, so there is no division in
+// expressions.
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize, // 1-based
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug)]
+pub struct Program {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    // every assembled instruction and where it ended up
+    pub instrs: Vec<(u16, Instruction)>,
+    pub labels: HashMap<String, u16>,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Num(i64),
+    Label(String), // already qualified when local
+    Here,          // @, the start of the current instruction
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    R8(Reg), // includes [hl]
+    R16(RegPair),
+    Cond(Condition), // nz, z, nc; `c` is an R8 until we know better
+    MemPair(RegPair), // [bc], [de]
+    HlInc,           // [hl+], [hli]
+    HlDec,           // [hl-], [hld]
+    MemC,            // [c], [$ff00+c]
+    Mem(Expr),
+    SpRel(Expr), // sp+e8
+    Imm(Expr),
+    Str(Vec<u8>), // only for db
+}
+
+enum Stmt {
+    Label(String),
+    Instr(String, Vec<Operand>),
+    Db(Vec<Operand>),
+    Dw(Vec<Operand>),
+}
+
+struct Parser {
+    global: String, // the scope of local labels
+}
+
+/// splits at `sep`, but not inside quotes, brackets or parens
+fn split_top(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut depth, mut quoted, mut start) = (0, false, 0);
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' | '[' if !quoted => depth += 1,
+            ')' | ']' if !quoted => depth -= 1,
+            c if c == sep && !quoted && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// drops a `;` comment, unless it is in a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+impl Parser {
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.global, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn statement(&mut self, s: &str) -> Result<Vec<Stmt>, String> {
+        let mut out = vec![];
+        let mut s = s.trim();
+
+        // any number of labels first
+        while let Some(colon) = s.find(':') {
+            let name = s[..colon].trim();
+            if name.is_empty()
+                || !name.starts_with(is_ident_start)
+                || !name.chars().all(is_ident)
+            {
+                break;
+            }
+            if !name.starts_with('.') {
+                self.global = name.to_string();
+            }
+            out.push(Stmt::Label(self.qualify(name)));
+            // `label::` exports in rgbds, which means nothing here
+            s = s[colon + 1..].trim_start_matches(':').trim();
+        }
+        if s.is_empty() {
+            return Ok(out);
+        }
+
+        let (mnemonic, rest) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        let operands = if rest.is_empty() {
+            vec![]
+        } else {
+            split_top(rest, ',')
+                .into_iter()
+                .map(|op| self.operand(op.trim()))
+                .collect::<Result<_, _>>()?
+        };
+        out.push(match mnemonic.as_str() {
+            "db" => Stmt::Db(operands),
+            "dw" => Stmt::Dw(operands),
+            _ => Stmt::Instr(mnemonic, operands),
+        });
+        Ok(out)
+    }
+
+    fn operand(&self, s: &str) -> Result<Operand, String> {
+        if s.is_empty() {
+            return Err("missing operand".into());
+        }
+        if let Some(inner) = s.strip_prefix('"') {
+            let inner =
+                inner.strip_suffix('"').ok_or("unterminated string")?;
+            return Ok(Operand::Str(inner.as_bytes().to_vec()));
+        }
+        if let Ok(r) = s.parse::<Reg>() {
+            return Ok(Operand::R8(r));
+        }
+        if let Ok(rr) = s.parse::<RegPair>() {
+            return Ok(Operand::R16(rr));
+        }
+        if let Ok(c) = s.parse::<Condition>() {
+            return Ok(Operand::Cond(c));
+        }
+        if let Some(inner) = s.strip_prefix('[') {
+            let inner = inner.strip_suffix(']').ok_or("unclosed [")?;
+            let flat: String = inner
+                .split_whitespace()
+                .collect::<String>()
+                .to_ascii_lowercase();
+            return Ok(match flat.as_str() {
+                "hl+" | "hli" => Operand::HlInc,
+                "hl-" | "hld" => Operand::HlDec,
+                "c" | "$ff00+c" | "0xff00+c" => Operand::MemC,
+                _ => match flat.parse::<RegPair>() {
+                    Ok(rr @ (RegPair::BC | RegPair::DE)) => {
+                        Operand::MemPair(rr)
+                    }
+                    _ => Operand::Mem(self.expr(inner)?),
+                },
+            });
+        }
+        let lower = s.to_ascii_lowercase();
+        if let Some(rest) = lower.strip_prefix("sp") {
+            let rest = rest.trim_start();
+            if rest.starts_with(['+', '-']) {
+                return Ok(Operand::SpRel(self.expr(rest)?));
+            }
+        }
+        Ok(Operand::Imm(self.expr(s)?))
+    }
+
+    fn expr(&self, s: &str) -> Result<Expr, String> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut pos = 0;
+        let e = self.sum(&chars, &mut pos)?;
+        skip_ws(&chars, &mut pos);
+        if pos != chars.len() {
+            return Err(format!("junk after expression: `{s}`"));
+        }
+        Ok(e)
+    }
+
+    fn sum(&self, s: &[char], pos: &mut usize) -> Result<Expr, String> {
+        let mut lhs = self.product(s, pos)?;
+        loop {
+            skip_ws(s, pos);
+            match s.get(*pos) {
+                Some('+') => {
+                    *pos += 1;
+                    let rhs = self.product(s, pos)?;
+                    lhs = Expr::Add(lhs.into(), rhs.into());
+                }
+                Some('-') => {
+                    *pos += 1;
+                    let rhs = self.product(s, pos)?;
+                    lhs = Expr::Sub(lhs.into(), rhs.into());
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    fn product(
+        &self,
+        s: &[char],
+        pos: &mut usize,
+    ) -> Result<Expr, String> {
+        let mut lhs = self.unary(s, pos)?;
+        loop {
+            skip_ws(s, pos);
+            if s.get(*pos) != Some(&'*') {
+                return Ok(lhs);
+            }
+            *pos += 1;
+            let rhs = self.unary(s, pos)?;
+            lhs = Expr::Mul(lhs.into(), rhs.into());
+        }
+    }
+
+    fn unary(
+        &self,
+        s: &[char],
+        pos: &mut usize,
+    ) -> Result<Expr, String> {
+        skip_ws(s, pos);
+        let Some(&c) = s.get(*pos) else {
+            return Err("expected an expression".into());
+        };
+        let radix = |prefix_len: usize, radix: u32, pos: &mut usize| {
+            *pos += prefix_len;
+            let start = *pos;
+            while s.get(*pos).is_some_and(|c| c.is_digit(radix)) {
+                *pos += 1;
+            }
+            let digits: String = s[start..*pos].iter().collect();
+            i64::from_str_radix(&digits, radix)
+                .map(Expr::Num)
+                .map_err(|_| format!("bad number `{digits}`"))
+        };
+        match c {
+            '-' => {
+                *pos += 1;
+                Ok(Expr::Neg(self.unary(s, pos)?.into()))
+            }
+            '+' => {
+                *pos += 1;
+                self.unary(s, pos)
+            }
+            '(' => {
+                *pos += 1;
+                let e = self.sum(s, pos)?;
+                skip_ws(s, pos);
+                if s.get(*pos) != Some(&')') {
+                    return Err("unclosed (".into());
+                }
+                *pos += 1;
+                Ok(e)
+            }
+            '@' => {
+                *pos += 1;
+                Ok(Expr::Here)
+            }
+            '$' => radix(1, 16, pos),
+            '%' => radix(1, 2, pos),
+            '&' => radix(1, 8, pos),
+            '0' if matches!(s.get(*pos + 1), Some('x' | 'X')) => {
+                radix(2, 16, pos)
+            }
+            '0' if matches!(s.get(*pos + 1), Some('b' | 'B')) => {
+                radix(2, 2, pos)
+            }
+            '0'..='9' => radix(0, 10, pos),
+            '\'' => match (s.get(*pos + 1), s.get(*pos + 2)) {
+                (Some(&ch), Some('\'')) if ch.is_ascii() => {
+                    *pos += 3;
+                    Ok(Expr::Num(ch as i64))
+                }
+                _ => Err("bad character literal".into()),
+            },
+            c if is_ident_start(c) => {
+                let start = *pos;
+                while s.get(*pos).is_some_and(|&c| is_ident(c)) {
+                    *pos += 1;
+                }
+                let name: String = s[start..*pos].iter().collect();
+                Ok(Expr::Label(self.qualify(&name)))
+            }
+            c => Err(format!("unexpected `{c}`")),
+        }
+    }
+}
+
+fn skip_ws(s: &[char], pos: &mut usize) {
+    while s.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+}
+
+/// turns parsed statements into bytes. run once to lay out the labels,
+/// then again for real.
+struct Assembler<'a> {
+    labels: &'a HashMap<String, u16>,
+    // the first pass only needs sizes, so it lets unknown labels and
+    // out of range values slide
+    sizing: bool,
+}
+
+impl Assembler<'_> {
+    fn eval(&self, e: &Expr, here: u16) -> Result<i64, String> {
+        Ok(match e {
+            Expr::Num(n) => *n,
+            Expr::Here => here as i64,
+            Expr::Label(name) => match self.labels.get(name) {
+                Some(&addr) => addr as i64,
+                None if self.sizing => 0,
+                None => return Err(format!("unknown label `{name}`")),
+            },
+            Expr::Neg(e) => -self.eval(e, here)?,
+            Expr::Add(a, b) => {
+                self.eval(a, here)? + self.eval(b, here)?
+            }
+            Expr::Sub(a, b) => {
+                self.eval(a, here)? - self.eval(b, here)?
+            }
+            Expr::Mul(a, b) => {
+                self.eval(a, here)? * self.eval(b, here)?
+            }
+        })
+    }
+
+    fn ranged(
+        &self,
+        e: &Expr,
+        here: u16,
+        range: std::ops::RangeInclusive<i64>,
+    ) -> Result<i64, String> {
+        let v = self.eval(e, here)?;
+        if !self.sizing && !range.contains(&v) {
+            return Err(format!("{v} does not fit in {range:?}"));
+        }
+        Ok(v)
+    }
+
+    fn d8(&self, e: &Expr, here: u16) -> Result<u8, String> {
+        Ok(self.ranged(e, here, -128..=255)? as u8)
+    }
+
+    fn d16(&self, e: &Expr, here: u16) -> Result<u16, String> {
+        Ok(self.ranged(e, here, -32768..=65535)? as u16)
+    }
+
+    fn r8(&self, e: &Expr, here: u16) -> Result<i8, String> {
+        Ok(self.ranged(e, here, -128..=127)? as i8)
+    }
+
+    fn instr(
+        &self,
+        mnemonic: &str,
+        ops: &[Operand],
+        pc: u16,
+    ) -> Result<Instruction, String> {
+        use Instruction::*;
+        use Operand::*;
+        use Reg::A;
+        use RegPair::{BC, DE, HL, SP};
+
+        let cond = |op: &Operand| match op {
+            Cond(c) => Some(*c),
+            R8(Reg::C) => Some(Condition::C),
+            _ => None,
+        };
+        let alu = |op: AluBlockOp, x: &Operand| match x {
+            R8(r) => Ok(Alu_A_RegOrNum(op, RegOrNum::Reg(*r))),
+            Imm(e) => {
+                Ok(Alu_A_RegOrNum(op, RegOrNum::Num(self.d8(e, pc)?)))
+            }
+            _ => Err("bad operand".to_string()),
+        };
+        let rel = |e: &Expr| -> Result<i8, String> {
+            let dest = self.eval(e, pc)?;
+            let offset = dest - (pc as i64 + 2);
+            if !self.sizing && !(-128..=127).contains(&offset) {
+                return Err(format!(
+                    "jr target ${dest:04x} is too far"
+                ));
+            }
+            Ok(offset as i8)
+        };
+        let prefix = |op: PrefixOp, ops: &[Operand]| match ops {
+            [R8(r)] => Ok(Prefix(op, *r)),
+            _ => Err("expected a register".to_string()),
+        };
+        let bit = |ops: &[Operand]| match ops {
+            [Imm(e), R8(r)] => {
+                Ok((self.ranged(e, pc, 0..=7)? as u8, *r))
+            }
+            _ => {
+                Err("expected a bit number and a register".to_string())
+            }
+        };
+        // ldh takes $ff00 + a8, or just a8
+        let high = |e: &Expr| -> Result<u8, String> {
+            let v = self.eval(e, pc)?;
+            match v {
+                0xff00..=0xffff => Ok(v as u8),
+                0..=0xff => Ok(v as u8),
+                _ if self.sizing => Ok(0),
+                _ => Err(format!("ldh can not reach ${v:04x}")),
+            }
+        };
+
+        Ok(match (mnemonic, ops) {
+            ("nop", []) => NOP,
+            ("stop", []) => STOP(0),
+            ("stop", [Imm(e)]) => STOP(self.d8(e, pc)?),
+            ("halt", []) => HALT,
+            ("di", []) => DI,
+            ("ei", []) => EI,
+            ("daa", []) => DAA,
+            ("cpl", []) => CPL,
+            ("scf", []) => SCF,
+            ("ccf", []) => CCF,
+            ("rlca", []) => RLCA,
+            ("rrca", []) => RRCA,
+            ("rla", []) => RLA,
+            ("rra", []) => RRA,
+
+            ("ld", [R8(Reg::HL_), R8(Reg::HL_)]) => {
+                return Err("`ld [hl], [hl]` is halt".into())
+            }
+            ("ld", [R8(r1), R8(r2)]) => LD_r_r(*r1, *r2),
+            ("ld", [R8(r), Imm(e)]) => LD_r_d8(*r, self.d8(e, pc)?),
+            ("ld", [R16(rr @ (BC | DE | HL | SP)), Imm(e)]) => {
+                LD_rr_d16(*rr, self.d16(e, pc)?)
+            }
+            ("ld", [MemPair(rr), R8(A)]) => LD_prr_A(*rr),
+            ("ld", [R8(A), MemPair(rr)]) => LD_A_prr(*rr),
+            ("ld" | "ldi", [HlInc, R8(A)]) => LD_pHLi_A,
+            ("ld" | "ldi", [R8(A), HlInc]) => LD_A_pHLi,
+            ("ld" | "ldd", [HlDec, R8(A)]) => LD_pHLd_A,
+            ("ld" | "ldd", [R8(A), HlDec]) => LD_A_pHLd,
+            ("ldi", [R8(Reg::HL_), R8(A)]) => LD_pHLi_A,
+            ("ldi", [R8(A), R8(Reg::HL_)]) => LD_A_pHLi,
+            ("ldd", [R8(Reg::HL_), R8(A)]) => LD_pHLd_A,
+            ("ldd", [R8(A), R8(Reg::HL_)]) => LD_A_pHLd,
+            ("ld", [Mem(e), R8(A)]) => LD_pa16_A(self.d16(e, pc)?),
+            ("ld", [R8(A), Mem(e)]) => LD_A_pa16(self.d16(e, pc)?),
+            ("ld", [Mem(e), R16(SP)]) => LD_pa16_SP(self.d16(e, pc)?),
+            ("ld", [R16(SP), R16(HL)]) => LD_SP_HL,
+            ("ld", [R16(HL), SpRel(e)]) => LD_HL_SP_r8(self.r8(e, pc)?),
+            ("ld" | "ldh", [MemC, R8(A)]) => LDH_pC_A,
+            ("ld" | "ldh", [R8(A), MemC]) => LDH_A_pC,
+            ("ldh", [Mem(e), R8(A)]) => LDH_pa8_A(high(e)?),
+            ("ldh", [R8(A), Mem(e)]) => LDH_A_pa8(high(e)?),
+
+            ("inc", [R8(r)]) => INC_r(*r),
+            ("dec", [R8(r)]) => DEC_r(*r),
+            ("inc", [R16(rr @ (BC | DE | HL | SP))]) => INC_rr(*rr),
+            ("dec", [R16(rr @ (BC | DE | HL | SP))]) => DEC_rr(*rr),
+
+            ("add", [R16(HL), R16(rr @ (BC | DE | HL | SP))]) => {
+                ADD_HL_rr(*rr)
+            }
+            ("add", [R16(SP), Imm(e)]) => ADD_SP_r8(self.r8(e, pc)?),
+            (
+                op @ ("add" | "adc" | "sub" | "sbc" | "and" | "xor"
+                | "or" | "cp"),
+                [R8(A), x] | [x],
+            ) => {
+                use AluBlockOp::*;
+                let op = match op {
+                    "add" => ADD,
+                    "adc" => ADC,
+                    "sub" => SUB,
+                    "sbc" => SBC,
+                    "and" => AND,
+                    "xor" => XOR,
+                    "or" => OR,
+                    _ => CP,
+                };
+                alu(op, x)?
+            }
+
+            ("jp", [R16(HL)] | [R8(Reg::HL_)]) => JP_HL,
+            ("jp", [Imm(e)]) => JP_a16(self.d16(e, pc)?),
+            ("jp", [c, Imm(e)]) if cond(c).is_some() => {
+                JP_c_a16(cond(c).unwrap(), self.d16(e, pc)?)
+            }
+            ("jr", [Imm(e)]) => JR_r8(rel(e)?),
+            ("jr", [c, Imm(e)]) if cond(c).is_some() => {
+                JR_c_r8(cond(c).unwrap(), rel(e)?)
+            }
+            ("call", [Imm(e)]) => CALL_a16(self.d16(e, pc)?),
+            ("call", [c, Imm(e)]) if cond(c).is_some() => {
+                CALL_c_a16(cond(c).unwrap(), self.d16(e, pc)?)
+            }
+            ("ret", []) => RET,
+            ("ret", [c]) if cond(c).is_some() => {
+                RET_c(cond(c).unwrap())
+            }
+            ("reti", []) => RETI,
+            ("rst", [Imm(e)]) => {
+                let vec = self.eval(e, pc)?;
+                if !self.sizing && (vec & !0x38 != 0) {
+                    return Err(format!(
+                        "${vec:02x} is not an rst vector"
+                    ));
+                }
+                RST_vector(vec as u8 & 0x38)
+            }
+            ("push", [R16(rr @ (BC | DE | HL | RegPair::AF))]) => {
+                PUSH_rr(*rr)
+            }
+            ("pop", [R16(rr @ (BC | DE | HL | RegPair::AF))]) => {
+                POP_rr(*rr)
+            }
+
+            ("rlc", ops) => prefix(PrefixOp::RLC, ops)?,
+            ("rrc", ops) => prefix(PrefixOp::RRC, ops)?,
+            ("rl", ops) => prefix(PrefixOp::RL, ops)?,
+            ("rr", ops) => prefix(PrefixOp::RR, ops)?,
+            ("sla", ops) => prefix(PrefixOp::SLA, ops)?,
+            ("sra", ops) => prefix(PrefixOp::SRA, ops)?,
+            ("swap", ops) => prefix(PrefixOp::SWAP, ops)?,
+            ("srl", ops) => prefix(PrefixOp::SRL, ops)?,
+            ("bit", ops) => {
+                let (n, r) = bit(ops)?;
+                Prefix(PrefixOp::BIT(n), r)
+            }
+            ("res", ops) => {
+                let (n, r) = bit(ops)?;
+                Prefix(PrefixOp::RES(n), r)
+            }
+            ("set", ops) => {
+                let (n, r) = bit(ops)?;
+                Prefix(PrefixOp::SET(n), r)
+            }
+
+            _ => {
+                return Err(format!(
+                    "can not assemble `{mnemonic}` here"
+                ))
+            }
+        })
+    }
+
+    fn data(
+        &self,
+        ops: &[Operand],
+        word: bool,
+        pc: u16,
+        out: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        for op in ops {
+            match op {
+                Operand::Str(s) if !word => out.extend(s),
+                Operand::Imm(e) if word => {
+                    out.extend(self.d16(e, pc)?.to_le_bytes())
+                }
+                Operand::Imm(e) => out.push(self.d8(e, pc)?),
+                _ => return Err("bad data".into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// assembles `src` as if it was loaded at `origin`
+pub fn assemble(src: &str, origin: u16) -> Result<Program, AsmError> {
+    let mut parser = Parser {
+        global: String::new(),
+    };
+    let mut stmts = vec![];
+    for (i, line) in src.lines().enumerate() {
+        for s in split_top(strip_comment(line), '/') {
+            let parsed = parser
+                .statement(s)
+                .map_err(|msg| AsmError { line: i + 1, msg })?;
+            stmts.extend(parsed.into_iter().map(|s| (i + 1, s)));
+        }
+    }
+
+    let mut labels = HashMap::new();
+    for sizing in [true, false] {
+        let asm = Assembler {
+            labels: &labels,
+            sizing,
+        };
+        let mut new_labels = HashMap::new();
+        let mut bytes = vec![];
+        let mut instrs = vec![];
+        for (line, stmt) in &stmts {
+            let pc = origin.wrapping_add(bytes.len() as u16);
+            let err = |msg| AsmError { line: *line, msg };
+            match stmt {
+                Stmt::Label(name) => {
+                    if new_labels.insert(name.clone(), pc).is_some() {
+                        return Err(err(format!(
+                            "`{name}` defined twice"
+                        )));
+                    }
+                }
+                Stmt::Instr(mnemonic, ops) => {
+                    let instr =
+                        asm.instr(mnemonic, ops, pc).map_err(err)?;
+                    bytes.extend(instr.encode());
+                    instrs.push((pc, instr));
+                }
+                Stmt::Db(ops) => {
+                    asm.data(ops, false, pc, &mut bytes).map_err(err)?
+                }
+                Stmt::Dw(ops) => {
+                    asm.data(ops, true, pc, &mut bytes).map_err(err)?
+                }
+            }
+        }
+        if !sizing {
+            return Ok(Program {
+                origin,
+                bytes,
+                instrs,
+                labels,
+            });
+        }
+        labels = new_labels;
+    }
+    unreachable!()
+}
+
+/// assembles a snippet, panicking on errors. `asm!("ld a, 3 / ret")`
+/// gives the bytes, loaded at 0; `asm!(0x150, "...")` loads at 0x150.
+#[allow(unused_macros)]
+macro_rules! asm {
+    ($origin:expr, $src:expr) => {
+        match $crate::sm83::asm::assemble($src, $origin) {
+            Ok(program) => program.bytes,
+            Err(e) => panic!("{e}"),
+        }
+    };
+    ($src:expr) => {
+        $crate::sm83::asm::asm!(0, $src)
+    };
+}
+#[allow(unused_imports)]
+pub(crate) use asm;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet() {
+        assert_eq!(
+            asm!("ld a, 3 / add b / ret"),
+            [0x3e, 3, 0x80, 0xc9]
+        );
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let src = "
+            main:   ld hl, data     ; $21 lo hi
+            .loop:  ld a, [hl+]
+                    and a
+                    jr nz, .loop
+                    jp main
+            other:
+            .loop:  jr .loop        ; not main.loop
+            data:   db \"hi\", 0 / dw main + 2, @
+        ";
+        let program = assemble(src, 0x150).unwrap();
+        assert_eq!(program.labels["main.loop"], 0x153);
+        assert_eq!(program.labels["other.loop"], 0x15a);
+        assert_eq!(
+            program.bytes,
+            [
+                0x21, 0x5c, 0x01, // ld hl, data
+                0x2a, 0xa7, 0x20, 0xfc, // loop
+                0xc3, 0x50, 0x01, // jp main
+                0x18, 0xfe, // other.loop
+                b'h', b'i', 0, 0x52, 0x01, 0x5f, 0x01,
+            ]
+        );
+    }
+
+    #[test]
+    fn disassembly_reassembles() {
+        // every instruction prints as something we assemble back
+        for op in 0..=255u8 {
+            for b1 in [0x00, 0x01, 0x7f, 0x80, 0xfe, 0xff] {
+                let bytes = [op, b1, 0x12];
+                let instr = decode_instr(bytes);
+                let text = instr.to_string();
+                let program = assemble(&text, 0x4000)
+                    .unwrap_or_else(|e| panic!("`{text}`: {e}"));
+                assert_eq!(
+                    program.bytes,
+                    bytes[..instr.len() as usize],
+                    "`{text}`"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |src| assemble(src, 0).unwrap_err().msg;
+        assert!(err("jr $1000").contains("too far"));
+        assert!(err("ld a, nowhere").contains("unknown label"));
+        assert!(err("ld a, 256").contains("does not fit"));
+        assert!(err("rst 3").contains("not an rst vector"));
+        assert!(err("ld [hl], [hl]").contains("halt"));
+    }
+}
@@ -6,7 +6,7 @@ pub fn decode_instr(inst: [u8; 3]) -> Instruction {
     use Instruction::*;
     use RegPair::*;
     let d8: u8 = inst[1]; //
-    let d16: u16 = inst[1] as u16 + (inst[2] as u16) << 8; // little endian
+    let d16: u16 = u16::from_le_bytes([inst[1], inst[2]]);
     let r8 = inst[1] as i8;
 
     // static REGS: [&Reg; 8] = [&B, &C, &D, &E, &H, &L, &HL_, &A];
@@ -83,11 +83,11 @@ pub fn decode_instr(inst: [u8; 3]) -> Instruction {
         0o303 => JP_a16(d16),
         0xCB => prefix(inst[1]),
         0o323 | 0o333 | 0o343 | 0o353 | 0o344 | 0o354 | 0o364
-        | 0o374 | 0o335 | 0o355 | 0o375 => Invalid,
+        | 0o374 | 0o335 | 0o355 | 0o375 => Invalid(inst[0]),
         0o363 => DI,
         0o373 => EI,
         0o304 | 0o314 | 0o324 | 0o334 => CALL_c_a16(c, d16),
-        0o305 | 0o325 | 0o345 => POP_rr(rr),
+        0o305 | 0o325 | 0o345 => PUSH_rr(rr),
         0o365 => PUSH_rr(AF),
         0o315 => CALL_a16(d16),
         0o306 => Alu_A_RegOrNum(AluBlockOp::ADD, RegOrNum::Num(d8)),
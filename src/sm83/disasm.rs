@@ -0,0 +1,274 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::cartridge::{BankedAddr, Cartridge};
+use crate::sm83::*;
+
+// everything here prints rgbds syntax
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Reg::*;
+        f.write_str(match self {
+            B => "b",
+            C => "c",
+            D => "d",
+            E => "e",
+            H => "h",
+            L => "l",
+            HL_ => "[hl]",
+            A => "a",
+            F => "f",
+        })
+    }
+}
+
+impl fmt::Display for RegPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RegPair::*;
+        f.write_str(match self {
+            BC => "bc",
+            DE => "de",
+            HL => "hl",
+            SP => "sp",
+            AF => "af",
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Condition::*;
+        f.write_str(match self {
+            NZ => "nz",
+            Z => "z",
+            NC => "nc",
+            C => "c",
+        })
+    }
+}
+
+impl fmt::Display for PrefixOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PrefixOp::*;
+        match self {
+            RLC => f.write_str("rlc"),
+            RRC => f.write_str("rrc"),
+            RL => f.write_str("rl"),
+            RR => f.write_str("rr"),
+            SLA => f.write_str("sla"),
+            SRA => f.write_str("sra"),
+            SWAP => f.write_str("swap"),
+            SRL => f.write_str("srl"),
+            BIT(u3) => write!(f, "bit {u3},"),
+            RES(u3) => write!(f, "res {u3},"),
+            SET(u3) => write!(f, "set {u3},"),
+        }
+    }
+}
+
+impl fmt::Display for AluBlockOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AluBlockOp::*;
+        // the way rgbds writes them: `a, ` on add, adc and sbc, the rest
+        // take only the operand
+        f.write_str(match self {
+            ADD => "add a,",
+            ADC => "adc a,",
+            SUB => "sub",
+            SBC => "sbc a,",
+            AND => "and",
+            XOR => "xor",
+            OR => "or",
+            CP => "cp",
+        })
+    }
+}
+
+impl fmt::Display for RegOrNum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegOrNum::Reg(r) => write!(f, "{r}"),
+            RegOrNum::Num(d8) => write!(f, "${d8:02x}"),
+        }
+    }
+}
+
+/// `+3` / `-3`, for `add sp, r8` and friends
+struct Signed(i16);
+
+impl fmt::Display for Signed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:+}", self.0)
+    }
+}
+
+/// an instruction at a known address, so `jr` can print its target
+pub struct At(pub Instruction, pub u16);
+
+impl fmt::Display for At {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        let At(instr, pc) = *self;
+        match (instr, instr.flow(pc)) {
+            (JR_r8(_), Flow::Jump(dest)) => write!(f, "jr ${dest:04x}"),
+            (JR_c_r8(c, _), Flow::Branch(_, dest)) => {
+                write!(f, "jr {c}, ${dest:04x}")
+            }
+            _ => write!(f, "{instr}"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        // jr without an address: relative to `@`, the start of the jr
+        let rel = |r8: i8| Signed(r8 as i16 + 2);
+        match *self {
+            NOP => write!(f, "nop"),
+            LD_pa16_SP(a16) => write!(f, "ld [${a16:04x}], sp"),
+            STOP(0) => write!(f, "stop"),
+            STOP(d8) => write!(f, "stop ${d8:02x}"),
+            JR_r8(r8) => write!(f, "jr @{}", rel(r8)),
+            JR_c_r8(c, r8) => write!(f, "jr {c}, @{}", rel(r8)),
+            LD_rr_d16(rr, d16) => write!(f, "ld {rr}, ${d16:04x}"),
+            ADD_HL_rr(rr) => write!(f, "add hl, {rr}"),
+            LD_prr_A(rr) => write!(f, "ld [{rr}], a"),
+            LD_A_prr(rr) => write!(f, "ld a, [{rr}]"),
+            LD_pHLi_A => write!(f, "ld [hl+], a"),
+            LD_A_pHLi => write!(f, "ld a, [hl+]"),
+            LD_pHLd_A => write!(f, "ld [hl-], a"),
+            LD_A_pHLd => write!(f, "ld a, [hl-]"),
+            INC_rr(rr) => write!(f, "inc {rr}"),
+            DEC_rr(rr) => write!(f, "dec {rr}"),
+            INC_r(r) => write!(f, "inc {r}"),
+            DEC_r(r) => write!(f, "dec {r}"),
+            LD_r_d8(r, d8) => write!(f, "ld {r}, ${d8:02x}"),
+            RLCA => write!(f, "rlca"),
+            RRCA => write!(f, "rrca"),
+            RLA => write!(f, "rla"),
+            RRA => write!(f, "rra"),
+            DAA => write!(f, "daa"),
+            CPL => write!(f, "cpl"),
+            SCF => write!(f, "scf"),
+            CCF => write!(f, "ccf"),
+            HALT => write!(f, "halt"),
+            LD_r_r(r1, r2) => write!(f, "ld {r1}, {r2}"),
+            Alu_A_RegOrNum(op, operand) => write!(f, "{op} {operand}"),
+            RET_c(c) => write!(f, "ret {c}"),
+            LDH_pa8_A(a8) => write!(f, "ldh [$ff{a8:02x}], a"),
+            ADD_SP_r8(r8) => write!(f, "add sp, {}", Signed(r8 as i16)),
+            LDH_A_pa8(a8) => write!(f, "ldh a, [$ff{a8:02x}]"),
+            LD_HL_SP_r8(r8) => write!(f, "ld hl, sp{}", Signed(r8 as i16)),
+            POP_rr(rr) => write!(f, "pop {rr}"),
+            RET => write!(f, "ret"),
+            RETI => write!(f, "reti"),
+            JP_HL => write!(f, "jp hl"),
+            LD_SP_HL => write!(f, "ld sp, hl"),
+            JP_c_a16(c, a16) => write!(f, "jp {c}, ${a16:04x}"),
+            LDH_pC_A => write!(f, "ldh [c], a"),
+            LD_pa16_A(a16) => write!(f, "ld [${a16:04x}], a"),
+            LDH_A_pC => write!(f, "ldh a, [c]"),
+            LD_A_pa16(a16) => write!(f, "ld a, [${a16:04x}]"),
+            JP_a16(a16) => write!(f, "jp ${a16:04x}"),
+            Prefix(op, r) => write!(f, "{op} {r}"),
+            Invalid(op) => write!(f, "db ${op:02x}"),
+            DI => write!(f, "di"),
+            EI => write!(f, "ei"),
+            CALL_c_a16(c, a16) => write!(f, "call {c}, ${a16:04x}"),
+            PUSH_rr(rr) => write!(f, "push {rr}"),
+            CALL_a16(a16) => write!(f, "call ${a16:04x}"),
+            RST_vector(vec) => write!(f, "rst ${vec:02x}"),
+        }
+    }
+}
+
+/// prints an `address  bytes  mnemonic` listing of `range` in `bank`,
+/// decoding linearly from its start.
+pub fn disasm(
+    out: &mut impl Write,
+    rom: &Cartridge,
+    bank: u16,
+    range: std::ops::Range<u16>,
+) -> io::Result<()> {
+    let mut pc = BankedAddr::new(range.start, bank);
+    while range.contains(&pc.addr) {
+        let bytes = rom.instr_bytes(pc);
+        let instr = decode_instr(bytes);
+        let len = instr.len() as usize;
+
+        let hex = bytes[..len]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(out, "{pc}  {hex:<8}  {}", At(instr, pc.addr))?;
+
+        match pc.addr.checked_add(len as u16) {
+            Some(next) => pc = BankedAddr::new(next, bank),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+
+    fn text(bytes: &[u8]) -> String {
+        let mut b = [0; 3];
+        b[..bytes.len()].copy_from_slice(bytes);
+        decode_instr(b).to_string()
+    }
+
+    #[test]
+    fn prints_rgbds() {
+        assert_eq!(text(&[0x22]), "ld [hl+], a");
+        assert_eq!(text(&[0x3a]), "ld a, [hl-]");
+        assert_eq!(text(&[0xe2]), "ldh [c], a");
+        assert_eq!(text(&[0xf0, 0x44]), "ldh a, [$ff44]");
+        assert_eq!(text(&[0x08, 0x34, 0x12]), "ld [$1234], sp");
+        assert_eq!(text(&[0x80]), "add a, b");
+        assert_eq!(text(&[0x9e]), "sbc a, [hl]");
+        assert_eq!(text(&[0xd6, 0x10]), "sub $10");
+        assert_eq!(text(&[0xe8, 0xfe]), "add sp, -2");
+        assert_eq!(text(&[0xf8, 0x05]), "ld hl, sp+5");
+        assert_eq!(text(&[0xcb, 0x7c]), "bit 7, h");
+        assert_eq!(text(&[0xcb, 0x37]), "swap a");
+        assert_eq!(text(&[0xff]), "rst $38");
+        assert_eq!(text(&[0x10, 0x00]), "stop");
+        assert_eq!(text(&[0xd3]), "db $d3");
+        // without an address jr is relative to itself
+        assert_eq!(text(&[0x18, 0xfe]), "jr @+0");
+        assert_eq!(text(&[0x20, 0x03]), "jr nz, @+5");
+    }
+
+    #[test]
+    fn at_prints_jr_targets() {
+        use Instruction::*;
+        assert_eq!(At(JR_r8(-2), 0x150).to_string(), "jr $0150");
+        assert_eq!(
+            At(JR_c_r8(Condition::C, 5), 0x4000).to_string(),
+            "jr c, $4007"
+        );
+        assert_eq!(At(JP_a16(0x150), 0x4000).to_string(), "jp $0150");
+    }
+
+    #[test]
+    fn lists_a_range() {
+        let rom = test_rom(&[0x3e, 0x12, 0x18, 0xfe, 0xc3, 0x50, 0x01]);
+        let mut out = vec![];
+        disasm(&mut out, &rom, 0, 0x150..0x155).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "00:0150  3e 12     ld a, $12\n\
+             00:0152  18 fe     jr $0152\n\
+             00:0154  c3 50 01  jp $0150\n"
+        );
+    }
+}
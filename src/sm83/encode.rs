@@ -0,0 +1,196 @@
+#![allow(dead_code)]
+
+use arrayvec::ArrayVec;
+
+use crate::sm83::*;
+
+// the inverse of decode_instr. octal again, it lines up with the opcode
+// table.
+
+const fn r(r: Reg) -> u8 {
+    match r {
+        Reg::F => panic!("F is not an operand"),
+        _ => r as u8,
+    }
+}
+
+// the rr of ld rr, d16 and friends
+const fn rr(rr: RegPair) -> u8 {
+    match rr {
+        RegPair::AF => panic!("AF is only an operand of push/pop"),
+        _ => rr as u8,
+    }
+}
+
+// the rr of push/pop, where AF takes the place of SP
+const fn qq(rr: RegPair) -> u8 {
+    match rr {
+        RegPair::SP => panic!("SP is not an operand of push/pop"),
+        RegPair::AF => 3,
+        _ => rr as u8,
+    }
+}
+
+impl PrefixOp {
+    /// the byte after 0xcb, without the register
+    pub const fn opcode(self) -> u8 {
+        use PrefixOp::*;
+        match self {
+            RLC => 0o000,
+            RRC => 0o010,
+            RL => 0o020,
+            RR => 0o030,
+            SLA => 0o040,
+            SRA => 0o050,
+            SWAP => 0o060,
+            SRL => 0o070,
+            BIT(u3) => 0o100 | (u3 & 7) << 3,
+            RES(u3) => 0o200 | (u3 & 7) << 3,
+            SET(u3) => 0o300 | (u3 & 7) << 3,
+        }
+    }
+}
+
+impl Instruction {
+    /// the bytes `decode_instr` turns back into this instruction.
+    ///
+    /// panics on operands the decoder never produces, like `ld [hl], a`
+    /// spelled as `LD_prr_A(HL)`.
+    pub fn encode(&self) -> ArrayVec<u8, 3> {
+        use Instruction::*;
+
+        let mut out = ArrayVec::new();
+        match *self {
+            NOP => out.push(0o000),
+            LD_pa16_SP(a16) => {
+                out.push(0o010);
+                out.extend(a16.to_le_bytes());
+            }
+            STOP(d8) => out.extend([0o020, d8]),
+            JR_r8(r8) => out.extend([0o030, r8 as u8]),
+            JR_c_r8(c, r8) => {
+                out.extend([0o040 | (c as u8) << 3, r8 as u8])
+            }
+            LD_rr_d16(p, d16) => {
+                out.push(0o001 | rr(p) << 4);
+                out.extend(d16.to_le_bytes());
+            }
+            ADD_HL_rr(p) => out.push(0o011 | rr(p) << 4),
+            LD_prr_A(p) => {
+                assert!(matches!(p, RegPair::BC | RegPair::DE));
+                out.push(0o002 | rr(p) << 4)
+            }
+            LD_A_prr(p) => {
+                assert!(matches!(p, RegPair::BC | RegPair::DE));
+                out.push(0o012 | rr(p) << 4)
+            }
+            LD_pHLi_A => out.push(0o042),
+            LD_A_pHLi => out.push(0o052),
+            LD_pHLd_A => out.push(0o062),
+            LD_A_pHLd => out.push(0o072),
+            INC_rr(p) => out.push(0o003 | rr(p) << 4),
+            DEC_rr(p) => out.push(0o013 | rr(p) << 4),
+            INC_r(x) => out.push(0o004 | r(x) << 3),
+            DEC_r(x) => out.push(0o005 | r(x) << 3),
+            LD_r_d8(x, d8) => out.extend([0o006 | r(x) << 3, d8]),
+            RLCA => out.push(0o007),
+            RRCA => out.push(0o017),
+            RLA => out.push(0o027),
+            RRA => out.push(0o037),
+            DAA => out.push(0o047),
+            CPL => out.push(0o057),
+            SCF => out.push(0o067),
+            CCF => out.push(0o077),
+            HALT => out.push(0o166),
+            LD_r_r(Reg::HL_, Reg::HL_) => panic!("that is halt"),
+            LD_r_r(r1, r2) => out.push(0o100 | r(r1) << 3 | r(r2)),
+            Alu_A_RegOrNum(alu, RegOrNum::Reg(x)) => {
+                out.push(0o200 | alu as u8 | r(x))
+            }
+            Alu_A_RegOrNum(alu, RegOrNum::Num(d8)) => {
+                out.extend([0o306 | alu as u8, d8])
+            }
+            RET_c(c) => out.push(0o300 | (c as u8) << 3),
+            LDH_pa8_A(a8) => out.extend([0o340, a8]),
+            ADD_SP_r8(r8) => out.extend([0o350, r8 as u8]),
+            LDH_A_pa8(a8) => out.extend([0o360, a8]),
+            LD_HL_SP_r8(r8) => out.extend([0o370, r8 as u8]),
+            POP_rr(p) => out.push(0o301 | qq(p) << 4),
+            RET => out.push(0o311),
+            RETI => out.push(0o331),
+            JP_HL => out.push(0o351),
+            LD_SP_HL => out.push(0o371),
+            JP_c_a16(c, a16) => {
+                out.push(0o302 | (c as u8) << 3);
+                out.extend(a16.to_le_bytes());
+            }
+            LDH_pC_A => out.push(0o342),
+            LD_pa16_A(a16) => {
+                out.push(0o352);
+                out.extend(a16.to_le_bytes());
+            }
+            LDH_A_pC => out.push(0o362),
+            LD_A_pa16(a16) => {
+                out.push(0o372);
+                out.extend(a16.to_le_bytes());
+            }
+            JP_a16(a16) => {
+                out.push(0o303);
+                out.extend(a16.to_le_bytes());
+            }
+            Prefix(p, x) => out.extend([0xcb, p.opcode() | r(x)]),
+            Invalid(opcode) => out.push(opcode),
+            DI => out.push(0o363),
+            EI => out.push(0o373),
+            CALL_c_a16(c, a16) => {
+                out.push(0o304 | (c as u8) << 3);
+                out.extend(a16.to_le_bytes());
+            }
+            PUSH_rr(p) => out.push(0o305 | qq(p) << 4),
+            CALL_a16(a16) => {
+                out.push(0o315);
+                out.extend(a16.to_le_bytes());
+            }
+            RST_vector(vec) => {
+                assert!(vec & !0o070 == 0, "not an rst vector");
+                out.push(0o307 | vec)
+            }
+        }
+        debug_assert_eq!(out.len(), self.len() as usize);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encode_round_trip() {
+        // every opcode with every operand, the whole 24 bit space
+        for bytes in 0..1u32 << 24 {
+            let [op, b1, b2, _] = bytes.to_le_bytes();
+            let instr = decode_instr([op, b1, b2]);
+            let encoded = instr.encode();
+            assert_eq!(
+                encoded[..],
+                [op, b1, b2][..encoded.len()],
+                "{instr:?} does not round trip"
+            );
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        // and the other way: re-decoding gives the same instruction
+        for op in 0..=255u8 {
+            for b1 in 0..=255u8 {
+                let instr = decode_instr([op, b1, 0x5a]);
+                let mut bytes = [0; 3];
+                let encoded = instr.encode();
+                bytes[..encoded.len()].copy_from_slice(&encoded);
+                assert_eq!(decode_instr(bytes), instr);
+            }
+        }
+    }
+}
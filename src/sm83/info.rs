@@ -0,0 +1,405 @@
+#![allow(dead_code)]
+
+use std::io::{self, Write};
+
+use crate::sm83::*;
+
+/// a set of flags, laid out like the F register
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct FlagSet(pub u8);
+
+impl FlagSet {
+    pub const NONE: FlagSet = FlagSet(0);
+    pub const Z: FlagSet = FlagSet(0x80);
+    pub const N: FlagSet = FlagSet(0x40);
+    pub const H: FlagSet = FlagSet(0x20);
+    pub const C: FlagSet = FlagSet(0x10);
+    pub const ALL: FlagSet = FlagSet(0xf0);
+
+    pub const fn contains(self, other: FlagSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub const fn union(self, other: FlagSet) -> FlagSet {
+        FlagSet(self.0 | other.0)
+    }
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for FlagSet {
+    type Output = FlagSet;
+    fn bitor(self, rhs: FlagSet) -> FlagSet {
+        self.union(rhs)
+    }
+}
+
+impl std::fmt::Display for FlagSet {
+    /// `ZNHC`, with `-` for the missing ones
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (flag, name) in [
+            (FlagSet::Z, 'Z'),
+            (FlagSet::N, 'N'),
+            (FlagSet::H, 'H'),
+            (FlagSet::C, 'C'),
+        ] {
+            let c = if self.contains(flag) { name } else { '-' };
+            write!(f, "{c}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagEffect {
+    Preserved,
+    Set,
+    Reset,
+    Computed, // depends on the result
+}
+
+/// what an instruction does to each flag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlagEffects {
+    pub z: FlagEffect,
+    pub n: FlagEffect,
+    pub h: FlagEffect,
+    pub c: FlagEffect,
+}
+
+impl FlagEffects {
+    /// from the usual opcode table notation, e.g. `Z0H-`
+    pub const fn from_table(s: &[u8; 4]) -> Self {
+        const fn effect(c: u8) -> FlagEffect {
+            match c {
+                b'-' => FlagEffect::Preserved,
+                b'0' => FlagEffect::Reset,
+                b'1' => FlagEffect::Set,
+                _ => FlagEffect::Computed,
+            }
+        }
+        Self {
+            z: effect(s[0]),
+            n: effect(s[1]),
+            h: effect(s[2]),
+            c: effect(s[3]),
+        }
+    }
+
+    pub const NONE: FlagEffects = FlagEffects::from_table(b"----");
+
+    /// the flags whose value changes, one way or another
+    pub const fn written(self) -> FlagSet {
+        let mut set = 0;
+        let effects = [self.z, self.n, self.h, self.c];
+        let mut i = 0;
+        while i < 4 {
+            if !matches!(effects[i], FlagEffect::Preserved) {
+                set |= 0x80 >> i;
+            }
+            i += 1;
+        }
+        FlagSet(set)
+    }
+}
+
+impl std::fmt::Display for FlagEffects {
+    /// back to the `Z0H-` notation
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (effect, name) in
+            [(self.z, 'Z'), (self.n, 'N'), (self.h, 'H'), (self.c, 'C')]
+        {
+            let c = match effect {
+                FlagEffect::Preserved => '-',
+                FlagEffect::Reset => '0',
+                FlagEffect::Set => '1',
+                FlagEffect::Computed => name,
+            };
+            write!(f, "{c}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemAccess {
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstrInfo {
+    pub len: u16,
+    // in M-cycles. the same unless the instruction is conditional
+    pub cycles_taken: u8,
+    pub cycles_not_taken: u8,
+    pub reads: FlagSet,
+    pub flags: FlagEffects,
+    pub mem: MemAccess,
+}
+
+impl Instruction {
+    pub fn info(&self) -> InstrInfo {
+        use AluBlockOp::*;
+        use Instruction::*;
+        use MemAccess::{Read, ReadWrite, Write};
+        use PrefixOp::*;
+        use Reg::HL_;
+
+        let fx = FlagEffects::from_table;
+        // the common columns: reads no flags, changes none, no memory
+        let (none, keep, no_mem) =
+            (FlagSet::NONE, FlagEffects::NONE, MemAccess::None);
+        let (z, c) = (FlagSet::Z, FlagSet::C);
+        let cond = |cc: Condition| match cc {
+            Condition::NZ | Condition::Z => z,
+            Condition::NC | Condition::C => c,
+        };
+        // [hl] operands cost a memory access each
+        let hl = |r: Reg, extra: u8| if r == HL_ { extra } else { 0 };
+
+        // (taken, not taken), flags read, flags written, memory
+        let ((taken, not_taken), reads, flags, mem) = match *self {
+            NOP | DI | EI | HALT | STOP(_) => {
+                ((1, 1), none, keep, no_mem)
+            }
+            // the cpu locks up
+            Invalid(_) => ((1, 1), none, keep, no_mem),
+            LD_pa16_SP(_) => ((5, 5), none, keep, Write),
+            JR_r8(_) => ((3, 3), none, keep, no_mem),
+            JR_c_r8(cc, _) => ((3, 2), cond(cc), keep, no_mem),
+            LD_rr_d16(..) => ((3, 3), none, keep, no_mem),
+            ADD_HL_rr(_) => ((2, 2), none, fx(b"-0HC"), no_mem),
+            LD_prr_A(_) | LD_pHLi_A | LD_pHLd_A => {
+                ((2, 2), none, keep, Write)
+            }
+            LD_A_prr(_) | LD_A_pHLi | LD_A_pHLd => {
+                ((2, 2), none, keep, Read)
+            }
+            INC_rr(_) | DEC_rr(_) => ((2, 2), none, keep, no_mem),
+            INC_r(r) | DEC_r(r) => {
+                let n =
+                    if matches!(self, INC_r(_)) { b'0' } else { b'1' };
+                let mem = if r == HL_ { ReadWrite } else { no_mem };
+                let t = 1 + hl(r, 2);
+                ((t, t), none, fx(&[b'Z', n, b'H', b'-']), mem)
+            }
+            LD_r_d8(r, _) => {
+                let mem = if r == HL_ { Write } else { no_mem };
+                let t = 2 + hl(r, 1);
+                ((t, t), none, keep, mem)
+            }
+            RLCA | RRCA => ((1, 1), none, fx(b"000C"), no_mem),
+            RLA | RRA => ((1, 1), c, fx(b"000C"), no_mem),
+            DAA => (
+                (1, 1),
+                FlagSet::N | FlagSet::H | c,
+                fx(b"Z-0C"),
+                no_mem,
+            ),
+            CPL => ((1, 1), none, fx(b"-11-"), no_mem),
+            SCF => ((1, 1), none, fx(b"-001"), no_mem),
+            CCF => ((1, 1), c, fx(b"-00C"), no_mem),
+            LD_r_r(r1, r2) => {
+                let mem = match (r1, r2) {
+                    (HL_, _) => Write,
+                    (_, HL_) => Read,
+                    _ => no_mem,
+                };
+                let t = 1 + hl(r1, 1) + hl(r2, 1);
+                ((t, t), none, keep, mem)
+            }
+            Alu_A_RegOrNum(op, operand) => {
+                let (t, mem) = match operand {
+                    RegOrNum::Reg(HL_) => (2, Read),
+                    RegOrNum::Reg(_) => (1, no_mem),
+                    RegOrNum::Num(_) => (2, no_mem),
+                };
+                let reads = match op {
+                    ADC | SBC => c,
+                    _ => none,
+                };
+                let flags = match op {
+                    ADD | ADC => fx(b"Z0HC"),
+                    SUB | SBC | CP => fx(b"Z1HC"),
+                    AND => fx(b"Z010"),
+                    XOR | OR => fx(b"Z000"),
+                };
+                ((t, t), reads, flags, mem)
+            }
+            RET_c(cc) => ((5, 2), cond(cc), keep, Read),
+            LDH_pa8_A(_) => ((3, 3), none, keep, Write),
+            LDH_A_pa8(_) => ((3, 3), none, keep, Read),
+            ADD_SP_r8(_) => ((4, 4), none, fx(b"00HC"), no_mem),
+            LD_HL_SP_r8(_) => ((3, 3), none, fx(b"00HC"), no_mem),
+            POP_rr(RegPair::AF) => ((3, 3), none, fx(b"ZNHC"), Read),
+            POP_rr(_) => ((3, 3), none, keep, Read),
+            RET | RETI => ((4, 4), none, keep, Read),
+            JP_HL => ((1, 1), none, keep, no_mem),
+            LD_SP_HL => ((2, 2), none, keep, no_mem),
+            JP_c_a16(cc, _) => ((4, 3), cond(cc), keep, no_mem),
+            JP_a16(_) => ((4, 4), none, keep, no_mem),
+            LDH_pC_A => ((2, 2), none, keep, Write),
+            LDH_A_pC => ((2, 2), none, keep, Read),
+            LD_pa16_A(_) => ((4, 4), none, keep, Write),
+            LD_A_pa16(_) => ((4, 4), none, keep, Read),
+            CALL_c_a16(cc, _) => ((6, 3), cond(cc), keep, Write),
+            CALL_a16(_) => ((6, 6), none, keep, Write),
+            RST_vector(_) => ((4, 4), none, keep, Write),
+            PUSH_rr(RegPair::AF) => ((4, 4), FlagSet::ALL, keep, Write),
+            PUSH_rr(_) => ((4, 4), none, keep, Write),
+            Prefix(op, r) => {
+                let reads = match op {
+                    RL | RR => c,
+                    _ => none,
+                };
+                let flags = match op {
+                    RLC | RRC | RL | RR | SLA | SRA | SRL => {
+                        fx(b"Z00C")
+                    }
+                    SWAP => fx(b"Z000"),
+                    BIT(_) => fx(b"Z01-"),
+                    RES(_) | SET(_) => keep,
+                };
+                // bit only reads [hl], the rest write it back
+                let (t, mem) = match (op, r) {
+                    (BIT(_), HL_) => (3, Read),
+                    (_, HL_) => (4, ReadWrite),
+                    _ => (2, no_mem),
+                };
+                ((t, t), reads, flags, mem)
+            }
+        };
+
+        InstrInfo {
+            len: self.len(),
+            cycles_taken: taken,
+            cycles_not_taken: not_taken,
+            reads,
+            flags,
+            mem,
+        }
+    }
+}
+
+/// every opcode, the cb ones as 0xcbXX, with what it decodes to. the
+/// operands are zeroes.
+pub fn opcode_table() -> impl Iterator<Item = (u16, Instruction)> {
+    let base = (0..=0xffu16)
+        .filter(|&op| op != 0xcb)
+        .map(|op| (op, decode_instr([op as u8, 0, 0])));
+    let cb = (0..=0xffu8)
+        .map(|op| (0xcb00 | op as u16, decode_instr([0xcb, op, 0])));
+    base.chain(cb)
+}
+
+/// the whole table as csv, one line per opcode
+pub fn write_csv(out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "opcode,mnemonic,length,cycles_taken,cycles_not_taken,\
+         flags_read,flags_written,memory"
+    )?;
+    for (op, instr) in opcode_table() {
+        let info = instr.info();
+        let op = if op > 0xff {
+            format!("cb{:02x}", op & 0xff)
+        } else {
+            format!("{op:02x}")
+        };
+        let mem = match info.mem {
+            MemAccess::None => "",
+            MemAccess::Read => "r",
+            MemAccess::Write => "w",
+            MemAccess::ReadWrite => "rw",
+        };
+        writeln!(
+            out,
+            "{op},\"{instr}\",{},{},{},{},{},{mem}",
+            info.len,
+            info.cycles_taken,
+            info.cycles_not_taken,
+            info.reads,
+            info.flags,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(bytes: [u8; 3]) -> InstrInfo {
+        decode_instr(bytes).info()
+    }
+
+    fn cycles(bytes: [u8; 3]) -> (u8, u8) {
+        let info = info(bytes);
+        (info.cycles_taken, info.cycles_not_taken)
+    }
+
+    #[test]
+    fn conditional_cycles() {
+        assert_eq!(cycles([0x20, 0x05, 0]), (3, 2)); // jr nz
+        assert_eq!(cycles([0xc2, 0x00, 0x40]), (4, 3)); // jp nz
+        assert_eq!(cycles([0xc4, 0x00, 0x40]), (6, 3)); // call nz
+        assert_eq!(cycles([0xc0, 0, 0]), (5, 2)); // ret nz
+        assert_eq!(cycles([0x18, 0x05, 0]), (3, 3)); // jr
+        assert_eq!(cycles([0xcd, 0x00, 0x40]), (6, 6)); // call
+        assert_eq!(cycles([0xc9, 0, 0]), (4, 4)); // ret
+
+        // [hl] costs a cycle per access
+        assert_eq!(cycles([0x34, 0, 0]), (3, 3)); // inc [hl]
+        assert_eq!(cycles([0xcb, 0x46, 0]), (3, 3)); // bit 0, [hl]
+        assert_eq!(cycles([0xcb, 0xc6, 0]), (4, 4)); // set 0, [hl]
+        assert_eq!(info([0x20, 0, 0]).reads, FlagSet::Z);
+        assert_eq!(info([0xd8, 0, 0]).reads, FlagSet::C);
+    }
+
+    #[test]
+    fn flag_effects() {
+        let daa = info([0x27, 0, 0]);
+        assert_eq!(daa.flags, FlagEffects::from_table(b"Z-0C"));
+        assert_eq!(daa.reads, FlagSet::N | FlagSet::H | FlagSet::C);
+        assert_eq!(daa.flags.written(), FlagSet(0xb0));
+
+        let add_sp = info([0xe8, 0xfe, 0]);
+        assert_eq!(add_sp.flags.to_string(), "00HC");
+        assert_eq!(add_sp.flags.written(), FlagSet::ALL);
+
+        let cpl = info([0x2f, 0, 0]);
+        assert_eq!(cpl.flags.n, FlagEffect::Set);
+        assert_eq!(cpl.flags.h, FlagEffect::Set);
+        assert_eq!(cpl.flags.written(), FlagSet::N | FlagSet::H);
+        assert_eq!(cpl.reads, FlagSet::NONE);
+
+        assert_eq!(info([0xf1, 0, 0]).flags.to_string(), "ZNHC");
+        assert_eq!(info([0xf5, 0, 0]).reads, FlagSet::ALL);
+        assert_eq!(info([0xcb, 0x46, 0]).mem, MemAccess::Read);
+        assert_eq!(info([0x34, 0, 0]).mem, MemAccess::ReadWrite);
+    }
+
+    #[test]
+    fn csv() {
+        let mut out = vec![];
+        write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "opcode,mnemonic,length,cycles_taken,cycles_not_taken,\
+             flags_read,flags_written,memory"
+        );
+        // 0xcb itself is only a prefix
+        assert_eq!(lines.len(), 1 + 255 + 256);
+        assert!(lines.contains(&"00,\"nop\",1,1,1,----,----,"));
+        assert!(lines.contains(&"27,\"daa\",1,1,1,-NHC,Z-0C,"));
+        assert!(lines.contains(&"20,\"jr nz, @+2\",2,3,2,Z---,----,"));
+        assert!(
+            lines.contains(&"cb46,\"bit 0, [hl]\",2,3,3,----,Z01-,r")
+        );
+    }
+}
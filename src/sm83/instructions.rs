@@ -22,7 +22,22 @@ impl Condition {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl std::str::FromStr for Condition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        use Condition::*;
+        match s.to_ascii_lowercase().as_str() {
+            "nz" => Ok(NZ),
+            "z" => Ok(Z),
+            "nc" => Ok(NC),
+            "c" => Ok(C),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PrefixOp {
     RLC,
     RRC,
@@ -37,7 +52,7 @@ pub enum PrefixOp {
     SET(u8),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AluBlockOp {
     // for all the 02xx instructions and 036x
     ADD = 0o00,
@@ -50,7 +65,7 @@ pub enum AluBlockOp {
     CP = 0o70,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RegOrNum {
     // useful for the 02xx instructions
     Reg(Reg),
@@ -58,7 +73,7 @@ pub enum RegOrNum {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     // I use octal because it alligns better for gb instructions
     // mnemonic                         // opcode
@@ -108,7 +123,7 @@ pub enum Instruction {
     LD_A_pa16(u16),                       // 372
     JP_a16(u16),                          // 303
     Prefix(PrefixOp, Reg),                // 0xcb
-    Invalid,                              // all invalid ops
+    Invalid(u8),                          // all invalid ops
     DI,                                   // 363
     EI,                                   // 373
     CALL_c_a16(Condition, u16),           // 304, 314, 324, 334
@@ -118,12 +133,71 @@ pub enum Instruction {
                                           // u8 is in [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38]
 }
 
+/// where control goes after an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Next,                       // falls through, including halt/stop
+    Jump(u16),                  // jp, jr
+    Branch(Condition, u16),     // conditional jp, jr; may fall through
+    Call(u16),                  // call, rst; comes back to the next instr
+    CondCall(Condition, u16),   // conditional call
+    Ret,                        // ret, reti
+    CondRet(Condition),         // conditional ret; may fall through
+    Indirect,                   // jp hl
+    Lockup,                     // invalid opcodes hang the cpu
+}
+
+impl Flow {
+    /// whether this ends a basic block
+    pub fn ends_block(self) -> bool {
+        self != Flow::Next
+    }
+
+    /// whether execution may continue at the next instruction
+    pub fn falls_through(self) -> bool {
+        use Flow::*;
+        match self {
+            Next | Branch(..) | Call(_) | CondCall(..) | CondRet(_) => {
+                true
+            }
+            Jump(_) | Ret | Indirect | Lockup => false,
+        }
+    }
+}
+
 impl Instruction {
     pub fn len(&self) -> u16 {
         use Instruction::*;
         match self {
-            LD_pa16_SP(_) => 3,
-            _ => unimplemented!(),
+            LD_pa16_SP(_) | LD_rr_d16(..) | JP_c_a16(..) | LD_pa16_A(_)
+            | LD_A_pa16(_) | JP_a16(_) | CALL_c_a16(..) | CALL_a16(_) => 3,
+
+            STOP(_) | JR_r8(_) | JR_c_r8(..) | LD_r_d8(..) | LDH_pa8_A(_)
+            | ADD_SP_r8(_) | LDH_A_pa8(_) | LD_HL_SP_r8(_) | Prefix(..)
+            | Alu_A_RegOrNum(_, RegOrNum::Num(_)) => 2,
+
+            _ => 1,
+        }
+    }
+
+    /// the control flow of this instruction, when it is at `pc`
+    pub fn flow(&self, pc: u16) -> Flow {
+        use Instruction::*;
+        // jr is relative to the next instruction
+        let rel = |r8: i8| pc.wrapping_add(2).wrapping_add_signed(r8 as i16);
+        match *self {
+            JP_a16(a16) => Flow::Jump(a16),
+            JR_r8(r8) => Flow::Jump(rel(r8)),
+            JP_c_a16(c, a16) => Flow::Branch(c, a16),
+            JR_c_r8(c, r8) => Flow::Branch(c, rel(r8)),
+            CALL_a16(a16) => Flow::Call(a16),
+            RST_vector(vec) => Flow::Call(vec as u16),
+            CALL_c_a16(c, a16) => Flow::CondCall(c, a16),
+            RET | RETI => Flow::Ret,
+            RET_c(c) => Flow::CondRet(c),
+            JP_HL => Flow::Indirect,
+            Invalid(_) => Flow::Lockup,
+            _ => Flow::Next,
         }
     }
 }
@@ -89,6 +89,42 @@ impl Reg {
     }
 }
 
+impl std::str::FromStr for RegPair {
+    type Err = ();
+
+    /// `bc`, `de`, `hl`, `sp`, `af`, in any case
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "bc" => Ok(BC),
+            "de" => Ok(DE),
+            "hl" => Ok(HL),
+            "sp" => Ok(SP),
+            "af" => Ok(AF),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::str::FromStr for Reg {
+    type Err = ();
+
+    /// the operand names, so `[hl]` is `HL_` and `f` is not a register
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let s: String = s.split_whitespace().collect();
+        match s.to_ascii_lowercase().as_str() {
+            "b" => Ok(B),
+            "c" => Ok(C),
+            "d" => Ok(D),
+            "e" => Ok(E),
+            "h" => Ok(H),
+            "l" => Ok(L),
+            "[hl]" => Ok(HL_),
+            "a" => Ok(A),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum HalfPair {
     None,
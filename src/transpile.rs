@@ -7,10 +7,17 @@ mod translate_instruction;
 use translate_instruction::*;
 
 mod context;
+mod flags;
+mod helpers;
 pub use context::Context;
+pub(crate) use context::{CodeBlock, CompileError};
 
 use iced_x86::{code_asm::*, IcedError};
 
+pub mod abi;
+pub mod elf;
+pub mod gas;
+pub mod header;
 pub mod mapping;
 
 ////////////////////// BS
@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::ffi::c_void;
+
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+
+use crate::analysis::Symbols;
+use crate::cartridge::BankedAddr;
+
+use super::context::{Emitter, Label, Sm83Label};
+use super::flags::{self, Flags};
+
+// the sm83_func abi: the registers come in as arguments, the way the
+// readme describes, and the whole state goes back out as a State in
+// rax:rdx. every entry point shares one prologue and one epilogue.
+//
+// calls are host calls too, so rets can be host rets: below every host
+// return address is the sm83 one it stands for, and a ret that pops
+// something else goes through the dispatcher instead. rbp keeps the
+// frame, since not every call comes back.
+//
+// code compiled with trap_io or switch_banks takes a 7th argument, the
+// BusHooks, which ends up at [rbp + BUS_ARG].
+
+/// what compiled code returns
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub exit: u16, // an ExitReason
+    pub ime: u16,
+}
+
+/// ime goes in at bit 16 of af. bus is only used with trap_io or
+/// switch_banks.
+pub type Sm83Func = unsafe extern "sysv64" fn(
+    af: u32,
+    bc: u32,
+    de: u32,
+    hl: u32,
+    sp: u32,
+    mem: *mut u8,
+    bus: *mut BusHooks,
+) -> State;
+
+/// what compiled code calls for what it traps: read before it reads
+/// 0xff00..0xff80, write after it writes there, or to the rom with
+/// switch_banks. both get the hooks, mem and the addr: read may change
+/// mem[addr] first, write finds the new value there.
+#[repr(C)]
+pub struct BusHooks {
+    pub data: *mut c_void,
+    pub read: unsafe extern "sysv64" fn(*mut BusHooks, *mut u8, u32),
+    pub write: unsafe extern "sysv64" fn(*mut BusHooks, *mut u8, u32),
+    /// the bank in 0x4000..0x8000, which write keeps up to date
+    pub rom_bank: u16,
+    /// set by write when another bank is mapped at 0x0000..0x4000.
+    /// compiled code clears it as it leaves for that.
+    pub rom0_switched: bool,
+}
+
+/// where the BusHooks pointer is, from rbp: above the saved rbx and the
+/// return address
+pub const BUS_ARG: i32 = 24;
+
+/// the i/o registers
+pub const IO: std::ops::Range<u16> = 0xff00..0xff80;
+
+/// why compiled code gave control back
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    // to pc, which is not compiled
+    Jump = 0,
+    Halt = 1,
+    Stop = 2,
+    // an invalid opcode at pc
+    Lockup = 3,
+    // to pc, by jp hl or a ret the dispatcher found nothing compiled for
+    Indirect = 4,
+    // to pc, after a store into compiled code (only with track_writes),
+    // or one that switched the bank at 0x0000 (with switch_banks)
+    CodeWrite = 5,
+}
+
+impl ExitReason {
+    pub fn from_u16(exit: u16) -> Option<Self> {
+        use ExitReason::*;
+        [Jump, Halt, Stop, Lockup, Indirect, CodeWrite]
+            .into_iter()
+            .find(|&e| e as u16 == exit)
+    }
+}
+
+pub const ENTER: &str = ".sm83_enter";
+pub const EXIT: &str = ".sm83_exit";
+
+/// the symbol an entry point is callable as
+pub fn entry_name(addr: BankedAddr) -> String {
+    // the label without its dot, which keeps it local
+    Sm83Label::new(addr).name()[1..].into()
+}
+
+/// a symbol for each function: its name from `syms` if that makes a c
+/// identifier nobody else has, else its entry_name
+pub fn function_names(
+    functions: impl IntoIterator<Item = BankedAddr>,
+    syms: Option<&Symbols>,
+) -> Vec<(BankedAddr, String)> {
+    let mut taken = HashSet::new();
+    functions
+        .into_iter()
+        .map(|addr| {
+            let name = syms
+                .and_then(|syms| syms.get(addr))
+                .filter(|name| is_c_identifier(name))
+                .filter(|name| taken.insert(name.to_string()))
+                .map_or_else(|| entry_name(addr), str::to_string);
+            (addr, name)
+        })
+        .collect()
+}
+
+fn is_c_identifier(name: &str) -> bool {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(word)
+}
+
+/// leaves compiled code at `pc`
+pub(crate) fn exit(
+    e: &mut Emitter,
+    pc: u16,
+    reason: ExitReason,
+) -> Result<(), IcedError> {
+    e.asm.mov(r8d, pc as u32 | (reason as u32) << 16)?;
+    e.asm.jmp(e.exit)
+}
+
+/// an sm83_func that starts at `addr`
+pub(crate) fn emit_entry(
+    e: &mut Emitter,
+    addr: BankedAddr,
+) -> Result<(), IcedError> {
+    let start = e.label(addr);
+    e.lea_label(r11, start)?;
+    e.asm.jmp(e.enter)
+}
+
+/// where ime is in r9, above the saved flags
+pub const IME: u32 = 0x100;
+
+/// unpacks the arguments into the mapped registers and jumps to r11
+pub(crate) fn emit_prologue(e: &mut Emitter) -> Result<(), IcedError> {
+    e.place(e.enter)?;
+    let a = &mut e.asm;
+    a.push(rbx)?;
+    a.push(rbp)?;
+    a.mov(rbp, rsp)?;
+    // a frame no sm83 address matches, for rets with nowhere to go
+    a.push(-1)?;
+    a.push(-1)?;
+    // edi = af | ime << 16, esi = bc, edx = de, ecx = hl, r8d = sp,
+    // r9 = mem
+    a.movzx(ebx, si)?;
+    a.mov(r10d, edi)?;
+    a.movzx(edi, r8w)?;
+    a.mov(r8d, edx)?;
+    a.movzx(edx, cx)?;
+    a.movzx(ecx, r8w)?;
+    a.mov(e.mem, r9)?;
+    // a, ime, then f into its places, which takes r11
+    a.movzx(eax, r10w)?;
+    a.shr(eax, 8)?;
+    a.mov(r9d, r10d)?;
+    a.shr(r9d, 8)?;
+    a.and(r9d, IME)?;
+    a.mov(r8, r11)?;
+    flags::unpack(e)?;
+    e.asm.jmp(r8)
+}
+
+/// packs the registers into a State and leaves the frame. r8d is pc |
+/// exit reason << 16.
+pub(crate) fn emit_epilogue(e: &mut Emitter) -> Result<(), IcedError> {
+    e.place(e.exit)?;
+    // f, from wherever the flags are between blocks
+    flags::pack(e, Flags::CANONICAL)?;
+    let a = &mut e.asm;
+    // rax = af | bc << 16 | de << 32 | hl << 48
+    a.movzx(r11d, al)?;
+    a.shl(r11d, 8)?;
+    a.or(r11d, r10d)?;
+    a.movzx(ebx, bx)?;
+    a.shl(rbx, 16)?;
+    a.or(r11, rbx)?;
+    a.movzx(ecx, cx)?;
+    a.shl(rcx, 32)?;
+    a.or(r11, rcx)?;
+    a.movzx(edx, dx)?;
+    a.shl(rdx, 48)?;
+    a.or(r11, rdx)?;
+    a.mov(rax, r11)?;
+    // rdx = sp | pc << 16 | exit << 32 | ime << 48
+    a.movzx(edx, di)?;
+    a.shl(r8, 16)?;
+    a.or(rdx, r8)?;
+    a.mov(r10d, r9d)?;
+    a.and(r10d, IME)?;
+    a.shl(r10, 48 - 8)?;
+    a.or(rdx, r10)?;
+    a.mov(rsp, rbp)?;
+    a.pop(rbp)?;
+    a.pop(rbx)?;
+    a.ret()
+}
@@ -1,220 +1,695 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
 
-use crate::sm83;
-// use iced_x86::Instruction;
+use iced_x86::code_asm::{rsi, AsmRegister64, CodeAssembler};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, IcedError, Instruction,
+    InstructionBlock, MemoryOperand, Register,
+};
 
-type Amd64Instr = super::translate_instruction::Amd64Instr;
+use crate::cartridge::{BankedAddr, Cartridge, CodeSource};
+use crate::sm83::{self, info::FlagSet};
+
+use super::abi::{self, ExitReason};
+use super::flags::{self, Flags};
+use super::helpers;
 
 pub struct Context {
-    pub mem_base_reg: String, //iced_x86::code_asm::AsmRegister64,
-    // sm83 addr -> code block index
-    label_map: HashMap<u16, Option<CodeBlock>>,
+    // not rex, so it mixes with the high byte registers
+    pub mem_base_reg: AsmRegister64,
+    // h and n right after every instruction, not only where something
+    // reads them
+    pub accurate_flags: bool,
+    // stores check whether they hit compiled code, and leave with a
+    // CodeWrite exit if so. pushes do not.
+    pub track_writes: bool,
+    // reads and writes of the i/o registers call the BusHooks, which
+    // come in as a 7th argument
+    pub trap_io: bool,
+    // stores to the rom call the BusHooks too, as mbc writes, instead of
+    // changing it, and so do stores to cart ram, which may be off. jumps
+    // and calls from elsewhere into 0x4000..0x8000 go to whatever bank
+    // is mapped right then.
+    pub switch_banks: bool,
+    // the compiled block at each sm83 addr. code jumps to it through
+    // the emitter's AsmLabel for that addr, a fake ip the encoder swaps
+    // for the real one.
+    label_map: HashMap<BankedAddr, Option<CodeBlock>>,
+    emitter: Emitter,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            mem_base_reg: rsi,
+            accurate_flags: false,
+            track_writes: false,
+            trap_io: false,
+            switch_banks: false,
+            label_map: HashMap::new(),
+            emitter: Emitter::new(rsi),
+        }
+    }
+}
+
+/// one piece of machine code, at the ip it was encoded for
+pub(crate) struct Assembled {
+    pub code: Vec<u8>,
+    // offsets of the entry stubs
+    pub entries: HashMap<BankedAddr, usize>,
+    // offsets of the compiled blocks
+    pub blocks: BTreeMap<BankedAddr, usize>,
+}
+
+impl Context {
+    pub(crate) fn insert(&mut self, block: CodeBlock) {
+        self.label_map
+            .insert(block.source.start.addr(), Some(block));
+    }
+
+    pub(crate) fn label_names(&self) -> HashMap<AsmLabel, String> {
+        self.emitter.label_names()
+    }
+
+    pub(crate) fn is_compiled(&self, pc: BankedAddr) -> bool {
+        matches!(self.label_map.get(&pc), Some(Some(_)))
+    }
+
+    /// where every compiled block starts, sorted
+    pub(crate) fn compiled(&self) -> Vec<BankedAddr> {
+        self.blocks()
+            .into_iter()
+            .map(|source| source.start)
+            .collect()
+    }
+
+    /// the sm83 code of every compiled block, sorted
+    pub(crate) fn blocks(&self) -> Vec<Range<BankedAddr>> {
+        let mut blocks: Vec<Range<BankedAddr>> = self
+            .label_map
+            .values()
+            .flatten()
+            .map(CodeBlock::source)
+            .collect();
+        blocks.sort_by_key(|source| source.start);
+        blocks
+    }
+
+    /// the blocks that were compiled from other bytes than `mem` has now.
+    /// switchable rom blocks only count for the `mapped` bank.
+    pub(crate) fn stale(
+        &self,
+        mem: &[u8; 0x10000],
+        mapped: u16,
+    ) -> Vec<BankedAddr> {
+        self.label_map
+            .values()
+            .flatten()
+            .filter(|block| {
+                let start = block.source.start.addr();
+                let other_bank = start.to(0x4000).bank != mapped;
+                !((0x4000..0x8000).contains(&start.addr) && other_bank)
+                    && block.bytes.iter().enumerate().any(|(i, &byte)| {
+                        mem[start.addr.wrapping_add(i as u16) as usize] != byte
+                    })
+            })
+            .map(|block| block.source.start.addr())
+            .collect()
+    }
+
+    /// forgets the block at `start`, so jumps there leave to the runtime
+    /// until it is compiled again
+    pub(crate) fn invalidate(&mut self, start: BankedAddr) {
+        self.label_map.remove(&start);
+    }
+
+    fn emitter(&mut self) -> &mut Emitter {
+        self.emitter.mem = self.mem_base_reg;
+        self.emitter.accurate_flags = self.accurate_flags;
+        self.emitter.track_writes = self.track_writes;
+        self.emitter.trap_io = self.trap_io;
+        self.emitter.switch_banks = self.switch_banks;
+        &mut self.emitter
+    }
+
+    /// compiles the block at `pc`, unless that is done already
+    pub(crate) fn compile(
+        &mut self,
+        rom: &Cartridge,
+        pc: BankedAddr,
+    ) -> Result<(), CompileError> {
+        // anything above the rom can be written to
+        if pc.addr >= 0x8000 {
+            return Err(CompileError::SelfModifyingCode);
+        }
+        if !self.is_compiled(pc) {
+            let block = transpile_block_at(rom, pc, self)?;
+            self.insert(block);
+        }
+        Ok(())
+    }
+
+    /// compiles the block at `pc` in ram from what `mem` has there now.
+    /// it checks on every entry that it still does.
+    pub(crate) fn compile_ram(
+        &mut self,
+        mem: &[u8; 0x10000],
+        pc: BankedAddr,
+    ) -> Result<(), CompileError> {
+        debug_assert!(pc.addr >= 0x8000);
+        if !self.is_compiled(pc) {
+            let block = transpile_block_at(mem, pc, self)?;
+            self.insert(block);
+        }
+        Ok(())
+    }
+
+    /// compiles `instrs` as one block at `start`, wherever they came from
+    pub(crate) fn compile_instrs(
+        &mut self,
+        start: BankedAddr,
+        instrs: &[sm83::Instruction],
+    ) -> Result<(), CompileError> {
+        let e = self.emitter();
+        let mut block = CodeBlock::new(e, start)?;
+        block.push_sm83_instrs(e, instrs)?;
+        block.finish(e)?;
+        self.insert(block);
+        Ok(())
+    }
+
+    /// everything compiled so far, callable from each of `entries`, but
+    /// not encoded yet
+    pub(crate) fn unit(
+        &mut self,
+        entries: &[BankedAddr],
+    ) -> Result<Unit, CompileError> {
+        let e = self.emitter();
+        // where each entry stub starts, as an instruction index
+        let mut stubs = vec![];
+        for &entry in entries {
+            stubs.push((entry, e.asm.instructions().len()));
+            abi::emit_entry(e, entry)?;
+        }
+        let enter = e.asm.instructions().len();
+        abi::emit_prologue(e)?;
+        let exit = e.asm.instructions().len();
+        abi::emit_epilogue(e)?;
+
+        let mut blocks: Vec<&CodeBlock> =
+            self.label_map.values().flatten().collect();
+        blocks.sort_by_key(|block| block.source.start.addr());
+        let sources: Vec<Range<BankedAddr>> =
+            blocks.iter().map(|b| b.source()).collect();
+
+        // whatever is jumped to but not compiled leaves to the runtime
+        let mut targets: Vec<BankedAddr> = blocks
+            .iter()
+            .flat_map(|block| block.targets())
+            .chain(entries.iter().copied())
+            .filter(|&addr| !self.is_compiled(addr))
+            .collect();
+        targets.sort();
+        targets.dedup();
+        let e = &mut self.emitter;
+        for target in targets {
+            let label = e.label(target);
+            e.place(label)?;
+            abi::exit(e, target.addr, ExitReason::Jump)?;
+        }
+
+        // runtime helpers have to come from somewhere
+        let mut called: Vec<(&'static str, AsmLabel)> = e
+            .helpers
+            .iter()
+            .map(|(&name, &label)| (name, label))
+            .collect();
+        called.sort();
+        let mut missing = vec![];
+        for (name, label) in called {
+            if !helpers::emit(e, name, label, &sources)? {
+                missing.push(name);
+            }
+        }
+        if !missing.is_empty() {
+            // the half done unit goes
+            let _ = e.take();
+            return Err(CompileError::Undefined(missing));
+        }
+
+        Ok(Unit {
+            common: e.take(),
+            entries: stubs,
+            enter,
+            exit,
+            blocks: blocks
+                .iter()
+                .map(|b| (b.source.start.addr(), b.out_instrs.clone()))
+                .collect(),
+        })
+    }
+
+    /// everything compiled so far as machine code for `ip`, callable
+    /// from each of `entries`
+    pub(crate) fn assemble(
+        &mut self,
+        entries: &[BankedAddr],
+        ip: u64,
+    ) -> Result<Assembled, CompileError> {
+        let unit = self.unit(entries)?;
+        let mut instrs = unit.common;
+        let mut starts = vec![];
+        for (addr, block) in unit.blocks {
+            starts.push((addr, instrs.len()));
+            instrs.extend(block);
+        }
+
+        let block = InstructionBlock::new(&instrs, ip);
+        let res = BlockEncoder::encode(
+            64,
+            block,
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )?;
+        let offset = |index: usize| res.new_instruction_offsets[index] as usize;
+        Ok(Assembled {
+            entries: unit
+                .entries
+                .into_iter()
+                .map(|(a, i)| (a, offset(i)))
+                .collect(),
+            blocks: starts.into_iter().map(|(a, i)| (a, offset(i))).collect(),
+            code: res.code_buffer,
+        })
+    }
+}
+
+/// a whole program before encoding
+pub(crate) struct Unit {
+    // the entry stubs, prologue, epilogue and exits to the runtime
+    pub common: Vec<Instruction>,
+    // where each entry stub starts in common
+    pub entries: Vec<(BankedAddr, usize)>,
+    // where the prologue and the epilogue start in common
+    pub enter: usize,
+    pub exit: usize,
+    // the compiled blocks, in address order
+    pub blocks: Vec<(BankedAddr, Vec<Instruction>)>,
+}
+
+/// a label is the ip of the instruction it is on, until the encoder
+/// gives that one a real address. jumps carry it as their target.
+pub(crate) type AsmLabel = u64;
+
+/// where instructions are emitted, with the labels they jump to. a label
+/// belongs to an sm83 addr for good, so blocks can jump to each other
+/// before either one is placed.
+pub(crate) struct Emitter {
+    pub asm: CodeAssembler,
+    pub mem: AsmRegister64,
+    pub accurate_flags: bool,
+    pub track_writes: bool,
+    pub trap_io: bool,
+    pub switch_banks: bool,
+    // the assembler's own labels start over with every
+    // take_instructions, these do not
+    next_label: AsmLabel,
+    // labels placed since the last take, by instruction index
+    placed: Vec<(usize, AsmLabel)>,
+    labels: HashMap<BankedAddr, AsmLabel>,
+    // runtime helpers that are called but nowhere to be found
+    helpers: HashMap<&'static str, AsmLabel>,
+    // where the flags are at the current instruction
+    pub flags: Flags,
+    pub enter: AsmLabel,
+    pub exit: AsmLabel,
 }
 
-struct Amd64Patch {
-    // indexes to patch instructions
-    index: usize,
-    sm83_addr: u16,
+impl Emitter {
+    fn new(mem: AsmRegister64) -> Self {
+        Self {
+            asm: CodeAssembler::new(64).unwrap(),
+            mem,
+            accurate_flags: false,
+            track_writes: false,
+            trap_io: false,
+            switch_banks: false,
+            // 0 is no label at all
+            next_label: 3,
+            placed: vec![],
+            labels: HashMap::new(),
+            helpers: HashMap::new(),
+            flags: Flags::CANONICAL,
+            enter: 1,
+            exit: 2,
+        }
+    }
+
+    pub fn new_label(&mut self) -> AsmLabel {
+        self.next_label += 1;
+        self.next_label
+    }
+
+    /// the label of the code for `addr`
+    pub fn label(&mut self, addr: BankedAddr) -> AsmLabel {
+        match self.labels.get(&addr) {
+            Some(&label) => label,
+            None => {
+                let label = self.new_label();
+                self.labels.insert(addr, label);
+                label
+            }
+        }
+    }
+
+    pub fn helper(&mut self, name: &'static str) -> AsmLabel {
+        match self.helpers.get(name) {
+            Some(&label) => label,
+            None => {
+                let label = self.new_label();
+                self.helpers.insert(name, label);
+                label
+            }
+        }
+    }
+
+    /// puts `label` at the next instruction. every piece of code that is
+    /// encoded on its own can place it again.
+    pub fn place(&mut self, label: AsmLabel) -> Result<(), IcedError> {
+        self.placed.push((self.asm.instructions().len(), label));
+        self.asm.zero_bytes()
+    }
+
+    /// `lea reg, [rip + label]`, which the assembler only has for its
+    /// own labels
+    pub fn lea_label(
+        &mut self,
+        reg: AsmRegister64,
+        label: AsmLabel,
+    ) -> Result<(), IcedError> {
+        let mem = MemoryOperand::with_base_displ(Register::RIP, label as i64);
+        let lea =
+            Instruction::with2(Code::Lea_r64_m, Register::from(reg), mem)?;
+        self.asm.add_instruction(lea)
+    }
+
+    /// the names labels go by in a .s. the rest are local numbers.
+    pub fn label_names(&self) -> HashMap<AsmLabel, String> {
+        let sm83 = self
+            .labels
+            .iter()
+            .map(|(&addr, &label)| (label, Sm83Label::new(addr).name()));
+        let helpers = self
+            .helpers
+            .iter()
+            .map(|(&name, &label)| (label, format!(".sm83_{name}")));
+        let abi = [
+            (self.enter, abi::ENTER.to_string()),
+            (self.exit, abi::EXIT.to_string()),
+        ];
+        sm83.chain(helpers).chain(abi).collect()
+    }
+
+    /// everything emitted since the last take, with the labels on it
+    pub fn take(&mut self) -> Vec<Instruction> {
+        let mut instrs = self.asm.take_instructions();
+        for (index, label) in self.placed.drain(..) {
+            instrs[index].set_ip(label);
+        }
+        instrs
+    }
 }
 
 pub(crate) struct CodeBlock {
     source: std::ops::Range<Sm83Label>, // source sm83 instrs
-    out_instrs: Vec<Amd64Instr>,        // resulting amd64 instrs
-    patches: Vec<Amd64Patch>,           // things to patch
+    bytes: Vec<u8>,                     // and their encoding
+    out_instrs: Vec<Instruction>,       // resulting amd64 instrs
+    targets: Vec<BankedAddr>,           // where the jumps go
 
-    mem_reg: String,
+    // whether the end continues at source.end
+    falls_through: bool,
 }
 
 impl CodeBlock {
-    fn push_sm83_instr(&mut self, sm83_instr: crate::Instruction) {
+    /// pushes `sm83_instrs` up to the first one the block can not go on
+    /// after
+    pub(crate) fn push_sm83_instrs(
+        &mut self,
+        e: &mut Emitter,
+        sm83_instrs: &[crate::Instruction],
+    ) -> Result<(), IcedError> {
+        let live = flags::live_after(self.source.end.addr().addr, sm83_instrs);
+        for (&instr, live) in sm83_instrs.iter().zip(live) {
+            if !self.push_sm83_instr(e, instr, live)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// returns whether the block may go on after `sm83_instr`. `live`
+    /// are the flags read after it.
+    pub(crate) fn push_sm83_instr(
+        &mut self,
+        e: &mut Emitter,
+        sm83_instr: crate::Instruction,
+        live: FlagSet,
+    ) -> Result<bool, IcedError> {
         use super::translate_instruction::{
-            transpile_instr_preserve_c_flag, TranspileInstrRes::*,
+            transpile_instr_preserve_c_flag, TranspileInstrRes,
         };
 
-        let (mut x86_instrs, res) = transpile_instr_preserve_c_flag(
-            sm83_instr,
-            &self.mem_reg,
-            self.source.end.addr(),
-        );
+        let pc = self.source.end.addr();
+        let res = transpile_instr_preserve_c_flag(e, sm83_instr, pc, live)?;
 
         self.source.end += sm83_instr.len();
+        self.bytes.extend(sm83_instr.encode());
+        let flow = sm83_instr.flow(pc.addr);
+        // a call's fall through is where the host ret comes back to
+        self.falls_through = flow.falls_through();
 
-        // we are patching the instr in the context of the whole
-        // block...
         match res {
-            Branch {
-                cond: _,
-                dest,
-                to_patch,
-            } => self.patches.push(Amd64Patch {
-                index: to_patch + self.out_instrs.len(),
-                sm83_addr: dest,
-            }),
-
-            Jump { dest, to_patch } => self.patches.push(Amd64Patch {
-                index: to_patch + self.out_instrs.len(),
-                sm83_addr: dest,
-            }),
-            Lockup { pc: _ } => todo!(),
-            Ok => {}
+            TranspileInstrRes::Branch { cond: _, dest }
+            | TranspileInstrRes::Jump { dest } => self.targets.push(dest),
+            TranspileInstrRes::Lockup { pc } => {
+                // halt and stop go on after themselves, once something
+                // wakes the cpu up. invalid opcodes do not go anywhere.
+                let (reason, pc) = match sm83_instr {
+                    sm83::Instruction::HALT => {
+                        (ExitReason::Halt, self.source.end.addr())
+                    }
+                    sm83::Instruction::STOP(_) => {
+                        (ExitReason::Stop, self.source.end.addr())
+                    }
+                    _ => (ExitReason::Lockup, pc),
+                };
+                flags::sync(e)?;
+                abi::exit(e, pc.addr, reason)?;
+                self.falls_through = false;
+                return Ok(false);
+            }
+            TranspileInstrRes::Ok => {}
         };
-        //        self.patches.push(Amd64Patch {});
-        self.out_instrs.append(&mut x86_instrs);
+        Ok(!flow.ends_block())
     }
 
-    fn new(mem_reg: &str, start: u16) -> Self {
-        Self {
-            source: Sm83Label::new(start as u16)
-                ..Sm83Label::new(start as u16),
+    /// starts a block at `start`. `e` has to be empty.
+    pub(crate) fn new(
+        e: &mut Emitter,
+        start: BankedAddr,
+    ) -> Result<Self, IcedError> {
+        debug_assert!(e.asm.instructions().is_empty());
+        let label = e.label(start);
+        e.place(label)?;
+        e.flags = Flags::CANONICAL;
+        Ok(Self {
+            source: Sm83Label::new(start)..Sm83Label::new(start),
+            bytes: vec![],
             out_instrs: vec![],
-            patches: vec![],
-            mem_reg: mem_reg.into(),
+            targets: vec![],
+            falls_through: true,
+        })
+    }
+
+    pub(crate) fn source(&self) -> Range<BankedAddr> {
+        self.source.start.addr()..self.source.end.addr()
+    }
+
+    /// leaves with a CodeWrite exit to the start if `mem` does not have
+    /// `bytes` there anymore, for code in ram. `e` is right after new.
+    fn check_source(
+        &self,
+        e: &mut Emitter,
+        bytes: &[u8],
+    ) -> Result<(), IcedError> {
+        use iced_x86::code_asm::*;
+        let start = self.source.start.addr().addr;
+        let stale = e.new_label();
+        let body = e.new_label();
+        let mem = e.mem;
+        let a = &mut e.asm;
+        a.lahf()?;
+        // as few compares as fit, without wrapping around mem
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = start.wrapping_add(offset as u16) as usize;
+            let size = [8, 4, 2, 1]
+                .into_iter()
+                .find(|&n| offset + n <= bytes.len() && addr + n <= 0x10000)
+                .unwrap();
+            let mut chunk = [0; 8];
+            chunk[..size].copy_from_slice(&bytes[offset..offset + size]);
+            let value = u64::from_le_bytes(chunk);
+            let at = mem + addr as i32;
+            match size {
+                8 => {
+                    a.mov(r10, value)?;
+                    a.cmp(qword_ptr(at), r10)?;
+                }
+                4 => a.cmp(dword_ptr(at), value as u32)?,
+                2 => a.cmp(word_ptr(at), value as u32)?,
+                _ => a.cmp(byte_ptr(at), value as u32)?,
+            }
+            a.jne(stale)?;
+            offset += size;
+        }
+        a.sahf()?;
+        a.jmp(body)?;
+        e.place(stale)?;
+        e.asm.sahf()?;
+        abi::exit(e, start, ExitReason::CodeWrite)?;
+        e.place(body)
+    }
+
+    /// takes what was emitted for the block out of `e`
+    pub(crate) fn finish(&mut self, e: &mut Emitter) -> Result<(), IcedError> {
+        if self.falls_through {
+            flags::sync(e)?;
+            let end = e.label(self.source.end.addr());
+            e.asm.jmp(end)?;
         }
+        self.out_instrs = e.take();
+        Ok(())
+    }
+
+    /// the sm83 addrs this block may continue at
+    pub(crate) fn targets(&self) -> impl Iterator<Item = BankedAddr> + '_ {
+        let end = self.falls_through.then(|| self.source.end.addr());
+        self.targets.iter().copied().chain(end)
     }
 }
 
+#[derive(Debug)]
 pub(crate) enum CompileError {
     SelfModifyingCode,
+    // iced refused to encode something
+    Iced(IcedError),
+    // runtime helpers the code calls but nothing defines
+    Undefined(Vec<&'static str>),
+    Object(object::write::Error),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::SelfModifyingCode => {
+                write!(f, "code in writable memory")
+            }
+            CompileError::Iced(e) => write!(f, "{e}"),
+            CompileError::Undefined(names) => {
+                write!(f, "undefined helpers: {}", names.join(", "))
+            }
+            CompileError::Object(e) => write!(f, "{e}"),
+        }
+    }
 }
 
+impl std::error::Error for CompileError {}
+
+impl From<IcedError> for CompileError {
+    fn from(e: IcedError) -> Self {
+        CompileError::Iced(e)
+    }
+}
+
+impl From<object::write::Error> for CompileError {
+    fn from(e: object::write::Error) -> Self {
+        CompileError::Object(e)
+    }
+}
+
+/// compiles the block at `pc` from `code`. blocks in ram check that
+/// their code is still there whenever they start.
 pub(crate) fn transpile_block_at(
-    rom: Box<[u8; 3 + 1 << 15]>, // to make bounds checking unnecessary
-    pc: i16, // later I will change to range 0, 1<<15
-    outer_ctx: &Context,
+    code: &(impl CodeSource + ?Sized),
+    pc: BankedAddr,
+    outer_ctx: &mut Context,
 ) -> Result<CodeBlock, CompileError> {
-    let pc: u16 = if pc < 0 {
-        return Err(CompileError::SelfModifyingCode);
-    } else {
-        pc as u16
-    };
-
-    let mut ret = CodeBlock::new(&outer_ctx.mem_base_reg, pc);
-
-    loop {
-        let next_instr_bytes: [u8; 3] = (0..=2)
-            .map(|x| rom[x])
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        //        rom[pc as usize..pc as usize + 3];
+    // one basic block: up to and including the first instruction that
+    // leaves it, or stops the cpu. rom code does not go on into ram, ram
+    // code does not wrap around.
+    let ram = pc.addr >= 0x8000;
+    let end = if ram { 0x10000 } else { 0x8000 };
+    let mut instrs = vec![];
+    let mut next = pc;
+    while (next.addr as u32) < end {
         let sm83_instr: crate::Instruction =
-            sm83::decode_instr(next_instr_bytes);
+            sm83::decode_instr(code.instr_bytes(next));
+        instrs.push(sm83_instr);
+        if sm83_instr.flow(next.addr).ends_block()
+            || matches!(
+                sm83_instr,
+                sm83::Instruction::HALT | sm83::Instruction::STOP(_)
+            )
+        {
+            break;
+        }
+        match next.addr.checked_add(sm83_instr.len()) {
+            Some(addr) => next = next.to(addr),
+            None => break,
+        }
+    }
 
-        ret.push_sm83_instr(sm83_instr);
+    let e = outer_ctx.emitter();
+    let mut ret = CodeBlock::new(e, pc)?;
+    if ram {
+        let bytes: Vec<u8> = instrs.iter().flat_map(|i| i.encode()).collect();
+        ret.check_source(e, &bytes)?;
     }
+    ret.push_sm83_instrs(e, &instrs)?;
+    ret.finish(e)?;
     Ok(ret)
 }
 
-#[allow(dead_code)]
-fn how_to_use_code_assembler() -> Result<(), iced_x86::IcedError> {
-    use iced_x86::code_asm::*;
-    let mut a = CodeAssembler::new(64)?;
-
-    // Anytime you add something to a register (or subtract from it), you create a
-    // memory operand. You can also call word_ptr(), dword_bcst() etc to create memory
-    // operands.
-    let _ = rax; // register
-    let _ = rax + 0; // memory with no size hint
-    let _ = ptr(rax); // memory with no size hint
-    let _ = rax + rcx * 4 - 123; // memory with no size hint
-                                 // To create a memory operand with only a displacement or only a base register,
-                                 // you can call one of the memory fns:
-    let _ = qword_ptr(123); // memory with a qword size hint
-    let _ = dword_bcst(rcx); // memory (broadcast) with a dword size hint
-                             // To add a segment override, call the segment methods:
-    let _ = ptr(rax).fs(); // fs:[rax]
-
-    // Each mnemonic is a method
-    a.push(rcx)?;
-    // There are a few exceptions where you must append `_<opcount>` to the mnemonic to
-    // get the instruction you need:
-    a.ret()?;
-    a.ret_1(123)?;
-    // Use byte_ptr(), word_bcst(), etc to force the arg to a memory operand and to add a
-    // size hint
-    a.xor(byte_ptr(rdx + r14 * 4 + 123), 0x10)?;
-    // Prefixes are also methods
-    a.rep().stosd()?;
-    // Sometimes, you must add an integer suffix to help the compiler:
-    a.mov(rax, 0x1234_5678_9ABC_DEF0u64)?;
-
-    // Create labels that can be referenced by code
-    let mut loop_lbl1 = a.create_label();
-    let mut after_loop1 = a.create_label();
-    a.mov(ecx, 10)?;
-    a.set_label(&mut loop_lbl1)?;
-    // If needed, a zero-bytes instruction can be used as a label but this is optional
-    a.zero_bytes()?;
-    a.dec(ecx)?;
-    a.jp(after_loop1)?;
-    a.jne(loop_lbl1)?;
-    a.set_label(&mut after_loop1)?;
-
-    // It's possible to reference labels with RIP-relative addressing
-    let mut skip_data = a.create_label();
-    let mut data = a.create_label();
-    a.jmp(skip_data)?;
-    a.set_label(&mut data)?;
-    a.db(b"\x90\xCC\xF1\x90")?;
-    a.set_label(&mut skip_data)?;
-    a.lea(rax, ptr(data))?;
-
-    // AVX512 opmasks, {z}, {sae}, {er} and broadcasting are also supported:
-    a.vsqrtps(zmm16.k2().z(), dword_bcst(rcx))?;
-    a.vsqrtps(zmm1.k2().z(), zmm23.rd_sae())?;
-    // Sometimes, the encoder doesn't know if you want VEX or EVEX encoding.
-    // You can force EVEX globally like so:
-    a.set_prefer_vex(false);
-    a.vucomiss(xmm31, xmm15.sae())?;
-    a.vucomiss(xmm31, ptr(rcx))?;
-    // or call vex()/evex() to override the encoding option:
-    a.evex().vucomiss(xmm31, xmm15.sae())?;
-    a.vex().vucomiss(xmm15, xmm14)?;
-
-    // Encode all added instructions.
-    // Use `assemble_options()` if you must get the address of a label
-    let bytes = a.assemble(0x1234_5678)?;
-    assert_eq!(bytes.len(), 82);
-    // If you don't want to encode them, you can get all instructions by calling
-    // one of these methods:
-    let instrs = a.instructions(); // Get a reference to the internal vec
-    assert_eq!(instrs.len(), 19);
-    let instrs = a.take_instructions(); // Take ownership of the vec with all instructions
-    assert_eq!(instrs.len(), 19);
-    assert_eq!(a.instructions().len(), 0);
-
-    Ok(())
-}
-
 pub(crate) trait Label {
     type Addr: Into<usize>;
 
     fn new(addr: Self::Addr) -> Self;
-    fn name<'a>(&self) -> String; // unique name within a given context
+    fn name(&self) -> String; // unique name within a given context
     fn addr(&self) -> Self::Addr;
 }
 
 #[derive(Clone, Copy)]
 pub(crate) struct Sm83Label {
-    addr: u16,
+    addr: BankedAddr,
 }
 
 impl Label for Sm83Label {
-    type Addr = u16;
+    type Addr = BankedAddr;
 
-    fn new(ip: u16) -> Self {
+    fn new(ip: BankedAddr) -> Self {
         Self { addr: ip }
     }
-    fn name<'a>(&self) -> String {
-        format!("[${:02x}]", self.addr)
+    fn name(&self) -> String {
+        format!(".sm83_{:02x}_{:04x}", self.addr.bank, self.addr.addr)
     }
     fn addr(&self) -> Self::Addr {
-        self.addr.into()
+        self.addr
     }
 }
 
 impl std::ops::AddAssign<u16> for Sm83Label {
     fn add_assign(&mut self, rhs: u16) {
-        self.addr = self.addr + rhs
+        self.addr = self.addr.to(self.addr.addr.wrapping_add(rhs))
     }
 }
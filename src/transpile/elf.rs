@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Instruction, InstructionBlock, OpKind,
+};
+use object::write::{
+    Object, Relocation, SectionId, Symbol, SymbolId, SymbolSection,
+};
+use object::{
+    elf, Architecture, BinaryFormat, Endianness, RelocationFlags, SectionKind,
+    SymbolFlags, SymbolKind, SymbolScope,
+};
+
+use crate::cartridge::BankedAddr;
+
+use super::abi;
+use super::context::{CompileError, Context, Label, Sm83Label};
+
+// a relocatable elf64 object of a whole program, to link ahead of time.
+//
+// .text has the entry stubs, one global sm83_func per function, and
+// everything they share. every block gets a section of its own, so the
+// jumps between blocks are relocations, like the ones between functions
+// in c with -ffunction-sections. .data has the sm83 -> host table.
+
+/// bytes of one table entry: u16 bank, u16 addr, u32 pad, u64 host addr
+pub const TABLE_ENTRY: usize = 16;
+
+/// the object for everything `ctx` compiled, with `functions` exported
+/// under the given names
+pub(crate) fn write_object(
+    ctx: &mut Context,
+    functions: &[(BankedAddr, String)],
+) -> Result<Vec<u8>, CompileError> {
+    let addrs: Vec<BankedAddr> = functions.iter().map(|f| f.0).collect();
+    let unit = ctx.unit(&addrs)?;
+
+    let mut pieces: Vec<&[Instruction]> = vec![&unit.common];
+    pieces.extend(unit.blocks.iter().map(|(_, instrs)| &instrs[..]));
+
+    // every piece gets an ip far enough from the others that the jumps
+    // between them can not be short
+    let mut ip = 0;
+    let blocks: Vec<InstructionBlock> = pieces
+        .iter()
+        .map(|instrs| {
+            let block = InstructionBlock::new(instrs, ip);
+            ip += instrs.len() as u64 * 15 + 0x100;
+            block
+        })
+        .collect();
+    let encoded = BlockEncoder::encode_slice(
+        64,
+        &blocks,
+        BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS
+            | BlockEncoderOptions::RETURN_CONSTANT_OFFSETS,
+    )?;
+
+    // labelled instructions have the label id as ip
+    let mut labels: HashMap<u64, (usize, u64)> = HashMap::new();
+    for (piece, (instrs, res)) in pieces.iter().zip(&encoded).enumerate() {
+        for (instr, &offset) in instrs.iter().zip(&res.new_instruction_offsets)
+        {
+            if instr.ip() != 0 {
+                labels.insert(instr.ip(), (piece, offset as u64));
+            }
+        }
+    }
+
+    let common_offsets = encoded[0].new_instruction_offsets.clone();
+
+    let mut obj = Object::new(
+        BinaryFormat::Elf,
+        Architecture::X86_64,
+        Endianness::Little,
+    );
+    let sections: Vec<SectionId> = (0..pieces.len())
+        .map(|piece| {
+            let name = match piece {
+                0 => ".text".to_string(),
+                _ => {
+                    let start = unit.blocks[piece - 1].0;
+                    format!(".text{}", Sm83Label::new(start).name())
+                }
+            };
+            obj.add_section(vec![], name.into_bytes(), SectionKind::Text)
+        })
+        .collect();
+    let section_syms: Vec<SymbolId> =
+        sections.iter().map(|&s| obj.section_symbol(s)).collect();
+
+    for (piece, (instrs, res)) in pieces.iter().zip(encoded).enumerate() {
+        let mut code = res.code_buffer;
+        let offsets = &res.new_instruction_offsets;
+        let mut relocs = vec![];
+        for (i, instr) in instrs.iter().enumerate() {
+            let target = if instr.op0_kind() == OpKind::NearBranch64 {
+                instr.near_branch64()
+            } else if instr.is_ip_rel_memory_operand() {
+                instr.ip_rel_memory_address()
+            } else {
+                continue;
+            };
+            let &(to, to_offset) = labels.get(&target).expect("unplaced");
+            if to == piece {
+                continue;
+            }
+
+            // the rel32 is relative to the end of the instruction
+            let consts = res.constant_offsets[i];
+            let (field, size) = match consts.has_displacement() {
+                true => {
+                    (consts.displacement_offset(), consts.displacement_size())
+                }
+                false => (consts.immediate_offset(), consts.immediate_size()),
+            };
+            assert_eq!(size, 4, "a short jump out of a section");
+            let start = offsets[i] as usize;
+            let end = offsets[i + 1..]
+                .iter()
+                .find(|&&o| o != u32::MAX)
+                .map_or(code.len(), |&o| o as usize);
+            let at = start + field;
+            code[at..at + 4].fill(0);
+            relocs.push(Relocation {
+                offset: at as u64,
+                symbol: section_syms[to],
+                addend: to_offset as i64 - (end - at) as i64,
+                flags: RelocationFlags::Elf {
+                    r_type: elf::R_X86_64_PC32,
+                },
+            });
+        }
+        obj.append_section_data(sections[piece], &code, 16);
+        for reloc in relocs {
+            obj.add_relocation(sections[piece], reloc)?;
+        }
+    }
+
+    // the labels, so a disassembly reads like the .s
+    let local = |name: String, section, value| Symbol {
+        name: name.into_bytes(),
+        value,
+        size: 0,
+        kind: SymbolKind::Label,
+        scope: SymbolScope::Compilation,
+        weak: false,
+        section: SymbolSection::Section(section),
+        flags: SymbolFlags::None,
+    };
+    for (piece, &(start, _)) in unit.blocks.iter().enumerate() {
+        let name = Sm83Label::new(start).name();
+        obj.add_symbol(local(name, sections[piece + 1], 0));
+    }
+    for (name, index) in [(abi::ENTER, unit.enter), (abi::EXIT, unit.exit)] {
+        let offset = common_offsets[index] as u64;
+        obj.add_symbol(local(name.into(), sections[0], offset));
+    }
+
+    // a stub is a lea and a jmp
+    for ((_, name), &(_, index)) in functions.iter().zip(&unit.entries) {
+        let offset = common_offsets[index] as u64;
+        obj.add_symbol(Symbol {
+            name: name.clone().into_bytes(),
+            value: offset,
+            size: common_offsets[index + 2] as u64 - offset,
+            kind: SymbolKind::Text,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section: SymbolSection::Section(sections[0]),
+            flags: SymbolFlags::None,
+        });
+    }
+
+    // sm83 addr -> the code of its block, sorted, for dispatchers
+    let data = obj.add_section(vec![], b".data".to_vec(), SectionKind::Data);
+    let mut table = vec![];
+    for &(start, _) in &unit.blocks {
+        table.extend(start.bank.to_le_bytes());
+        table.extend(start.addr.to_le_bytes());
+        table.extend([0; 12]);
+    }
+    let table_offset = obj.append_section_data(data, &table, 8);
+    // the blocks' sections come right after .text
+    for (i, &symbol) in section_syms[1..].iter().enumerate() {
+        let at = table_offset + (i * TABLE_ENTRY + 8) as u64;
+        obj.add_relocation(
+            data,
+            Relocation {
+                offset: at,
+                symbol,
+                addend: 0,
+                flags: RelocationFlags::Elf {
+                    r_type: elf::R_X86_64_64,
+                },
+            },
+        )?;
+    }
+    let len = (unit.blocks.len() as u64).to_le_bytes();
+    let len_offset = obj.append_section_data(data, &len, 8);
+    for (name, value, size) in [
+        ("sm83_table", table_offset, table.len() as u64),
+        ("sm83_table_len", len_offset, 8),
+    ] {
+        obj.add_symbol(Symbol {
+            name: name.into(),
+            value,
+            size,
+            kind: SymbolKind::Data,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section: SymbolSection::Section(data),
+            flags: SymbolFlags::None,
+        });
+    }
+
+    // no executable stack, please
+    obj.add_section(vec![], b".note.GNU-stack".to_vec(), SectionKind::Other);
+    Ok(obj.write()?)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    use object::{Object as _, ObjectSection, ObjectSymbol};
+
+    use super::*;
+    use crate::analysis::{Cfg, Symbols};
+    use crate::transpile::header::write_header;
+    use crate::cartridge::test_rom;
+    use crate::sm83::asm::asm;
+
+    const MAIN: BankedAddr = BankedAddr::new(0x150, 0);
+
+    /// a loop and a call, compiled, with main_loop from a .sym
+    pub fn program() -> (Context, Vec<(BankedAddr, String)>) {
+        let rom = test_rom(&asm!(
+            0x150,
+            "       ld b, 10
+                    xor a
+            loop:   add b
+                    dec b
+                    jr nz, loop
+                    call store
+                    halt
+            store:  ld [$c000], a
+                    halt"
+        ));
+        let cfg = Cfg::discover_from(&rom, &[MAIN]);
+        let mut ctx = Context::default();
+        for &start in cfg.blocks.keys() {
+            ctx.compile(&rom, start).unwrap();
+        }
+        let syms = Symbols::parse("00:0150 main_loop").unwrap();
+        let functions = cfg.functions.union(&cfg.entries).copied();
+        (ctx, abi::function_names(functions, Some(&syms)))
+    }
+
+    /// what running program() from c prints: a, [$c000], pc after the
+    /// halt, halt, sp with the return addr, then the table
+    pub const PRINTED: &str = "55 55 015f 1 fffc \
+                               0150:1 0153:1 0157:1 015a:1 015b:1";
+
+    /// links `obj` in `dir` with a c main that runs program(), through its
+    /// header
+    pub fn run_c(dir: &Path, obj: &str) -> String {
+        let (_, functions) = program();
+        let header = write_header("rom", &functions);
+        std::fs::write(dir.join("rom.h"), header).unwrap();
+        std::fs::write(
+            dir.join("main.c"),
+            r#"
+            #include <stdio.h>
+            #include "rom.h"
+
+            static uint8_t mem[0x10000];
+
+            int main(void) {
+                sm83_func f = main_loop;
+                sm83_state s = f(0, 0, 0, 0, 0xfffe, mem);
+                printf("%d %d %04x %d %04x", s.af >> 8, mem[0xc000], s.pc,
+                       s.exit, s.sp);
+                for (uint64_t i = 0; i < sm83_table_len; i++)
+                    printf(" %04x:%d", sm83_table[i].addr,
+                           sm83_table[i].code != 0);
+                return 0;
+            }
+            "#,
+        )
+        .unwrap();
+
+        let status = Command::new("cc")
+            .current_dir(dir)
+            .args(["-Wall", "-Werror", "-o", "main", "main.c", obj])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let out = Command::new(dir.join("main")).output().unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    fn object() -> Vec<u8> {
+        let (mut ctx, functions) = program();
+        write_object(&mut ctx, &functions).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    #[test]
+    fn exports_functions_and_relocates_jumps() {
+        let data = object();
+        let file = object::File::parse(&*data).unwrap();
+        let globals: Vec<&str> = file
+            .symbols()
+            .filter(|sym| sym.is_global())
+            .map(|sym| sym.name().unwrap())
+            .collect();
+        assert_eq!(
+            globals,
+            ["main_loop", "sm83_00_015b", "sm83_table", "sm83_table_len"]
+        );
+
+        // jr nz and the fall through both go to other blocks
+        let block = file.section_by_name(".text.sm83_00_0150").unwrap();
+        assert_eq!(block.relocations().count(), 2);
+        let table = file.section_by_name(".data").unwrap();
+        assert_eq!(table.relocations().count(), 5);
+    }
+
+    #[test]
+    fn links_into_c() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("rom.o"), object()).unwrap();
+        assert_eq!(run_c(dir.path(), "rom.o"), PRINTED);
+    }
+}
@@ -0,0 +1,226 @@
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+
+use crate::sm83::info::FlagSet;
+use crate::sm83::{self, Flow};
+
+use super::context::Emitter;
+
+// where the sm83 flags are while compiled code runs. z, h and c have
+// twins in EFLAGS (ZF, AF and CF), which x86 arithmetic keeps for free;
+// n has none and sits at its bit of F in the saved byte. whatever an
+// instruction sets to a constant stays a constant until something needs
+// it in its place: the end of a block, push af or a helper.
+
+pub const Z: u8 = 0x80;
+pub const N: u8 = 0x40;
+pub const H: u8 = 0x20;
+pub const C: u8 = 0x10;
+
+/// the saved flags byte, laid out like F
+pub const SAVED: AsmRegister8 = r9b;
+
+/// where one flag is right now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Loc {
+    // in EFLAGS. n can not be here.
+    Host,
+    // at its bit in SAVED
+    Saved,
+    Const(bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags {
+    pub z: Loc,
+    pub n: Loc,
+    pub h: Loc,
+    pub c: Loc,
+}
+
+impl Flags {
+    /// where they are between blocks, and in and out of compiled code
+    pub const CANONICAL: Self = Self {
+        z: Loc::Host,
+        n: Loc::Saved,
+        h: Loc::Host,
+        c: Loc::Host,
+    };
+
+    /// after an instruction that sets n and h to constants
+    pub fn set_nh(&mut self, n: bool, h: bool) {
+        self.n = Loc::Const(n);
+        self.h = Loc::Const(h);
+    }
+
+    /// each flag with its bit in F
+    fn each(self) -> [(u8, Loc); 4] {
+        [(Z, self.z), (N, self.n), (H, self.h), (C, self.c)]
+    }
+
+    /// the bits of F whose flags are where `pred` says
+    fn bits(self, pred: impl Fn(Loc) -> bool) -> u8 {
+        self.each()
+            .into_iter()
+            .filter(|&(_, loc)| pred(loc))
+            .fold(0, |bits, (bit, _)| bits | bit)
+    }
+}
+
+/// for each of `instrs`, one block starting at `pc`, the flags that are
+/// read after it before anything sets them again. whatever leaves the
+/// block reads them all.
+pub fn live_after(pc: u16, instrs: &[sm83::Instruction]) -> Vec<FlagSet> {
+    let mut pcs = vec![];
+    instrs.iter().fold(pc, |pc, instr| {
+        pcs.push(pc);
+        pc.wrapping_add(instr.len())
+    });
+
+    let mut live = FlagSet::ALL;
+    let mut after = vec![FlagSet::NONE; instrs.len()];
+    for (i, instr) in instrs.iter().enumerate().rev() {
+        after[i] = live;
+        let info = instr.info();
+        let leaves = instr.flow(pcs[i]) != Flow::Next
+            || matches!(
+                instr,
+                sm83::Instruction::HALT | sm83::Instruction::STOP(_)
+            );
+        live = match leaves {
+            true => FlagSet::ALL,
+            false => FlagSet(live.0 & !info.flags.written().0 | info.reads.0),
+        };
+    }
+    after
+}
+
+/// the bit of EFLAGS that holds the flag at `bit` of F
+fn host_bit(bit: u8) -> u32 {
+    match bit {
+        Z => 0x40,
+        H => 0x10,
+        C => 0x01,
+        _ => unreachable!("n has no host flag"),
+    }
+}
+
+/// puts every flag where Flags::CANONICAL says, without changing any
+pub(crate) fn sync(e: &mut Emitter) -> Result<(), IcedError> {
+    let flags = e.flags;
+    debug_assert_eq!(flags.bits(|loc| loc == Loc::Saved) & !N, 0);
+    debug_assert_ne!(flags.n, Loc::Host);
+
+    let (mut clear, mut set) = (0, 0);
+    for (bit, loc) in flags.each() {
+        if let (Loc::Const(value), true) = (loc, bit != N) {
+            clear |= host_bit(bit);
+            if value {
+                set |= host_bit(bit);
+            }
+        }
+    }
+    let a = &mut e.asm;
+    if clear != 0 {
+        a.lahf()?;
+        a.and(ah, !clear & 0xff)?;
+        if set != 0 {
+            a.or(ah, set)?;
+        }
+        a.sahf()?;
+    }
+    if let Loc::Const(n) = flags.n {
+        // mov leaves EFLAGS alone
+        a.mov(SAVED, if n { N as u32 } else { 0 })?;
+    }
+    e.flags = Flags::CANONICAL;
+    Ok(())
+}
+
+/// the exact F for `flags` into r10d, low nibble zero. EFLAGS stay.
+pub(crate) fn pack(e: &mut Emitter, flags: Flags) -> Result<(), IcedError> {
+    let a = &mut e.asm;
+    // ah can not go to r10 directly, only through memory
+    a.lahf()?;
+    a.mov(byte_ptr(rsp - 8), ah)?;
+    a.movzx(r10d, byte_ptr(rsp - 8))?;
+    // ZF and AF are one bit to the right of z and h, CF four
+    a.mov(r11d, r10d)?;
+    a.and(r11d, 0x50)?;
+    a.shl(r11d, 1)?;
+    a.and(r10d, 1)?;
+    a.shl(r10d, 4)?;
+    a.or(r10d, r11d)?;
+
+    let host = flags.bits(|loc| loc == Loc::Host);
+    if host != Z | H | C {
+        a.and(r10d, host as u32)?;
+    }
+    let set = flags.bits(|loc| loc == Loc::Const(true));
+    if set != 0 {
+        a.or(r10d, set as u32)?;
+    }
+    let saved = flags.bits(|loc| loc == Loc::Saved);
+    if saved != 0 {
+        a.movzx(r11d, SAVED)?;
+        a.and(r11d, saved as u32)?;
+        a.or(r10d, r11d)?;
+    }
+    a.sahf()
+}
+
+/// F from the low byte of r10d into its places, clobbering r10 and r11
+pub(crate) fn unpack(e: &mut Emitter) -> Result<(), IcedError> {
+    let a = &mut e.asm;
+    a.mov(SAVED, r10b)?;
+    a.mov(r11d, r10d)?;
+    a.and(r11d, (Z | H) as u32)?;
+    a.shr(r11d, 1)?;
+    a.shr(r10d, 4)?;
+    a.and(r10d, 1)?;
+    a.or(r10d, r11d)?;
+    a.mov(byte_ptr(rsp - 8), r10b)?;
+    a.mov(ah, byte_ptr(rsp - 8))?;
+    a.sahf()?;
+    e.flags = Flags::CANONICAL;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm83::asm::asm;
+
+    fn live(src: &str) -> Vec<FlagSet> {
+        let code = asm!(0x150, src);
+        let mut instrs = vec![];
+        let mut at = 0;
+        while at < code.len() {
+            let mut bytes = [0; 3];
+            let len = bytes.len().min(code.len() - at);
+            bytes[..len].copy_from_slice(&code[at..at + len]);
+            let instr = sm83::decode_instr(bytes);
+            instrs.push(instr);
+            at += instr.len() as usize;
+        }
+        live_after(0x150, &instrs)
+    }
+
+    #[test]
+    fn h_is_dead_until_read() {
+        let h = |live: &[FlagSet]| -> Vec<bool> {
+            live.iter().map(|l| l.contains(FlagSet::H)).collect()
+        };
+        // inc sets h again before push af reads it
+        assert_eq!(
+            h(&live("add hl, bc / inc a / push af / ld a, b")),
+            [false, true, true, true]
+        );
+        // and the end of the block reads everything
+        assert_eq!(
+            h(&live("add hl, bc / and a / jr nz, @")),
+            [false, true, true]
+        );
+        assert_eq!(h(&live("add hl, bc / ld a, b")), [true, true]);
+    }
+}
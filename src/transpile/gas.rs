@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use iced_x86::{
+    CC_ae, CC_b, CC_e, CC_ne, Code, Formatter, Instruction, IntelFormatter,
+    MemorySizeOptions, OpKind, Register, SymbolResolver, SymbolResult,
+};
+
+use crate::cartridge::BankedAddr;
+use crate::sm83::{Reg, RegPair};
+
+use super::context::{AsmLabel, CompileError, Context, Label, Sm83Label};
+use super::flags;
+use super::mapping::{g16, g8, name};
+
+// the whole program as gnu as source, intel syntax without prefixes, to
+// read, hand edit and assemble. it is the same code the jit and the
+// object get, only with gas picking the jump sizes.
+
+/// names the labels jumps and lea go to. immediates that happen to look
+/// like a label stay numbers.
+struct Labels(HashMap<AsmLabel, String>);
+
+impl Labels {
+    fn name(&self, label: AsmLabel) -> String {
+        self.0
+            .get(&label)
+            .cloned()
+            .unwrap_or_else(|| format!(".L{label}"))
+    }
+}
+
+impl SymbolResolver for Labels {
+    fn symbol(
+        &mut self,
+        instruction: &Instruction,
+        operand: u32,
+        _instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<SymbolResult<'_>> {
+        let label = match instruction.op_kind(operand) {
+            OpKind::NearBranch64 => true,
+            OpKind::Memory => instruction.memory_base() == Register::RIP,
+            _ => false,
+        };
+        label.then(|| SymbolResult::with_string(address, self.name(address)))
+    }
+}
+
+/// the source for everything `ctx` compiled, with `functions` exported
+/// under the given names
+pub(crate) fn write_asm(
+    ctx: &mut Context,
+    functions: &[(BankedAddr, String)],
+) -> Result<String, CompileError> {
+    let addrs: Vec<BankedAddr> = functions.iter().map(|f| f.0).collect();
+    let unit = ctx.unit(&addrs)?;
+    let labels = Labels(ctx.label_names());
+
+    let mut out = String::new();
+    writeln!(out, "# recompiled by gb_recompiler").unwrap();
+    write_mapping(&mut out, ctx);
+    writeln!(out, ".intel_syntax noprefix").unwrap();
+    writeln!(out, ".text").unwrap();
+
+    let mut f = IntelFormatter::with_options(
+        Some(Box::new(Labels(labels.0.clone()))),
+        None,
+    );
+    let options = f.options_mut();
+    options.set_hex_prefix("0x");
+    options.set_hex_suffix("");
+    options.set_uppercase_hex(false);
+    options.set_space_after_operand_separator(true);
+    options.set_rip_relative_addresses(true);
+    options.set_show_branch_size(false);
+    // gas can not always guess the size
+    options.set_memory_size_options(MemorySizeOptions::Always);
+    // the flags are sm83 z and c, so say so
+    options.set_cc_e(CC_e::z);
+    options.set_cc_ne(CC_ne::nz);
+    options.set_cc_b(CC_b::c);
+    options.set_cc_ae(CC_ae::nc);
+
+    let mut write = |out: &mut String, instr: &Instruction| {
+        if instr.code() == Code::Zero_bytes {
+            writeln!(out, "{}:", labels.name(instr.ip())).unwrap();
+        } else if instr.code() == Code::DeclareByte {
+            // data among the code, like code_lines
+            let bytes: Vec<String> = (0..instr.declare_data_len())
+                .map(|i| format!("{:#04x}", instr.get_declare_byte_value(i)))
+                .collect();
+            writeln!(out, "    .byte {}", bytes.join(", ")).unwrap();
+        } else {
+            out.push_str("    ");
+            f.format(instr, out);
+            out.push('\n');
+        }
+    };
+
+    // the entry stubs come first, a lea and a jmp each
+    let stubs: HashMap<usize, &str> = functions
+        .iter()
+        .zip(&unit.entries)
+        .map(|((_, name), &(_, index))| (index, name.as_str()))
+        .collect();
+    for (index, instr) in unit.common.iter().enumerate() {
+        if let Some(name) = stubs.get(&index) {
+            writeln!(out, "\n.globl {name}").unwrap();
+            writeln!(out, ".type {name}, @function").unwrap();
+            writeln!(out, "{name}:").unwrap();
+        }
+        if index == unit.enter || index == unit.exit {
+            out.push('\n');
+        }
+        write(&mut out, instr);
+        let stub = index.checked_sub(1).and_then(|i| stubs.get(&i));
+        if let Some(name) = stub {
+            writeln!(out, ".size {name}, . - {name}").unwrap();
+        }
+    }
+    for (_, block) in &unit.blocks {
+        out.push('\n');
+        for instr in block {
+            write(&mut out, instr);
+        }
+    }
+
+    // sm83 addr -> the code of its block, like in the object
+    writeln!(out, "\n.data").unwrap();
+    writeln!(out, ".balign 8").unwrap();
+    writeln!(out, ".globl sm83_table").unwrap();
+    writeln!(out, "sm83_table:").unwrap();
+    for &(start, _) in &unit.blocks {
+        writeln!(out, "    .short {:#x}, {:#06x}", start.bank, start.addr)
+            .unwrap();
+        writeln!(out, "    .long 0").unwrap();
+        writeln!(out, "    .quad {}", Sm83Label::new(start).name()).unwrap();
+    }
+    writeln!(out, ".globl sm83_table_len").unwrap();
+    writeln!(out, "sm83_table_len:").unwrap();
+    writeln!(out, "    .quad {}", unit.blocks.len()).unwrap();
+
+    writeln!(out, "\n.section .note.GNU-stack, \"\", @progbits").unwrap();
+    Ok(out)
+}
+
+/// a comment with where the sm83 state lives
+fn write_mapping(out: &mut String, ctx: &Context) {
+    use Reg::*;
+    use RegPair::*;
+    let regs =
+        [A, F, B, C, D, E, H, L].map(|r| format!("{r:?} = {}", name(g8(r))));
+    writeln!(out, "# {}", regs.join(", ").to_lowercase()).unwrap();
+    writeln!(
+        out,
+        "# sp = {}, mem = {}, z h c in ZF AF CF, n in {}",
+        name(g16(SP)),
+        name(ctx.mem_base_reg),
+        name(flags::SAVED),
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::transpile::elf::tests::{program, run_c, PRINTED};
+
+    #[test]
+    fn assembles_and_links_into_c() {
+        let (mut ctx, functions) = program();
+        let src = write_asm(&mut ctx, &functions).unwrap();
+        assert!(src.contains("\n.globl main_loop\n"), "{src}");
+        assert!(src.contains("\n.sm83_00_0153:\n"), "{src}");
+        assert!(src.contains("# sp = di, mem = rsi"), "{src}");
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("rom.s"), &src).unwrap();
+        let status = Command::new("as")
+            .current_dir(dir.path())
+            .args(["--64", "-o", "rom.o", "rom.s"])
+            .status()
+            .unwrap();
+        assert!(status.success(), "{src}");
+        assert_eq!(run_c(dir.path(), "rom.o"), PRINTED);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt::Write;
+
+use crate::cartridge::BankedAddr;
+
+use super::abi::{BusHooks, ExitReason, State};
+use super::elf::TABLE_ENTRY;
+
+// a c header for the object or .s of a program: the abi from the readme,
+// a prototype per exported function and where it was on the sm83.
+
+/// the header for a program exporting `functions`. `name` keeps the
+/// include guard apart from other programs'.
+pub fn write_header(name: &str, functions: &[(BankedAddr, String)]) -> String {
+    let guard: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    let mut out = String::new();
+    writeln!(out, "/* recompiled by gb_recompiler */").unwrap();
+    writeln!(out, "#ifndef GB_RECOMPILED_{guard}_H").unwrap();
+    writeln!(out, "#define GB_RECOMPILED_{guard}_H").unwrap();
+    writeln!(out, "\n#include <stdint.h>").unwrap();
+
+    // shared with every other recompiled program
+    writeln!(out, "\n#ifndef GB_RECOMPILED_ABI").unwrap();
+    writeln!(out, "#define GB_RECOMPILED_ABI").unwrap();
+    out.push_str(ABI);
+    writeln!(out, "#endif").unwrap();
+
+    writeln!(out).unwrap();
+    for (addr, name) in functions {
+        writeln!(out, "#define SM83_BANK_{name} {:#04x}", addr.bank).unwrap();
+        writeln!(out, "#define SM83_ADDR_{name} {:#06x}", addr.addr).unwrap();
+        writeln!(out, "sm83_state {name}{PARAMS};").unwrap();
+    }
+
+    writeln!(out, "\n#endif").unwrap();
+    out
+}
+
+const PARAMS: &str = "(uint32_t af, uint32_t bc, uint32_t de, uint32_t hl,
+                      uint32_t sp, uint8_t *mem)";
+
+const ABI: &str = "
+typedef struct {
+    uint16_t af, bc, de, hl, sp, pc;
+    uint16_t exit; /* an sm83_exit */
+    uint16_t ime;
+} sm83_state;
+
+enum sm83_exit {
+    SM83_EXIT_JUMP = 0, /* to pc, which is not compiled */
+    SM83_EXIT_HALT = 1,
+    SM83_EXIT_STOP = 2,
+    SM83_EXIT_LOCKUP = 3, /* an invalid opcode at pc */
+    SM83_EXIT_INDIRECT = 4, /* to pc, by jp hl or ret, not compiled */
+    SM83_EXIT_CODE_WRITE = 5, /* to pc, after a store into compiled code */
+};
+
+/* ime goes in at bit 16 of af */
+typedef sm83_state (*sm83_func)(uint32_t af, uint32_t bc, uint32_t de,
+                                uint32_t hl, uint32_t sp, uint8_t *mem);
+
+/* code compiled to trap i/o or switch banks takes a 7th argument, an
+   sm83_bus *. read is called before $ff00-$ff7f is read and may change
+   mem[addr] first; write after it or the rom is written, with the new
+   value in mem[addr]. write keeps rom_bank up to date, and sets
+   rom0_switched when another bank is mapped at $0000-$3fff; the code
+   clears it as it leaves with a CODE_WRITE exit for that. */
+typedef struct sm83_bus {
+    void *data;
+    void (*read)(struct sm83_bus *bus, uint8_t *mem, uint32_t addr);
+    void (*write)(struct sm83_bus *bus, uint8_t *mem, uint32_t addr);
+    uint16_t rom_bank; /* in $4000-$7fff */
+    uint8_t rom0_switched;
+} sm83_bus;
+
+/* sm83 addr -> the code of its block, sorted */
+typedef struct {
+    uint16_t bank, addr;
+    uint32_t pad;
+    void *code;
+} sm83_table_entry;
+extern const sm83_table_entry sm83_table[];
+extern const uint64_t sm83_table_len;
+";
+
+// the header spells these out by hand
+const _: () = {
+    assert!(std::mem::size_of::<State>() == 16);
+    assert!(TABLE_ENTRY == 16);
+    assert!(std::mem::size_of::<BusHooks>() == 32);
+    assert!(ExitReason::CodeWrite as u16 == 5);
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declares_functions() {
+        let functions = [
+            (BankedAddr::new(0x150, 0), "main_loop".to_string()),
+            (BankedAddr::new(0x4000, 2), "sm83_02_4000".to_string()),
+        ];
+        let h = write_header("rom-1", &functions);
+        assert!(h.contains("#ifndef GB_RECOMPILED_ROM_1_H\n"), "{h}");
+        assert!(h.contains("#define SM83_ADDR_main_loop 0x0150\n"), "{h}");
+        assert!(h.contains("#define SM83_BANK_sm83_02_4000 0x02\n"), "{h}");
+        assert!(h.contains("sm83_state main_loop(uint32_t af,"), "{h}");
+    }
+}
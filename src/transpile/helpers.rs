@@ -0,0 +1,262 @@
+use std::ops::Range;
+
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+
+use crate::cartridge::BankedAddr;
+
+use super::abi::{BusHooks, ExitReason, BUS_ARG};
+use super::context::{AsmLabel, Emitter};
+use super::flags::{self, SAVED};
+
+// runtime helpers: what blocks call instead of inlining it every time.
+// they go with the prologue and the epilogue, once per unit, and find the
+// flags where flags::sync leaves them. some are only data.
+
+/// the helper called `name`, at `label`, for a unit with the sm83 code in
+/// `blocks` compiled, sorted. false if there is no such thing.
+pub(crate) fn emit(
+    e: &mut Emitter,
+    name: &str,
+    label: AsmLabel,
+    blocks: &[Range<BankedAddr>],
+) -> Result<bool, IcedError> {
+    e.place(label)?;
+    match name {
+        "daa" => daa(e)?,
+        "dispatch" => {
+            let starts: Vec<BankedAddr> =
+                blocks.iter().map(|b| b.start).collect();
+            dispatch(e, &starts)?
+        }
+        "code_lines" => code_lines(e, blocks)?,
+        "bus_read" => bus_hook(e, std::mem::offset_of!(BusHooks, read))?,
+        "bus_write" => bus_hook(e, std::mem::offset_of!(BusHooks, write))?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// calls the hook at `offset` in the BusHooks for the addr in r10d. the
+/// sm83 registers stay, EFLAGS do not.
+fn bus_hook(e: &mut Emitter, offset: usize) -> Result<(), IcedError> {
+    let saved = [rax, rcx, rdx, rsi, rdi, r8, r9];
+    let mem = e.mem;
+    let a = &mut e.asm;
+    for reg in saved {
+        a.push(reg)?;
+    }
+    // the stack is aligned for the call, and the old rsp is on it
+    a.mov(r8, rsp)?;
+    a.and(rsp, -16)?;
+    a.push(r8)?;
+    a.push(r8)?;
+    a.mov(rsi, mem)?;
+    a.mov(rdi, qword_ptr(rbp + BUS_ARG))?;
+    a.mov(edx, r10d)?;
+    a.call(qword_ptr(rdi + offset))?;
+    a.pop(rsp)?;
+    for reg in saved.into_iter().rev() {
+        a.pop(reg)?;
+    }
+    a.ret()
+}
+
+/// how many bytes of sm83 memory one bit of code_lines stands for
+pub const LINE_BITS: u32 = 6;
+
+/// a bit per 64 bytes of the sm83 address space, set where some block's
+/// code is, in any bank. with switch_banks stores do not change the rom,
+/// so only ram has any.
+fn code_lines(
+    e: &mut Emitter,
+    blocks: &[Range<BankedAddr>],
+) -> Result<(), IcedError> {
+    let mut lines = [0u8; 0x10000 >> LINE_BITS >> 3];
+    let rom = |block: &&Range<BankedAddr>| block.start.addr < 0x8000;
+    let skip_rom = e.switch_banks;
+    for block in blocks.iter().filter(|b| !(skip_rom && rom(b))) {
+        // the last byte of a block is end - 1, which may be 0xffff
+        let last = block.end.addr.wrapping_sub(1).max(block.start.addr);
+        for line in block.start.addr >> LINE_BITS..=last >> LINE_BITS {
+            lines[line as usize >> 3] |= 1 << (line & 7);
+        }
+    }
+    e.asm.db(&lines)
+}
+
+/// how much host stack calls may take before a call or dispatch leaves to
+/// the runtime instead, which starts over with an empty one. calls that
+/// never return the usual way (pop hl / jp hl) leave their frames behind.
+pub const MAX_DEPTH: i32 = 0x80000;
+
+/// jumps to the block for the sm83 addr in r10d, or leaves to the runtime
+/// with an Indirect exit if it is not compiled. r11d is the bank
+/// 0x4000..0x8000 means. the flags stay.
+fn dispatch(e: &mut Emitter, blocks: &[BankedAddr]) -> Result<(), IcedError> {
+    let lookup = e.new_label();
+    let miss = e.new_label();
+    let deep = e.new_label();
+    let a = &mut e.asm;
+    a.lahf()?;
+    a.lea(r8, ptr(rbp - MAX_DEPTH))?;
+    a.cmp(rsp, r8)?;
+    a.jb(deep)?;
+    // bank << 16 | addr, which sorts like BankedAddr. only the
+    // switchable rom has a bank.
+    a.cmp(r10d, 0x4000)?;
+    a.jb(lookup)?;
+    a.cmp(r10d, 0x8000)?;
+    a.jae(lookup)?;
+    a.shl(r11d, 16)?;
+    a.or(r10d, r11d)?;
+    e.place(lookup)?;
+
+    let keys: Vec<(u32, AsmLabel)> = blocks
+        .iter()
+        .map(|&addr| {
+            let key = (addr.bank as u32) << 16 | addr.addr as u32;
+            (key, e.new_label())
+        })
+        .collect();
+    search(e, &keys, miss)?;
+
+    // lea instead of or, the flags are back
+    e.place(miss)?;
+    let a = &mut e.asm;
+    a.sahf()?;
+    a.movzx(r8d, r10w)?;
+    a.lea(r8d, ptr(r8 + ((ExitReason::Indirect as i32) << 16)))?;
+    a.jmp(e.exit)?;
+    // compiled or not, the runtime comes back with an empty stack
+    e.place(deep)?;
+    let a = &mut e.asm;
+    a.sahf()?;
+    a.movzx(r8d, r10w)?;
+    a.jmp(e.exit)?;
+    for (&(_, hit), &addr) in keys.iter().zip(blocks) {
+        e.place(hit)?;
+        e.asm.sahf()?;
+        let block = e.label(addr);
+        e.asm.jmp(block)?;
+    }
+    Ok(())
+}
+
+/// a binary search for r10d in sorted `keys`, to their labels
+fn search(
+    e: &mut Emitter,
+    keys: &[(u32, AsmLabel)],
+    miss: AsmLabel,
+) -> Result<(), IcedError> {
+    if keys.len() <= 3 {
+        for &(key, hit) in keys {
+            e.asm.cmp(r10d, key)?;
+            e.asm.je(hit)?;
+        }
+        return e.asm.jmp(miss);
+    }
+    let (low, high) = keys.split_at(keys.len() / 2);
+    let (key, hit) = high[0];
+    let below = e.new_label();
+    e.asm.cmp(r10d, key)?;
+    e.asm.je(hit)?;
+    e.asm.jb(below)?;
+    search(e, &high[1..], miss)?;
+    e.place(below)?;
+    search(e, low, miss)
+}
+
+/// daa on al. takes c and h from CF and AF, n from SAVED, and leaves z in
+/// ZF and c in CF. h is 0 after, whatever AF says.
+fn daa(e: &mut Emitter) -> Result<(), IcedError> {
+    let [add_hi, add_lo, add_6, add, sub, sub_lo, sub_6, done] =
+        [(); 8].map(|_| e.new_label());
+
+    // r10 has c at bit 0 and h at bit 4, r11 the correction
+    let a = &mut e.asm;
+    a.lahf()?;
+    a.mov(byte_ptr(rsp - 8), ah)?;
+    a.movzx(r10d, byte_ptr(rsp - 8))?;
+    a.xor(r11d, r11d)?;
+    a.test(SAVED, flags::N as u32)?;
+    a.jnz(sub)?;
+
+    // after an add: a digit is off if it carried or went past 9
+    a.test(r10b, 1)?;
+    a.jnz(add_hi)?;
+    a.cmp(al, 0x99)?;
+    a.jbe(add_lo)?;
+    e.place(add_hi)?;
+    let a = &mut e.asm;
+    a.mov(r11d, 0x60)?;
+    a.or(r10d, 1)?;
+    e.place(add_lo)?;
+    let a = &mut e.asm;
+    a.test(r10b, 0x10)?;
+    a.jnz(add_6)?;
+    a.mov(ah, al)?;
+    a.and(ah, 0xf)?;
+    a.cmp(ah, 9)?;
+    a.jbe(add)?;
+    e.place(add_6)?;
+    e.asm.or(r11d, 6)?;
+    e.place(add)?;
+    let a = &mut e.asm;
+    a.add(al, r11b)?;
+    a.jmp(done)?;
+
+    // after a sub only the flags say what borrowed
+    e.place(sub)?;
+    let a = &mut e.asm;
+    a.test(r10b, 1)?;
+    a.jz(sub_lo)?;
+    a.mov(r11d, 0x60)?;
+    e.place(sub_lo)?;
+    let a = &mut e.asm;
+    a.test(r10b, 0x10)?;
+    a.jz(sub_6)?;
+    a.or(r11d, 6)?;
+    e.place(sub_6)?;
+    e.asm.sub(al, r11b)?;
+
+    // z from the add or sub, c from r10. bt leaves ZF alone.
+    e.place(done)?;
+    let a = &mut e.asm;
+    a.bt(r10d, 0)?;
+    a.ret()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::BankedAddr;
+    use crate::interp::Cpu;
+    use crate::jit::Jit;
+    use crate::sm83::Instruction;
+    use crate::transpile::Context;
+
+    #[test]
+    fn daa_agrees_with_interp_everywhere() {
+        let start = BankedAddr::new(0x150, 0);
+        let mut ctx = Context::default();
+        ctx.compile_instrs(start, &[Instruction::DAA]).unwrap();
+        let jit =
+            Jit::new(&mut ctx, &[start]).unwrap_or_else(|e| panic!("{e}"));
+
+        for a in 0..=0xff {
+            for f in (0..=0xf0).step_by(0x10) {
+                let mut reference = Cpu::default();
+                reference.regs.a = a;
+                reference.regs.f = f;
+                let mut cpu = reference.clone();
+                reference.execute(Instruction::DAA);
+                jit.run(start, &mut cpu);
+                assert_eq!(
+                    (cpu.regs.a, cpu.regs.f),
+                    (reference.regs.a, reference.regs.f),
+                    "daa of {a:02x} with f = {f:02x}"
+                );
+            }
+        }
+    }
+}
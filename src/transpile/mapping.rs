@@ -1,9 +1,7 @@
 use crate::sm83::regs::*;
 use iced_x86::{
     code_asm::{
-        registers::{
-            gpr64::get_gpr64, gpr16::get_gpr16, gpr8::get_gpr8,
-        },
+        registers::{gpr16::get_gpr16, gpr64::get_gpr64, gpr8::get_gpr8},
         AsmRegister16, AsmRegister64, AsmRegister8,
     },
     Register,
@@ -11,12 +9,19 @@ use iced_x86::{
 
 type Amd64 = iced_x86::Register;
 
+// the pairs are the 16 bit halves of the registers holding their parts,
+// so both views stay in sync for free. all of these are legacy registers:
+// the high byte ones (ah, bh, ch, dh) can not share an instruction with
+// a rex prefix, so anything using r8-r15 has to stay away from them.
+//
+// F is ah, but the flags themselves live in EFLAGS. ah is just where
+// lahf/sahf put them for a moment.
+
 pub fn g64(rr: RegPair) -> AsmRegister64 {
-    get_gpr64(rr.map()).unwrap()
+    get_gpr64(rr.map().full_register()).unwrap()
 }
 
 pub fn g16(rr: RegPair) -> AsmRegister16 {
-    use RegPair::*;
     get_gpr16(rr.map()).unwrap()
 }
 
@@ -24,6 +29,11 @@ pub fn g8(r: Reg) -> AsmRegister8 {
     get_gpr8(r.map()).unwrap()
 }
 
+/// the name the assembler knows it by, e.g. `bh`
+pub fn name(r: impl Into<Register>) -> String {
+    format!("{:?}", r.into()).to_lowercase()
+}
+
 trait Mapped {
     fn map(self) -> Amd64;
 }
@@ -32,8 +42,11 @@ impl Mapped for RegPair {
     fn map(self) -> Amd64 {
         use RegPair::*;
         match self {
+            AF => Amd64::AX,
+            BC => Amd64::BX,
+            DE => Amd64::CX,
+            HL => Amd64::DX,
             SP => Amd64::DI,
-            _ => unreachable!(),
         }
     }
 }
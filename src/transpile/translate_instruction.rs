@@ -1,43 +1,25 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
-// use iced_x86::code_asm::{
-//     byte_ptr, get_gpr16, get_gpr8, word_ptr, AsmRegister64,
-//     CodeAssembler,
-// };
-// use iced_x86::IcedError;
+use std::ops::Range;
 
+use iced_x86::code_asm::*;
+use iced_x86::IcedError;
+
+use crate::cartridge::BankedAddr;
 use crate::sm83;
-//use crate::sm83::*;
+use crate::sm83::info::FlagSet;
 
+use super::abi::{self, ExitReason, IME};
+use super::context::{AsmLabel, Emitter};
+use super::flags::{self, Flags, Loc};
+use super::helpers::{LINE_BITS, MAX_DEPTH};
 use super::mapping::{g16, g64, g8};
 
-// type Amd64 = iced_x86::Register;
-
-// enum TranspileResult {
-//     Success,
-//     OutputError(IcedError),
-//     InvalidInstr,
-//     Required,
-// }
-
-pub struct Amd64Instr {
-    instr: String,
-    dest_label: Option<String>,
-}
-
-impl Amd64Instr {
-    fn new(instr: String) -> Self {
-        Self {
-            instr,
-            dest_label: None,
-        }
-    }
-    fn with_label(instr: String, dest_label: String) -> Self {
-        let dest_label = Some(dest_label);
-        Self { instr, dest_label }
-    }
-}
+// z, h and c live in ZF, AF and CF unless flags.rs knows better, so
+// whatever an sm83 instruction leaves alone has to survive too: lea
+// instead of inc for addresses, lahf/sahf around the rest. r10 and r11
+// are scratch.
 
 pub enum TranspileInstrRes {
     Ok,
@@ -45,472 +27,802 @@ pub enum TranspileInstrRes {
         // condition for jump
         cond: sm83::Condition,
         // dest in sm83 space
-        dest: u16,
-        // the index of the output instruction to patch later
-        to_patch: usize,
+        dest: BankedAddr,
     },
     // same but no condition
     Jump {
-        dest: u16,
-        to_patch: usize,
+        dest: BankedAddr,
     },
     Lockup {
         // that's for stop, halt, invalid instructions
-        pc: u16,
+        pc: BankedAddr,
     },
 }
 
-pub fn transpile_instr_preserve_c_flag(
+/// an 8 bit operand: a register, or [hl]
+#[derive(Clone, Copy)]
+enum Op8 {
+    Reg(AsmRegister8),
+    Mem(AsmMemoryOperand),
+}
+
+/// `op8!(a.op(o, rest..))` for either kind of Op8
+macro_rules! op8 {
+    ($a:ident . $op:ident ( $o:expr $(, $rest:expr)* )) => {
+        match $o {
+            Op8::Reg(r) => $a.$op(r $(, $rest)*),
+            Op8::Mem(m) => $a.$op(m $(, $rest)*),
+        }
+    };
+}
+
+/// the x86 twin of an alu op, on al
+macro_rules! alu {
+    ($a:ident, $alu:expr, $src:expr) => {{
+        use sm83::AluBlockOp::*;
+        // sub/sbb/cmp borrow the same way as sm83
+        match $alu {
+            ADD => $a.add(al, $src),
+            ADC => $a.adc(al, $src),
+            SUB => $a.sub(al, $src),
+            SBC => $a.sbb(al, $src),
+            AND => $a.and(al, $src),
+            XOR => $a.xor(al, $src),
+            OR => $a.or(al, $src),
+            CP => $a.cmp(al, $src),
+        }
+    }};
+}
+
+/// jumps to `dest` if `c` holds, with the flags where `known` says
+fn cond_jump(
+    a: &mut CodeAssembler,
+    known: Flags,
+    c: sm83::Condition,
+    dest: AsmLabel,
+) -> Result<(), IcedError> {
+    use crate::sm83::Condition::*;
+    let (loc, want) = match c {
+        NZ => (known.z, false),
+        Z => (known.z, true),
+        NC => (known.c, false),
+        C => (known.c, true),
+    };
+    match loc {
+        Loc::Const(value) if value == want => a.jmp(dest),
+        Loc::Const(_) => Ok(()),
+        // z and c are ZF and CF
+        _ => match c {
+            NZ => a.jnz(dest),
+            Z => a.jz(dest),
+            NC => a.jnc(dest),
+            C => a.jc(dest),
+        },
+    }
+}
+
+/// the return address goes on the sm83 stack, high byte first
+fn push_imm(
+    a: &mut CodeAssembler,
+    mem: AsmRegister64,
+    addr: u16,
+) -> Result<(), IcedError> {
+    let [lo, hi] = addr.to_le_bytes();
+    a.lea(di, ptr(rdi - 1))?;
+    a.mov(byte_ptr(mem + rdi), hi as u32)?;
+    a.lea(di, ptr(rdi - 1))?;
+    a.mov(byte_ptr(mem + rdi), lo as u32)
+}
+
+/// where an instruction reads or writes memory
+#[derive(Clone, Copy)]
+enum MemAddr {
+    Const(u16),
+    // a register pair plus something
+    Pair(sm83::RegPair, i32),
+    // 0xff00 + c
+    HighC,
+}
+
+/// the addr in r10d, with EFLAGS gone
+fn mem_addr(a: &mut CodeAssembler, addr: MemAddr) -> Result<(), IcedError> {
+    match addr {
+        MemAddr::Const(addr) => a.mov(r10d, addr as u32),
+        MemAddr::Pair(rr, 0) => a.movzx(r10d, g16(rr)),
+        MemAddr::Pair(rr, offset) => {
+            a.lea(r10d, ptr(g64(rr) + offset))?;
+            a.movzx(r10d, r10w)
+        }
+        MemAddr::HighC => {
+            a.movzx(r10d, g8(sm83::Reg::C))?;
+            a.or(r10d, 0xff00)
+        }
+    }
+}
+
+/// where `instr` reads memory, as the registers are before it, pops
+/// aside
+fn loaded(instr: sm83::Instruction) -> Option<MemAddr> {
+    use sm83::Instruction::*;
+    use sm83::{Reg::HL_, RegOrNum, RegPair::*};
+    Some(match instr {
+        LD_r_r(_, HL_)
+        | Alu_A_RegOrNum(_, RegOrNum::Reg(HL_))
+        | INC_r(HL_)
+        | DEC_r(HL_)
+        | Prefix(_, HL_)
+        | LD_A_pHLi
+        | LD_A_pHLd => MemAddr::Pair(HL, 0),
+        LD_A_prr(rr) => MemAddr::Pair(rr, 0),
+        LD_A_pa16(a16) => MemAddr::Const(a16),
+        LDH_A_pa8(a8) => MemAddr::Const(0xff00 | a8 as u16),
+        LDH_A_pC => MemAddr::HighC,
+        _ => return None,
+    })
+}
+
+/// where `instr` stores to memory, as the registers are after it, pushes
+/// aside
+fn stored(instr: sm83::Instruction) -> Option<MemAddr> {
+    use sm83::Instruction::*;
+    use sm83::PrefixOp::BIT;
+    use sm83::{Reg::HL_, RegPair::*};
+    Some(match instr {
+        LD_r_r(HL_, _) | LD_r_d8(HL_, _) | INC_r(HL_) | DEC_r(HL_) => {
+            MemAddr::Pair(HL, 0)
+        }
+        Prefix(op, HL_) if !matches!(op, BIT(_)) => MemAddr::Pair(HL, 0),
+        LD_prr_A(rr) => MemAddr::Pair(rr, 0),
+        // hl has moved on by now
+        LD_pHLi_A => MemAddr::Pair(HL, -1),
+        LD_pHLd_A => MemAddr::Pair(HL, 1),
+        LD_pa16_A(a16) | LD_pa16_SP(a16) => MemAddr::Const(a16),
+        LDH_pa8_A(a8) => MemAddr::Const(0xff00 | a8 as u16),
+        LDH_pC_A => MemAddr::HighC,
+        _ => return None,
+    })
+}
+
+/// leaves with a CodeWrite exit to `next` if what `instr` just stored is
+/// in a line code_lines has a bit for
+fn check_write(
+    e: &mut Emitter,
     instr: sm83::Instruction,
-    mem_reg: &str,
+    next: u16,
+) -> Result<(), IcedError> {
+    let addr = match stored(instr) {
+        // the rom stays as it is
+        Some(MemAddr::Const(..0x8000)) if e.switch_banks => return Ok(()),
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+    let lines = e.helper("code_lines");
+    let ok = e.new_label();
+    // ld [a16], sp stores a byte more
+    let last = match instr {
+        sm83::Instruction::LD_pa16_SP(_) => 1,
+        _ => 0,
+    };
+    let a = &mut e.asm;
+    a.lahf()?;
+    mem_addr(a, addr)?;
+    if last != 0 {
+        a.lea(r11d, ptr(r10 + last))?;
+        a.movzx(r11d, r11w)?;
+        a.shr(r11d, LINE_BITS)?;
+    }
+    a.shr(r10d, LINE_BITS)?;
+    e.lea_label(r8, lines)?;
+    let a = &mut e.asm;
+    a.bt(dword_ptr(r8), r10d)?;
+    if last != 0 {
+        let hit = e.new_label();
+        e.asm.jc(hit)?;
+        e.asm.bt(dword_ptr(r8), r11d)?;
+        e.asm.jnc(ok)?;
+        e.place(hit)?;
+    } else {
+        a.jnc(ok)?;
+    }
+    // the runtime wants the flags in their places, the rest of the block
+    // still knows where they are
+    e.asm.sahf()?;
+    let known = e.flags;
+    flags::sync(e)?;
+    e.flags = known;
+    abi::exit(e, next, ExitReason::CodeWrite)?;
+    e.place(ok)?;
+    e.asm.sahf()
+}
+
+/// calls the `hook` helper if `addr` is in one of `trapped`: right away
+/// if it is known to be, after a check if it may be
+fn bus_access(
+    e: &mut Emitter,
+    addr: MemAddr,
+    hook: &'static str,
+    trapped: &[Range<u16>],
+) -> Result<(), IcedError> {
+    let proven = match addr {
+        MemAddr::Const(addr) if trapped.iter().any(|r| r.contains(&addr)) => {
+            true
+        }
+        MemAddr::Const(_) => return Ok(()),
+        _ if trapped.is_empty() => return Ok(()),
+        _ => false,
+    };
+    let hook = e.helper(hook);
+    let hit = e.new_label();
+    let skip = e.new_label();
+    let a = &mut e.asm;
+    a.lahf()?;
+    mem_addr(a, addr)?;
+    if !proven {
+        for range in trapped {
+            a.lea(r11d, ptr(r10 - range.start as i32))?;
+            a.cmp(r11d, range.len() as u32)?;
+            a.jb(hit)?;
+        }
+        a.jmp(skip)?;
+    }
+    e.place(hit)?;
+    e.asm.call(hook)?;
+    e.place(skip)?;
+    e.asm.sahf()
+}
+
+/// whether `addr` is in the switchable bank
+fn switchable(addr: u16) -> bool {
+    (0x4000..0x8000).contains(&addr)
+}
+
+/// r11d = the bank in 0x4000..0x8000, as the code at `pc` sees it: its
+/// own, or with switch_banks and `pc` elsewhere, what is mapped right
+/// then. EFLAGS stay.
+fn mapped_bank(e: &mut Emitter, pc: BankedAddr) -> Result<(), IcedError> {
+    if e.switch_banks && !switchable(pc.addr) {
+        bus_rom_bank(&mut e.asm)
+    } else {
+        e.asm.mov(r11d, pc.to(0x4000).bank as u32)
+    }
+}
+
+/// r11d = BusHooks::rom_bank, the bank mapped right now. EFLAGS stay.
+fn bus_rom_bank(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    let rom_bank = std::mem::offset_of!(abi::BusHooks, rom_bank);
+    a.mov(r11, qword_ptr(rbp + abi::BUS_ARG))?;
+    a.movzx(r11d, word_ptr(r11 + rom_bank))
+}
+
+/// code in the switchable bank that may just have switched it goes on
+/// at `next` in the bank mapped now, through the dispatcher, unless that
+/// is still its own
+fn check_bank(
+    e: &mut Emitter,
+    pc: BankedAddr,
+    next: u16,
+) -> Result<(), IcedError> {
+    let same = e.new_label();
+    let dispatch = e.helper("dispatch");
+    let a = &mut e.asm;
+    a.lahf()?;
+    bus_rom_bank(a)?;
+    a.cmp(r11d, pc.bank as u32)?;
+    a.je(same)?;
+    a.sahf()?;
+    // like any other way into the dispatcher, with the flags in their
+    // places. the rest of the block still knows where they are.
+    let known = e.flags;
+    flags::sync(e)?;
+    e.flags = known;
+    e.asm.mov(r10d, next as u32)?;
+    e.asm.jmp(dispatch)?;
+    e.place(same)?;
+    e.asm.sahf()
+}
+
+/// leaves with a CodeWrite exit to `next` if the store just mapped
+/// another bank at 0x0000..0x4000, for the runtime to compile what is
+/// there now
+fn check_rom0(e: &mut Emitter, next: u16) -> Result<(), IcedError> {
+    let switched = std::mem::offset_of!(abi::BusHooks, rom0_switched);
+    let same = e.new_label();
+    let a = &mut e.asm;
+    a.lahf()?;
+    a.mov(r11, qword_ptr(rbp + abi::BUS_ARG))?;
+    a.cmp(byte_ptr(r11 + switched), 0)?;
+    a.je(same)?;
+    a.mov(byte_ptr(r11 + switched), 0)?;
+    a.sahf()?;
+    let known = e.flags;
+    flags::sync(e)?;
+    e.flags = known;
+    abi::exit(e, next, ExitReason::CodeWrite)?;
+    e.place(same)?;
+    e.asm.sahf()
+}
+
+/// a host call to `dest`, with `ret` both on the sm83 stack and under the
+/// host return address, where ret looks for it. with the host stack too
+/// deep it leaves to the runtime at `pc` instead. the flags are in their
+/// places.
+fn call(
+    e: &mut Emitter,
+    dest: AsmLabel,
     pc: u16,
-) -> (Vec<Amd64Instr>, TranspileInstrRes) {
+    ret: u16,
+) -> Result<(), IcedError> {
+    let ok = e.new_label();
+    let a = &mut e.asm;
+    a.lahf()?;
+    a.lea(r8, ptr(rbp - MAX_DEPTH))?;
+    a.cmp(rsp, r8)?;
+    a.jae(ok)?;
+    a.sahf()?;
+    abi::exit(e, pc, ExitReason::Jump)?;
+    e.place(ok)?;
+    let a = &mut e.asm;
+    a.sahf()?;
+    push_imm(a, e.mem, ret)?;
+    a.push(ret as i32)?;
+    a.call(dest)?;
+    // back here, through a ret that found `ret` where it should be
+    a.lea(rsp, ptr(rsp + 8))
+}
+
+/// pops the return address and goes there: by a host ret if the call that
+/// pushed it is on top of the host stack, else through the dispatcher.
+/// the flags are in their places.
+fn ret(e: &mut Emitter, pc: BankedAddr, reti: bool) -> Result<(), IcedError> {
+    let mem = e.mem;
+    let slow = e.new_label();
+    let dispatch = e.helper("dispatch");
+    let a = &mut e.asm;
+    a.lahf()?;
+    a.movzx(r10d, byte_ptr(mem + rdi))?;
+    a.lea(di, ptr(rdi + 1))?;
+    a.movzx(r11d, byte_ptr(mem + rdi))?;
+    a.lea(di, ptr(rdi + 1))?;
+    a.shl(r11d, 8)?;
+    a.or(r10d, r11d)?;
+    if reti {
+        a.movzx(r9d, flags::SAVED)?;
+        a.or(r9d, IME)?;
+    }
+    a.cmp(r10d, dword_ptr(rsp + 8))?;
+    a.jne(slow)?;
+    a.sahf()?;
+    a.ret()?;
+    e.place(slow)?;
+    let a = &mut e.asm;
+    a.sahf()?;
+    mapped_bank(e, pc)?;
+    e.asm.jmp(dispatch)
+}
+
+/// `live` are the flags something reads after `instr`, before they are
+/// set again
+pub fn transpile_instr_preserve_c_flag(
+    e: &mut Emitter,
+    instr: sm83::Instruction,
+    pc: BankedAddr,
+    live: FlagSet,
+) -> Result<TranspileInstrRes, IcedError> {
     use sm83::Instruction::*;
     use sm83::{Reg::*, RegPair::*};
+    use Loc::{Const, Host};
+
+    // jr is jp with the target relative to the next instr, and rst a
+    // call. next is from before that, rst is a byte long.
+    let next = pc.addr.wrapping_add(instr.len());
+    let instr = match instr.flow(pc.addr) {
+        sm83::Flow::Jump(dest) if matches!(instr, JR_r8(_)) => JP_a16(dest),
+        sm83::Flow::Branch(c, dest) if matches!(instr, JR_c_r8(..)) => {
+            JP_c_a16(c, dest)
+        }
+        sm83::Flow::Call(dest) if matches!(instr, RST_vector(_)) => {
+            CALL_a16(dest)
+        }
+        _ => instr,
+    };
+
+    // the flags go to their places before anything leaves the block, but
+    // what is known about them still decides the conditions
+    let known = e.flags;
+    if instr.flow(pc.addr).ends_block() {
+        flags::sync(e)?;
+    }
 
-    fn single(s: String) -> Vec<Amd64Instr> {
-        vec![Amd64Instr::new(s)]
+    let mem = e.mem;
+    let stack = g64(SP);
+    // a register, or [hl]
+    let op = |r: sm83::Reg| match r {
+        HL_ => Op8::Mem(byte_ptr(mem + g64(HL))),
+        _ => Op8::Reg(g8(r)),
+    };
+
+    // the read callback gets to change what is read first
+    if let (true, Some(addr)) = (e.trap_io, loaded(instr)) {
+        bus_access(e, addr, "bus_read", &[abi::IO])?;
     }
 
+    // into a bank that is only known when it runs, by the dispatcher
+    let switch_banks = e.switch_banks;
+    let by_bank = |a16| switch_banks && !switchable(pc.addr) && switchable(a16);
     let res: TranspileInstrRes = match instr {
-        JP_c_a16(c, a16) => TranspileInstrRes::Branch {
-            cond: c,
-            dest: a16,
-            to_patch: 0,
-        },
-        CALL_c_a16(c, a16) => TranspileInstrRes::Branch {
+        JP_a16(a16) | CALL_a16(a16) | JP_c_a16(_, a16) | CALL_c_a16(_, a16)
+            if by_bank(a16) =>
+        {
+            TranspileInstrRes::Ok
+        }
+        JP_a16(a16) | CALL_a16(a16) => {
+            TranspileInstrRes::Jump { dest: pc.to(a16) }
+        }
+        JP_c_a16(c, a16) | CALL_c_a16(c, a16) => TranspileInstrRes::Branch {
             cond: c,
-            dest: a16,
-            to_patch: 4,
+            dest: pc.to(a16),
         },
-        Invalid | HALT | STOP(_) => TranspileInstrRes::Lockup { pc },
+        Invalid(_) | HALT | STOP(_) => TranspileInstrRes::Lockup { pc },
         _ => TranspileInstrRes::Ok,
     };
 
-    let instrs = match instr {
-        DI | EI | NOP => vec![], // basically nops
-        LD_pa16_SP(addr) => {
-            single(format!(
-                "mov  WORD PTR [{mem_reg} + {:#?}], {:#?}",
-                addr,
-                g16(SP)
-            ))
-            // asm.mov(word_ptr(mem_reg + addr as u32), g16(SP))?
-        }
-        Invalid | HALT | STOP(_) => vec![], // but the result is a Lockup
+    let a = &mut e.asm;
+    match instr {
+        NOP => {}
+        // ime is bit 8 of r9, above the saved flags. lea leaves EFLAGS.
+        DI => a.movzx(r9d, flags::SAVED)?,
+        EI => {
+            a.movzx(r9d, flags::SAVED)?;
+            a.lea(r9d, ptr(r9 + IME))?;
+        }
+        LD_pa16_SP(0xffff) => {
+            // the high byte wraps around to 0, so one at a time, through
+            // the red zone
+            a.mov(word_ptr(rsp - 2), g16(SP))?;
+            a.mov(r10b, byte_ptr(rsp - 2))?;
+            a.mov(byte_ptr(mem + 0xffff), r10b)?;
+            a.mov(r10b, byte_ptr(rsp - 1))?;
+            a.mov(byte_ptr(mem), r10b)?;
+        }
+        LD_pa16_SP(addr) => a.mov(word_ptr(mem + addr as i32), g16(SP))?,
+        Invalid(_) | HALT | STOP(_) => {} // but the result is a Lockup
 
-        LD_rr_d16(rr, d16) => {
-            single(format!("mov {:#?}, {d16}", g16(rr)))
-            // asm.mov(g16(rr), d16 as u32)?;
+        LD_rr_d16(rr, d16) => a.mov(g16(rr), d16 as u32)?,
+        ADD_HL_rr(rr) if !e.accurate_flags && !live.contains(FlagSet::H) => {
+            // nothing wants h, so it is whatever AF was. z stays, the new
+            // CF goes into bit 0 of the saved flags.
+            a.lahf()?;
+            a.add(g16(HL), g16(rr))?;
+            a.rcr(ah, 1)?;
+            a.rol(ah, 1)?;
+            a.sahf()?;
+            e.flags = Flags {
+                n: Const(false),
+                h: Host,
+                c: Host,
+                ..known
+            };
         }
         ADD_HL_rr(rr) => {
-            single(format!("add {:#?}, {:#?}", g16(HL), g16(rr)))
-            //asm.add(g16(HL), g16(rr))?,
-        }
-        LD_prr_A(rr) => {
-            single(format!(
-                "mov BYTE PTR [{:#?}], {:#?}",
-                g16(rr),
-                g8(A)
-            ))
-            // asm.mov(ptr(rr), g8(A))?,
-        }
-        LD_A_prr(rr) => {
-            single(format!(
-                "mov {:#?}, BYTE PTR [{:#?}]",
-                g8(A),
-                g64(rr)
-            ))
-            // asm.mov(g8(A), ptr(rr))?,
-        }
-        LD_pHLi_A => {
-            [
-                format!("mov BYTE PTR [{:#?}], {:#?}", g64(HL), g8(A)),
-                format!("inc {:#?}", g16(HL)),
-            ]
-            .map(Amd64Instr::new)
-            .into()
-
-            // asm.mov(ptr(HL), g8(A))?;
-            // how do I increase HL without changing the flags?
-            // if g16(HL) == get_gpr16(Amd64::SI).unwrap()
-            //     && g8(A) == get_gpr8(Amd64::AL).unwrap()
-            // {
-            //     ret.extend(vec![
-            //         Amd64Instr::new("cld".into()),
-            //         Amd64Instr::new("lodsb".into()),
-            //     ]);
-            //     // asm.cld()?;
-            //     // asm.lodsb()?;
-            // }
-        }
-        LD_A_pHLi => {
-            [
-                format!("mov {:#?}, BYTE PTR [{:#?}]", g8(A), g64(HL)),
-                format!("inc {:#?}", g16(HL)),
-            ]
-            .map(Amd64Instr::new)
-            .into()
-            //beep
-        }
-        LD_pHLd_A => {
-            let ret = [
-                format!("mov BYTE PTR [{:#?}], {:#?}", g64(HL), g8(A)),
-                format!("dec {:#?}", g16(HL)),
-            ]
-            .map(Amd64Instr::new)
-            .into();
-
-            ret
-        }
-        LD_A_pHLd => {
-            [
-                format!("mov {:#?}, BYTE PTR [{:#?}]", g8(A), g64(HL)),
-                format!("dec {:#?}", g16(HL)),
-            ]
-            .map(Amd64Instr::new)
-            .into()
-            //beep
-        }
-
-        INC_rr(rr) => single(format!("inc {:#?}", g16(rr))),
-        DEC_rr(rr) => single(format!("dec {:#?}", g16(rr))),
-
-        INC_r(r) => single(format!("inc {:#?}", g8(r))),
-        DEC_r(r) => single(format!("dec {:#?}", g8(r))),
-        LD_r_d8(r, d8) => single(format!("mov {:#?} {d8}", g8(r))),
+            // a byte at a time, so AF is the carry out of bit 11. z stays,
+            // from the flags saved in the red zone.
+            a.lahf()?;
+            a.mov(byte_ptr(rsp - 8), ah)?;
+            a.mov(word_ptr(rsp - 6), g16(rr))?;
+            a.add(g8(L), byte_ptr(rsp - 6))?;
+            a.adc(g8(H), byte_ptr(rsp - 5))?;
+            a.lahf()?;
+            a.and(ah, 0x11)?;
+            a.and(byte_ptr(rsp - 8), 0xee)?;
+            a.or(ah, byte_ptr(rsp - 8))?;
+            a.sahf()?;
+            e.flags = Flags {
+                n: Const(false),
+                h: Host,
+                c: Host,
+                ..known
+            };
+        }
+        LD_prr_A(rr) => a.mov(byte_ptr(mem + g64(rr)), al)?,
+        LD_A_prr(rr) => a.mov(al, byte_ptr(mem + g64(rr)))?,
+        LD_pHLi_A | LD_pHLd_A | LD_A_pHLi | LD_A_pHLd => {
+            let hl = byte_ptr(mem + g64(HL));
+            match instr {
+                LD_pHLi_A | LD_pHLd_A => a.mov(hl, al)?,
+                _ => a.mov(al, hl)?,
+            }
+            let step = match instr {
+                LD_pHLi_A | LD_A_pHLi => 1,
+                _ => -1,
+            };
+            // lea, to not touch the flags
+            a.lea(g16(HL), ptr(g64(HL) + step))?;
+        }
+
+        INC_rr(rr) => a.lea(g16(rr), ptr(g64(rr) + 1))?,
+        DEC_rr(rr) => a.lea(g16(rr), ptr(g64(rr) - 1))?,
+
+        // inc and dec leave CF alone on both
+        INC_r(r) | DEC_r(r) => {
+            match instr {
+                INC_r(_) => op8!(a.inc(op(r)))?,
+                _ => op8!(a.dec(op(r)))?,
+            }
+            e.flags = Flags {
+                z: Host,
+                n: Const(matches!(instr, DEC_r(_))),
+                h: Host,
+                ..known
+            };
+        }
+        LD_r_d8(r, d8) => op8!(a.mov(op(r), d8 as u32))?,
 
         RLCA | RRCA | RLA | RRA => {
-            let op: sm83::Instruction = match instr {
-                RLCA => Prefix(sm83::PrefixOp::RLC, A),
-                RRCA => Prefix(sm83::PrefixOp::RRC, A),
-                RLA => Prefix(sm83::PrefixOp::RL, A),
-                RRA => Prefix(sm83::PrefixOp::RR, A),
-                _ => unreachable!(),
+            match instr {
+                RLCA => a.rol(al, 1)?,
+                RRCA => a.ror(al, 1)?,
+                RLA => a.rcl(al, 1)?,
+                _ => a.rcr(al, 1)?,
+            }
+            // the a-only rotates always reset z
+            e.flags = Flags {
+                z: Const(false),
+                n: Const(false),
+                h: Const(false),
+                c: Host,
             };
-            return transpile_instr_preserve_c_flag(
-                op,
-                mem_reg,
-                pc - 1,
-            );
         }
         DAA => {
-            single("call daa".into())
-            /* branchless DAA implementation by ax6 : (A, F) in (al, dil)
-              mov r10b, dil
-              test dil, 0x20
-              setnz sil
-              and r10, 0x10
-              add r10b, sil
-              lea r10, [r10 + r10 * 2]
-              lea rsi, [r10 + r10]
-              and edi, 0x60
-              mov r10, rsi
-              neg r10
-              test dil, 0x40
-              cmovnz rsi, r10
-              cmovz r10, rdi
-              and r10, 0xf
-              sub rsi, r10
-              add al, r10b
-              add al, sil
-              setz r10b
-              setc sil
-              lea rsi, [rsi + r10 * 8]
-              test dil, 0x40
-              setnz r10b
-              xor rsi, r10
-              shl esi, 4
-              or dil, sil
-            */
-        }
-        CPL => single(format!("not {:#?}", g8(A))),
-        SCF => single("stc".into()),
-        CCF => single("cmc".into()),
-
-        LD_r_r(r1, r2) => {
-            single(format!("mov {:#?}, {:#?}", g8(r1), g8(r2)))
-        }
-        // asm.mov(g8(r1), g8(r2))?,
-        Alu_A_RegOrNum(op, operand) => {
-            use sm83::{AluBlockOp::*, RegOrNum};
-            let a = g8(A);
-            match (op, operand) {
-                (ADD, RegOrNum::Reg(r)) => vec![Amd64Instr::new(
-		    format!("add {a:#?}, {:#?}", 
-			    g8(r)))],
-		// asm.add(a, g8(r))?,
-		_ => todo!()
-                // (ADC, RegOrNum::Reg(r)) => asm.adc(a, g8(r))?,
-                // (SUB, RegOrNum::Reg(r)) => asm.sub(a, g8(r))?,
-                // (SBC, RegOrNum::Reg(r)) => asm.sbb(a, g8(r))?,
-                // (AND, RegOrNum::Reg(r)) => asm.and(a, g8(r))?,
-                // (XOR, RegOrNum::Reg(r)) => asm.xor(a, g8(r))?,
-                // (OR, RegOrNum::Reg(r)) => asm.or(a, g8(r))?,
-                // (CP, RegOrNum::Reg(r)) => asm.cmp(a, g8(r))?,
-
-                // (ADD, RegOrNum::Num(d8)) => asm.add(a, d8 as u32)?,
-                // (ADC, RegOrNum::Num(d8)) => asm.adc(a, d8 as u32)?,
-                // (SUB, RegOrNum::Num(d8)) => asm.sub(a, d8 as u32)?,
-                // (SBC, RegOrNum::Num(d8)) => asm.sbb(a, d8 as u32)?,
-                // (AND, RegOrNum::Num(d8)) => asm.and(a, d8 as u32)?,
-                // (XOR, RegOrNum::Num(d8)) => asm.xor(a, d8 as u32)?,
-                // (OR, RegOrNum::Num(d8)) => asm.or(a, d8 as u32)?,
-                // (CP, RegOrNum::Num(d8)) => asm.cmp(a, d8 as u32)?,
+            // the helper wants n, h and c in their places
+            flags::sync(e)?;
+            let daa = e.helper("daa");
+            e.asm.call(daa)?;
+            e.flags = Flags {
+                z: Host,
+                h: Const(false),
+                c: Host,
+                ..Flags::CANONICAL
+            };
+        }
+        CPL => {
+            a.not(al)?;
+            e.flags.set_nh(true, true);
+        }
+        SCF | CCF => {
+            match instr {
+                SCF => a.stc()?,
+                _ => a.cmc()?,
             }
+            e.flags.set_nh(false, false);
+            e.flags.c = Host;
         }
-        RET_c(_) => {
-            todo!(); // how tf do we return?!
-                     // need a bigger rework for this...
-                     // option 1 would be returning to the runtime
-        }
-        LDH_pa8_A(a8) => {
-            vec![Amd64Instr::new(format!(
-                "mov BYTE PTR [{mem_reg} + {}], {:#?}",
-                a8 as u16 + 0xff00,
-                g8(A)
-            ))]
-            // asm.mov(byte_ptr(mem_reg + 0xff00 + a8 as u32), g8(A))?
-        }
-        LDH_A_pa8(a8) => {
-            single(format!(
-                "mov {:#?}, BYTE PTR [{mem_reg} + {}]",
-                g8(A),
-                a8 as u16 + 0xff00,
-            ))
-            // asm.mov(g8(A), byte_ptr(mem_reg + 0xff00 + a8 as u32))?
-        }
-        ADD_SP_r8(rel8) => {
-            single(format!("add {:#?}, {rel8}", g16(SP)))
-        }
-        LD_HL_SP_r8(rel8) => {
-            single(format!(
-                "lea {:#?}, {:#?} + {rel8}",
-                g64(HL),
-                g64(SP)
-            ))
-            // asm.lea(g64(HL), word_ptr(g64(SP) + rel8))?
+
+        LD_r_r(r1, r2) => match (op(r1), op(r2)) {
+            (Op8::Reg(r1), Op8::Reg(r2)) => a.mov(r1, r2)?,
+            (Op8::Reg(r1), Op8::Mem(m)) => a.mov(r1, m)?,
+            (Op8::Mem(m), Op8::Reg(r2)) => a.mov(m, r2)?,
+            (Op8::Mem(_), Op8::Mem(_)) => unreachable!("that is halt"),
+        },
+        Alu_A_RegOrNum(alu, operand) => {
+            match operand {
+                sm83::RegOrNum::Reg(r) => match op(r) {
+                    Op8::Reg(r) => alu!(a, alu, r)?,
+                    Op8::Mem(m) => alu!(a, alu, m)?,
+                },
+                sm83::RegOrNum::Num(d8) => alu!(a, alu, d8 as u32)?,
+            }
+            use sm83::AluBlockOp::*;
+            // AF is the half carry of the arithmetic ones, but undefined
+            // after the logic ones
+            let h = match alu {
+                AND => Const(true),
+                XOR | OR => Const(false),
+                _ => Host,
+            };
+            e.flags = Flags {
+                z: Host,
+                n: Const(matches!(alu, SUB | SBC | CP)),
+                h,
+                c: Host,
+            };
+        }
+        RET_c(c) => {
+            let skip_ret = e.new_label();
+            cond_jump(&mut e.asm, known, c.not(), skip_ret)?;
+            ret(e, pc, false)?;
+            e.place(skip_ret)?;
+        }
+        LDH_pa8_A(a8) => a.mov(byte_ptr(mem + (0xff00 | a8 as i32)), al)?,
+        LDH_A_pa8(a8) => a.mov(al, byte_ptr(mem + (0xff00 | a8 as i32)))?,
+        ADD_SP_r8(rel8) | LD_HL_SP_r8(rel8) => {
+            // z = 0, h and c from the low byte, as if unsigned
+            a.movzx(r10d, g16(SP))?;
+            a.add(r10b, rel8 as u8 as u32)?;
+            e.flags = Flags {
+                z: Const(false),
+                n: Const(false),
+                h: Host,
+                c: Host,
+            };
+            let dest = match instr {
+                ADD_SP_r8(_) => g16(SP),
+                _ => g16(HL),
+            };
+            a.lea(dest, ptr(stack + rel8 as i32))?;
+        }
+        POP_rr(AF) => {
+            a.mov(r10b, byte_ptr(mem + stack))?;
+            a.lea(g16(SP), ptr(stack + 1))?;
+            a.mov(al, byte_ptr(mem + stack))?;
+            a.lea(g16(SP), ptr(stack + 1))?;
+            flags::unpack(e)?;
         }
         POP_rr(rr) => {
-            // if rr is mapped... just do the obvious thing.
-            // since both x86 and sm83 are little endian...
-            let instrs = [
-                format!(
-                    "mov {:#?}, WORD PTR [{:#?}]",
-                    g16(rr),
-                    g64(SP)
-                ),
-                format!("inc {:#?}", g16(SP)),
-                format!("inc {:#?}", g16(SP)),
-            ]
-            .map(Amd64Instr::new)
-            .into();
-            // asm.mov(g16(rr), word_ptr(g64(SP)))?;
-            // asm.inc(g16(SP))?;
-            // asm.inc(g16(SP))?; // to not hurt the carry by accident
-
-            if rr == AF {
-                // extract flags
-                todo!()
-            }
-            instrs
+            // one byte at a time, sp can wrap in between
+            let (hi, lo) = rr.parts().unwrap();
+            a.mov(g8(lo), byte_ptr(mem + stack))?;
+            a.lea(g16(SP), ptr(stack + 1))?;
+            a.mov(g8(hi), byte_ptr(mem + stack))?;
+            a.lea(g16(SP), ptr(stack + 1))?;
+        }
+        PUSH_rr(AF) => {
+            flags::pack(e, known)?;
+            let a = &mut e.asm;
+            a.lea(g16(SP), ptr(stack - 1))?;
+            a.mov(byte_ptr(mem + stack), al)?;
+            a.lea(g16(SP), ptr(stack - 1))?;
+            a.mov(byte_ptr(mem + stack), r10b)?;
         }
         PUSH_rr(rr) => {
-            let mut instrs = vec![];
-            if rr == AF {
-                // encode flags
-                todo!()
-            }
-
-            // if rr is mapped... just do the obvious thing.
-            // since both x86 and sm83 are little endian...
-            instrs.extend(
-                [
-                    format!("dec {:#?}", g16(SP)), // need to wrap at 1<<16
-                    format!("dec {:#?}", g16(SP)), // so cant lea
-                    format!(
-                        "mov WORD PTR [{:#?}], {:#?}",
-                        g64(SP),
-                        g16(rr)
-                    ),
-                ]
-                .map(Amd64Instr::new)
-                .into_iter(),
-            );
-            instrs
-            // asm.dec(g16(SP))?;
-            // asm.dec(g16(SP))?; // to not hurt the carry by accident
-            // asm.mov(word_ptr(g64(SP)), g16(rr))?;
-        }
-        RET | RETI => {
-            // since we are not emulating interrupts...
-            todo!() // how do we return ffs
+            let (hi, lo) = rr.parts().unwrap();
+            a.lea(g16(SP), ptr(stack - 1))?;
+            a.mov(byte_ptr(mem + stack), g8(hi))?;
+            a.lea(g16(SP), ptr(stack - 1))?;
+            a.mov(byte_ptr(mem + stack), g8(lo))?;
         }
+        RET | RETI => ret(e, pc, instr == RETI)?,
         JP_HL => {
-            // how do we do jumps to unknown locations?
-            todo!()
-        }
-        LD_SP_HL => {
-            single(format!("mov {:#?}, {:#?}", SP, HL))
-            //asm.mov(g16(SP), g16(HL))?,
-        }
-        LDH_pC_A => single(format!(
-            "mov BYTE PTR [{mem_reg}+{:#?} + $ff00], {:#?}",
-            g8(C),
-            g8(A)
-        )),
-        LDH_A_pC => single(format!(
-            "mov {:#?}, BYTE PTR [{mem_reg}+{:#?} + $ff00]",
-            g8(A),
-            g8(C)
-        )),
-        LD_pa16_A(a16) => {
-            single(format!(
-                "lea BYTE PTR [{mem_reg} + {a16}], {:#?}",
-                g8(A)
-            ))
-            // asm.mov(byte_ptr(mem_reg + a16 as u32), g8(A))?;
-        }
-        LD_A_pa16(a16) => {
-            single(format!(
-                "mov {:#?}, BYTE PTR [{mem_reg} + {a16}]",
-                g8(A),
-            ))
-            // asm.mov(g8(A), byte_ptr(mem_reg + a16 as u32))?;
-        }
-        Prefix(op, r1) => {
-            use sm83::instructions::PrefixOp::*;
-            fn bit_op(op: &str, r1: crate::Reg) -> Vec<Amd64Instr> {
-                single(format!("{op} {:#?}, 1", r1))
+            // jump tables and such, whatever is compiled
+            let dispatch = e.helper("dispatch");
+            e.asm.movzx(r10d, g16(HL))?;
+            mapped_bank(e, pc)?;
+            e.asm.jmp(dispatch)?;
+        }
+        LD_SP_HL => a.mov(g16(SP), g16(HL))?,
+        LDH_pC_A | LDH_A_pC => {
+            a.movzx(r10d, g8(C))?;
+            let ptr = byte_ptr(mem + r10 + 0xff00);
+            match instr {
+                LDH_pC_A => a.mov(ptr, al)?,
+                _ => a.mov(al, ptr)?,
             }
-            match op {
-                RLC => bit_op("rol", r1),
-                RRC => bit_op("ror", r1),
-                RL => bit_op("rcl", r1),
-                RR => bit_op("rcr", r1),
-
-                SLA => bit_op("sal", r1),
-                SRA => bit_op("sar", r1),
-                SWAP => [format!("rol {:#?}, 4", g8(r1)), "clc".into()]
-                    .map(Amd64Instr::new)
-                    .into(),
-                SRL => bit_op("shr", r1),
-
-                BIT(_u3) => {
-                    todo!();
+        }
+        LD_pa16_A(a16) => a.mov(byte_ptr(mem + a16 as i32), al)?,
+        LD_A_pa16(a16) => a.mov(al, byte_ptr(mem + a16 as i32))?,
+        Prefix(prefix_op, r1) => {
+            use sm83::instructions::PrefixOp::*;
+            let o = op(r1);
+            match prefix_op {
+                // the rotates set CF, but not ZF
+                RLC | RRC | RL | RR => {
+                    match prefix_op {
+                        RLC => op8!(a.rol(o, 1))?,
+                        RRC => op8!(a.ror(o, 1))?,
+                        RL => op8!(a.rcl(o, 1))?,
+                        _ => op8!(a.rcr(o, 1))?,
+                    }
+                    // z from the result, keeping CF. bt leaves ZF alone.
+                    a.setc(r10b)?;
+                    op8!(a.cmp(o, 0))?;
+                    a.bt(r10d, 0)?;
+                    e.flags = Flags {
+                        z: Host,
+                        n: Const(false),
+                        h: Const(false),
+                        c: Host,
+                    };
+                }
+
+                // the shifts set both. cmp resets CF for swap.
+                SLA | SRA | SRL | SWAP => {
+                    match prefix_op {
+                        SLA => op8!(a.shl(o, 1))?,
+                        SRA => op8!(a.sar(o, 1))?,
+                        SRL => op8!(a.shr(o, 1))?,
+                        _ => {
+                            op8!(a.rol(o, 4))?;
+                            op8!(a.cmp(o, 0))?;
+                        }
+                    }
+                    e.flags = Flags {
+                        z: Host,
+                        n: Const(false),
+                        h: Const(false),
+                        c: Host,
+                    };
+                }
+
+                BIT(u3) => {
                     //      Z       Set if the selected bit is 0.
                     //      C       Preserved.
-
-                    [
-                        // save carry in lowest bit of F
-                        format!("rcl {:#?}, 1", g8(F)),
-                        // test with test; bt uses carry instead of zero
-                        format!("test {:#?}, {}", r1, 1 << _u3),
-                        // now zf set if bit was 0. restore cf.
-                        format!("btr {:#?}, {_u3}", g8(F)),
-                        // WHAT ABOUT THE LEFT DRIFT HUH
-                    ]
-                    .map(Amd64Instr::new)
-                    .into()
+                    a.setc(r10b)?;
+                    op8!(a.test(o, 1u32 << u3))?;
+                    a.bt(r10d, 0)?;
+                    e.flags = Flags {
+                        z: Host,
+                        n: Const(false),
+                        h: Const(true),
+                        ..known
+                    };
                 }
                 RES(u3) => {
-                    [
-                        // save carry in lowest bit of F
-                        format!("rcl {:#?}, 1", g8(F)),
-                        // reset the bit
-                        format!("and {:#?}, {}", r1, !(1u8 << u3)),
-                        // restore cf.
-                        format!("ror {:#?}, 1", g8(F)),
-                    ]
-                    .map(Amd64Instr::new)
-                    .into()
+                    a.lahf()?;
+                    op8!(a.and(o, !(1u8 << u3) as u32))?;
+                    a.sahf()?;
                 }
                 SET(u3) => {
-                    [
-                        // save carry in lowest bit of F
-                        format!("rcl {:#?}, 1", g8(F)),
-                        // reset the bit
-                        format!("or {:#?}, {}", r1, 1u8 << u3),
-                        // restore cf.
-                        format!("ror {:#?}, 1", g8(F)),
-                    ]
-                    .map(Amd64Instr::new)
-                    .into()
+                    a.lahf()?;
+                    op8!(a.or(o, 1u32 << u3))?;
+                    a.sahf()?;
                 }
             }
         }
-        JR_r8(r8) => single(format!(
-            "jmp .sm83_{:02x}",
-            pc.wrapping_add_signed(r8 as i16)
-        )),
-        JR_c_r8(c, r8) => {
-            return transpile_instr_preserve_c_flag(
-                JP_c_a16(c, pc.wrapping_add_signed(r8 as i16)),
-                mem_reg,
-                pc,
-            )
-        }
-
-        JP_a16(a16) => single(format!("jmp .sm83_{a16:02x}")),
-        JP_c_a16(c, a16) => {
-            let op: &str = transpile_cond_jump(c);
-            single(format!("{op} .sm83_{a16:02x}"))
-        }
-        CALL_c_a16(c, a16) => {
-            // skip call if condition does not hold
-            let op: &str = transpile_cond_jump(c.not());
-            [
-                // the cond jump part
-                format!("{op} .after_call_{a16:02x}"),
-                // the call part
-                format!("dec {:#?}", g16(SP)), // need to wrap at 1<<16
-                format!("dec {:#?}", g16(SP)), // so cant lea
-                // push the address of next instruction, 3 bytes from here
-                // the jump part
-                format!("mov WORD PTR [{:#?}], {:#?}", g64(SP), pc + 3),
-                format!("jmp .sm83_{a16:02x}"),
-                // label for skipping
-                format!(".after_call_{a16:02x}:"),
-            ]
-            .map(Amd64Instr::new)
-            .into()
-        }
-        CALL_a16(a16) => {
-            [
-                // the call part
-                format!("dec {:#?}", g16(SP)), // need to wrap at 1<<16
-                format!("dec {:#?}", g16(SP)), // so cant lea
-                // push the address of next instruction, 3 bytes from here
-                // the jump part
-                format!("mov WORD PTR [{:#?}], {:#?}", g64(SP), pc + 3),
-                format!("jmp .sm83_{a16:02x}"),
-            ]
-            .map(Amd64Instr::new)
-            .into()
-        }
-        RST_vector(vec) => {
-            return transpile_instr_preserve_c_flag(
-                CALL_a16(vec as u16),
-                mem_reg,
-                pc - 2, // fix addr calc
-            );
+        JR_r8(_) | JR_c_r8(..) => unreachable!("that is jp by now"),
+
+        JP_a16(a16) | JP_c_a16(_, a16) | CALL_a16(a16) | CALL_c_a16(_, a16) => {
+            let skip = e.new_label();
+            if let JP_c_a16(c, _) | CALL_c_a16(c, _) = instr {
+                cond_jump(&mut e.asm, known, c.not(), skip)?;
+            }
+            let dest = match by_bank(a16) {
+                true => {
+                    e.asm.mov(r10d, a16 as u32)?;
+                    mapped_bank(e, pc)?;
+                    e.helper("dispatch")
+                }
+                false => e.label(pc.to(a16)),
+            };
+            match instr {
+                CALL_a16(_) | CALL_c_a16(..) => call(e, dest, pc.addr, next)?,
+                _ => e.asm.jmp(dest)?,
+            }
+            e.place(skip)?;
         }
+        RST_vector(_) => unreachable!("that is call by now"),
     };
-    (instrs, res)
-}
-
-fn transpile_cond_jump(c: crate::sm83::Condition) -> &'static str {
-    use crate::sm83::Condition::*;
-    match c {
-        NZ => {
-            "je";
-            todo!() // where is the zero flag?!
+    // cart ram can be off, or a clock register
+    let trapped = [
+        (e.trap_io, abi::IO),
+        (e.switch_banks, 0..0x8000),
+        (e.switch_banks, 0xa000..0xc000),
+    ];
+    let trapped: Vec<Range<u16>> =
+        trapped.into_iter().filter(|t| t.0).map(|t| t.1).collect();
+    if let Some(addr) = stored(instr) {
+        bus_access(e, addr, "bus_write", &trapped)?;
+        // the other byte of sp
+        if let (LD_pa16_SP(a16), MemAddr::Const(_)) = (instr, addr) {
+            let next = MemAddr::Const(a16.wrapping_add(1));
+            bus_access(e, next, "bus_write", &trapped)?;
         }
-        Z => {
-            "jne";
-            todo!()
+    }
+    if e.track_writes {
+        check_write(e, instr, next)?;
+    }
+    // the rest of this bank's code is not what runs after a switch
+    let to_rom = match stored(instr) {
+        // ld [$ffff], sp wraps around to 0
+        Some(MemAddr::Const(addr)) => {
+            addr < 0x8000 || instr == LD_pa16_SP(0xffff)
+        }
+        Some(_) => true,
+        None => false,
+    };
+    if e.switch_banks && to_rom {
+        check_rom0(e, next)?;
+        if switchable(pc.addr) {
+            check_bank(e, pc, next)?;
         }
-        NC => "jc",
-        C => "jnc",
     }
+    Ok(res)
 }